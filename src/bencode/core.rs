@@ -240,11 +240,44 @@ impl<'de> BencodeDeserializer<'de> {
             let value = self.get_any()?;
             map.insert(key, value);
         }
+        self.pos = self.pos.checked_add(1).expect("Position overflow");
 
         Ok(Bencode::Dict(map))
     }
+
+    /// Skips the next value and returns it still encoded.
+    pub(crate) fn parse_raw(&mut self) -> Result<&'de [u8], BencodeDeserializationError> {
+        let start = self.pos;
+        self.get_any()?;
+        Ok(&self.input[start..self.pos])
+    }
+
+    /// Entries of a dictionary in input order, with values left encoded.
+    pub fn parse_raw_dict(&mut self) -> Result<RawEntries<'de>, BencodeDeserializationError> {
+        self.check_for_container_type()?;
+        self.check_type(BencodeType::Dict)?;
+        self.pos += 1;
+
+        let mut entries = Vec::new();
+        loop {
+            match self.input.get(self.pos) {
+                None => return Err(BencodeDeserializationError::UnexpectedEof),
+                Some(&END) => break,
+                Some(_) => {
+                    let key = self.parse_bytes()?;
+                    let value = self.parse_raw()?;
+                    entries.push((key, value));
+                }
+            }
+        }
+        self.pos += 1;
+        Ok(entries)
+    }
 }
 
+/// Dictionary keys with their still encoded values.
+pub type RawEntries<'a> = Vec<(&'a [u8], &'a [u8])>;
+
 #[derive(Debug, PartialEq)]
 #[allow(dead_code)]
 enum Bencode<'a> {
@@ -414,7 +447,7 @@ mod tests {
             ),
             // List with dictionary
             (
-                &b"lld3:foo3:baree"[..],
+                &b"lld3:foo3:bareee"[..],
                 Bencode::List(vec![Bencode::List(vec![Bencode::Dict({
                     let mut map = BTreeMap::new();
                     map.insert(&b"foo"[..], Bencode::Bytes(&b"bar"[..]));
@@ -452,8 +485,27 @@ mod tests {
             let mut deserializer = BencodeDeserializer::new(input);
             let actual = deserializer.get_dict().unwrap();
             assert_eq!(actual, expected);
+            assert!(deserializer.is_consumed());
         }
     }
 
+    #[test]
+    fn parse_raw_dict() {
+        let input = &b"d1:bli1ei2ee1:ad1:xi0eee"[..];
+        let mut deserializer = BencodeDeserializer::new(input);
+        let entries = deserializer.parse_raw_dict().unwrap();
+        assert_eq!(
+            entries,
+            vec![(&b"b"[..], &b"li1ei2ee"[..]), (&b"a"[..], &b"d1:xi0ee"[..])]
+        );
+        assert!(deserializer.is_consumed());
+
+        let mut deserializer = BencodeDeserializer::new(&b"d1:ai1e"[..]);
+        assert_eq!(
+            deserializer.parse_raw_dict(),
+            Err(BencodeDeserializationError::UnexpectedEof)
+        );
+    }
+
     // TODO: dict error cases
 }
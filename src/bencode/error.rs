@@ -26,6 +26,8 @@ pub enum BencodeDeserializationError {
     InvalidInteger(char),
     #[error("integer contains leading zeroes")]
     InvalidIntegerLeadingZero,
+    #[error("boolean should be encoded as 0 or 1, got {0}")]
+    InvalidBool(i64),
     #[error("invalid bencode data: expected {expected:?}, got {actual:?}")]
     UnexpectedBencodeType {
         expected: Option<BencodeType>,
@@ -6,7 +6,7 @@ mod error;
 mod serde;
 
 pub use crate::bencode::core::BencodeDeserializer;
-pub use crate::bencode::error::BencodeDeserializationError;
+pub use crate::bencode::error::{BencodeDeserializationError, BencodeSerializationError};
 pub use crate::bencode::serde::to_bencode;
 
 #[cfg(test)]
@@ -8,9 +8,9 @@ impl<'de> serde::de::Deserializer<'de> for &mut BencodeDeserializer<'de> {
     type Error = BencodeDeserializationError;
 
     forward_to_deserialize_any! {
-        bool i8 i16 i32 u8 u16 u32 u64 f32 f64 char str string
+        i8 i16 i32 u8 u16 u32 u64 f32 f64 char str string
         unit unit_struct newtype_struct tuple
-        tuple_struct identifier enum ignored_any option
+        tuple_struct identifier enum ignored_any
         byte_buf
     }
 
@@ -31,6 +31,25 @@ impl<'de> serde::de::Deserializer<'de> for &mut BencodeDeserializer<'de> {
         }
     }
 
+    fn deserialize_bool<V>(self, visitor: V) -> Result<V::Value, Self::Error>
+    where
+        V: Visitor<'de>,
+    {
+        match self.parse_integer()? {
+            0 => visitor.visit_bool(false),
+            1 => visitor.visit_bool(true),
+            other => Err(BencodeDeserializationError::InvalidBool(other)),
+        }
+    }
+
+    fn deserialize_option<V>(self, visitor: V) -> Result<V::Value, Self::Error>
+    where
+        V: Visitor<'de>,
+    {
+        // Absent keys are handled by `#[serde(default)]`, so a present value is always `Some`.
+        visitor.visit_some(self)
+    }
+
     fn deserialize_i64<V>(self, visitor: V) -> Result<V::Value, Self::Error>
     where
         V: Visitor<'de>,
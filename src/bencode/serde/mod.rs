@@ -138,6 +138,7 @@ mod tests {
     #[test]
     fn mixed_list_tests() {
         #[derive(Debug, Deserialize, PartialEq)]
+        #[allow(dead_code)]
         struct IntWrapper(i64);
 
         // Test with Vec<Option<i64>> to check handling of different types
@@ -329,4 +330,58 @@ mod tests {
         test_serialize(42i64, b"i42e");
         test_serialize(42i64, b"i42e");
     }
+
+    #[test]
+    fn bool_round_trip() {
+        test_serialize(true, b"i1e");
+        test_serialize(false, b"i0e");
+
+        let mut deserializer = BencodeDeserializer::new(&b"i1e"[..]);
+        test_happy_case(&mut deserializer, true);
+        let mut deserializer = BencodeDeserializer::new(&b"i0e"[..]);
+        test_happy_case(&mut deserializer, false);
+
+        let mut deserializer = BencodeDeserializer::new(&b"i2e"[..]);
+        test_error_case::<bool>(
+            &mut deserializer,
+            BencodeDeserializationError::InvalidBool(2),
+        );
+    }
+
+    #[test]
+    fn optional_fields() {
+        #[derive(Debug, Deserialize, Serialize, PartialEq)]
+        struct WithOptional {
+            name: String,
+            #[serde(default, skip_serializing_if = "Option::is_none")]
+            comment: Option<String>,
+            #[serde(default, skip_serializing_if = "Option::is_none")]
+            private: Option<bool>,
+        }
+
+        let cases = [
+            (
+                &b"d4:name3:fooe"[..],
+                WithOptional {
+                    name: "foo".to_string(),
+                    comment: None,
+                    private: None,
+                },
+            ),
+            (
+                &b"d7:comment2:hi4:name3:foo7:privatei1ee"[..],
+                WithOptional {
+                    name: "foo".to_string(),
+                    comment: Some("hi".to_string()),
+                    private: Some(true),
+                },
+            ),
+        ];
+
+        for (data, expected_value) in cases {
+            test_serialize(&expected_value, data);
+            let mut deserializer = BencodeDeserializer::new(data);
+            test_happy_case(&mut deserializer, expected_value);
+        }
+    }
 }
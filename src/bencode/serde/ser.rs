@@ -300,8 +300,9 @@ impl serde::Serializer for BencodeSerializer {
     type SerializeStruct = BencodeMapSerializer;
     type SerializeStructVariant = serde::ser::Impossible<Self::Ok, Self::Error>;
 
-    fn serialize_bool(self, _v: bool) -> Result<Self::Ok, Self::Error> {
-        Err(BencodeSerializationError::UnsupportedType("bool"))
+    fn serialize_bool(self, v: bool) -> Result<Self::Ok, Self::Error> {
+        // Bencode has no boolean type, flags like `private` are encoded as 0/1 integers.
+        Ok(format!("i{}e", u8::from(v)).as_bytes().to_vec())
     }
 
     fn serialize_i8(self, v: i8) -> Result<Self::Ok, Self::Error> {
@@ -365,11 +366,12 @@ impl serde::Serializer for BencodeSerializer {
         Err(BencodeSerializationError::UnsupportedType("none"))
     }
 
-    fn serialize_some<T>(self, _value: &T) -> Result<Self::Ok, Self::Error>
+    fn serialize_some<T>(self, value: &T) -> Result<Self::Ok, Self::Error>
     where
         T: ?Sized + Serialize,
     {
-        Err(BencodeSerializationError::UnsupportedType("some"))
+        // `None` has no representation, so optional fields should be skipped when empty.
+        value.serialize(self)
     }
 
     fn serialize_unit(self) -> Result<Self::Ok, Self::Error> {
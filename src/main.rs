@@ -1,7 +1,11 @@
+use crate::torrent::meta::InfoHash;
 use crate::torrent::network::{PeerClient, PeerMessage};
+use crate::torrent::recheck::Recheck;
+use crate::torrent::storage::TorrentStorage;
+use crate::torrent::tracker::async_client::{AsyncTrackerClient, CancellationToken};
+use crate::torrent::tracker::TrackerError;
 use sha1::{Digest, Sha1};
-use std::fs::File;
-use std::io::{Seek, Write};
+use std::path::Path;
 
 mod bencode;
 mod torrent;
@@ -13,23 +17,83 @@ fn main() {
         std::process::exit(1);
     };
 
+    if torrent_path.starts_with("magnet:") {
+        magnet(torrent_path);
+        return;
+    }
+    if torrent_path == "recheck" {
+        recheck(&args[2..]);
+        return;
+    }
+
     let torrent_file = torrent::meta::TorrentFile::open(torrent_path).unwrap();
-    println!("Filename: {:#?}", torrent_file.info.name);
+    println!("Filename: {:#?}", torrent_file.info().name);
     println!("Tracker URL: {:#?}", torrent_file.announce);
-    println!("Length: {}", torrent_file.info.length);
-
-    let client = torrent::network::TorrentTrackerClient::new();
+    println!("Length: {}", torrent_file.info().total_length());
+    if let Some(comment) = &torrent_file.comment {
+        println!("Comment: {}", comment);
+    }
+    if let Some(created_by) = &torrent_file.created_by {
+        println!("Created by: {}", created_by);
+    }
+    if let Some(creation_date) = torrent_file.creation_date {
+        println!("Creation date: {}", creation_date);
+    }
+    if let Some(encoding) = &torrent_file.encoding {
+        println!("Encoding: {}", encoding);
+    }
+    println!("Private: {}", torrent_file.info().is_private());
+    if let Some(source) = &torrent_file.info().source {
+        println!("Source: {}", source);
+    }
+    for web_seed in torrent_file.web_seeds() {
+        println!("Web seed ({:?}): {}", web_seed.kind, web_seed.url);
+    }
+    let info_hashes = torrent_file
+        .info_hashes()
+        .expect("Failed to encode info dictionary");
+    for info_hash in &info_hashes {
+        match info_hash {
+            InfoHash::V1(_) => println!("Info Hash v1: {}", info_hash),
+            InfoHash::V2(_) => println!("Info Hash v2: {}", info_hash),
+        }
+    }
+    let report = torrent_file.validate();
+    if !report.issues.is_empty() {
+        print!("Validation issues:\n{}", report);
+    }
+    if !report.is_valid() {
+        std::process::exit(1);
+    }
 
-    let peers = client.get_peers(&torrent_file);
+    let hash = torrent_file
+        .primary_info_hash()
+        .expect("Failed to encode info dictionary");
+    let runtime = tokio::runtime::Runtime::new().expect("Failed to start async runtime");
+    let peers = runtime.block_on(async {
+        let client = AsyncTrackerClient::new()?;
+        let peers = client
+            .get_peers(&torrent_file, &CancellationToken::new())
+            .await?;
+        let peer_id = client.peer_id_for(&info_hashes);
+        Ok::<_, TrackerError>((peers, peer_id))
+    });
+    let (peers, peer_id) = match peers {
+        Ok(peers) => peers,
+        Err(e) => {
+            println!("Tracker error: {}", e);
+            std::process::exit(1);
+        }
+    };
 
-    let hash = torrent_file.meta_hash();
     // for peer_addr in peers {
     //     println!("{:?}", peer_addr);
     //     let _peer_client = PeerClient::new(peer_addr, hash);
     // }
 
     let first_peer = peers.first().unwrap();
-    let mut peer_client = PeerClient::new(*first_peer, hash);
+    let mut peer_client =
+        PeerClient::new(*first_peer, hash, peer_id, torrent_file.info().is_v2());
     // TODO: State machine
     let msg_1 = peer_client.read_message();
     println!("RECEIVED MESSAGE 1: {:?}", msg_1);
@@ -39,7 +103,7 @@ fn main() {
     println!("RECEIVED MESSAGE 2: {:?}", msg_2);
     let mut pieces: Vec<Vec<u8>> = vec![Vec::new()];
     let mut current_index = 0;
-    for piece_info in torrent_file.info.as_piece_infos() {
+    for piece_info in torrent_file.info().as_piece_infos() {
         println!("Piece info: {:?}", piece_info);
         if piece_info.index != current_index {
             current_index += 1;
@@ -53,13 +117,10 @@ fn main() {
         }
     }
 
-    let mut output_file = File::create(torrent_file.info.name).unwrap();
-    output_file
-        .set_len(torrent_file.info.length as u64)
-        .unwrap();
+    let storage = TorrentStorage::new(Path::new("."), torrent_file.info()).unwrap();
 
     for (piece_index, (info_hash_piece, piece)) in torrent_file
-        .info
+        .info()
         .pieces
         .chunks(20)
         .zip(pieces.iter())
@@ -71,10 +132,52 @@ fn main() {
         println!("Piece hash A: {}", hex::encode(info_hash_piece));
         println!("Piece hash B: {}", hex::encode(hash));
 
-        let offset = piece_index * torrent_file.info.piece_length;
-        output_file
-            .seek(std::io::SeekFrom::Start(offset as u64))
-            .unwrap();
-        output_file.write_all(piece).unwrap();
+        storage.write_piece(piece_index as u32, piece).unwrap();
+    }
+    storage.finalize().unwrap();
+}
+
+fn magnet(link: &str) {
+    let magnet = torrent::magnet::MagnetLink::parse(link).unwrap();
+    println!("Name: {:#?}", magnet.display_name);
+    for info_hash in &magnet.info_hashes {
+        println!("Info Hash: {}", info_hash);
+    }
+    println!("Trackers: {:#?}", magnet.trackers);
+
+    let client = torrent::tracker::TorrentTrackerClient::new();
+    match client.get_peers_for_magnet(&magnet) {
+        Ok(peers) => println!("Peers: {:?}", peers),
+        Err(e) => println!("Tracker error: {}", e),
+    }
+}
+
+/// `recheck <torrent> [download dir]`: verifies data on disk against the piece hashes.
+fn recheck(args: &[String]) {
+    let Some(torrent_path) = args.first() else {
+        println!("Usage: recheck <torrent> [download dir]");
+        std::process::exit(1);
+    };
+    let download_root = args.get(1).map(String::as_str).unwrap_or(".");
+
+    let torrent_file = torrent::meta::TorrentFile::open(torrent_path).unwrap();
+    let storage = TorrentStorage::new(Path::new(download_root), torrent_file.info()).unwrap();
+    let report = Recheck::new(&storage, torrent_file.info())
+        .progress(|progress| eprint!("\rChecked {}/{} pieces", progress.checked, progress.total))
+        .run()
+        .unwrap();
+    eprintln!();
+
+    for file in &report.files {
+        println!("{:6.2}% {}", file.percent(), file.path.display());
+    }
+    println!(
+        "Valid pieces: {}/{}",
+        report.pieces.count_ones(),
+        report.pieces.len()
+    );
+    if !report.is_complete() {
+        println!("Bad pieces: {:?}", report.bad_pieces);
+        std::process::exit(1);
     }
 }
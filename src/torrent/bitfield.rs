@@ -0,0 +1,85 @@
+//! Piece bitfield in the wire format of the `bitfield` peer message.
+
+/// One bit per piece, the high bit of the first byte is piece 0.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Bitfield {
+    bytes: Vec<u8>,
+    len: usize,
+}
+
+impl Bitfield {
+    pub fn new(len: usize) -> Self {
+        Self {
+            bytes: vec![0; len.div_ceil(8)],
+            len,
+        }
+    }
+
+    /// Spare bits past `len` are cleared.
+    pub fn from_bytes(mut bytes: Vec<u8>, len: usize) -> Self {
+        bytes.resize(len.div_ceil(8), 0);
+        if !len.is_multiple_of(8) {
+            if let Some(last) = bytes.last_mut() {
+                *last &= 0xFF << (8 - len % 8);
+            }
+        }
+        Self { bytes, len }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn get(&self, index: usize) -> bool {
+        index < self.len && self.bytes[index / 8] & (0x80 >> (index % 8)) != 0
+    }
+
+    pub fn set(&mut self, index: usize, value: bool) {
+        assert!(index < self.len, "bit {} out of {}", index, self.len);
+        let mask = 0x80 >> (index % 8);
+        match value {
+            true => self.bytes[index / 8] |= mask,
+            false => self.bytes[index / 8] &= !mask,
+        }
+    }
+
+    pub fn count_ones(&self) -> usize {
+        self.bytes.iter().map(|b| b.count_ones() as usize).sum()
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.count_ones() == self.len
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bits_are_msb_first() {
+        let mut bitfield = Bitfield::new(10);
+        bitfield.set(0, true);
+        bitfield.set(9, true);
+        assert_eq!(bitfield.as_bytes(), &[0x80, 0x40]);
+        assert!(bitfield.get(9));
+        assert!(!bitfield.get(10));
+        assert_eq!(bitfield.count_ones(), 2);
+
+        bitfield.set(0, false);
+        assert_eq!(bitfield.as_bytes(), &[0x00, 0x40]);
+        assert_eq!(
+            Bitfield::from_bytes(vec![0xFF, 0xFF], 10).as_bytes(),
+            &[0xFF, 0xC0]
+        );
+        assert!(Bitfield::from_bytes(vec![0xFF, 0xFF], 10).is_complete());
+    }
+}
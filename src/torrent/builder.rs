@@ -0,0 +1,568 @@
+//! Creation of .torrent files from a file or a directory.
+
+use crate::torrent::meta::{
+    FileAttributes, FileEntry, MetaInfo, TorrentFile, UnixTimestamp, UrlList,
+};
+use crate::torrent::parallel::{run_parallel, run_parallel_with};
+use crate::torrent::v2::{self, FileTree, PieceLayers, Sha256Hash, V2File, MERKLE_BLOCK_SIZE};
+use serde_bytes::ByteBuf;
+use sha1::{Digest, Sha1};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+const MIN_PIECE_LENGTH: usize = 16 * 1024;
+const MAX_PIECE_LENGTH: usize = 16 * 1024 * 1024;
+/// Automatic piece length aims for roughly this many pieces.
+const TARGET_PIECE_COUNT: u64 = 1500;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TorrentVersion {
+    #[default]
+    V1,
+    V2,
+    /// Both v1 `pieces` and v2 `file tree`, with files padded to piece boundaries.
+    Hybrid,
+}
+
+impl TorrentVersion {
+    fn has_v1(&self) -> bool {
+        matches!(self, TorrentVersion::V1 | TorrentVersion::Hybrid)
+    }
+
+    fn has_v2(&self) -> bool {
+        matches!(self, TorrentVersion::V2 | TorrentVersion::Hybrid)
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum TorrentBuildError {
+    #[error("failed to read content")]
+    Io(#[from] std::io::Error),
+    #[error("no files found under {0}")]
+    NoFiles(PathBuf),
+    #[error("at least one tracker is required")]
+    NoTrackers,
+    #[error("invalid piece length {0}")]
+    InvalidPieceLength(usize),
+    #[error("path is not valid UTF-8: {0}")]
+    NonUtf8Path(PathBuf),
+}
+
+pub struct TorrentBuilder {
+    root: PathBuf,
+    trackers: Vec<Vec<String>>,
+    web_seeds: Vec<String>,
+    comment: Option<String>,
+    created_by: Option<String>,
+    creation_date: Option<UnixTimestamp>,
+    private: bool,
+    source: Option<String>,
+    piece_length: Option<usize>,
+    version: TorrentVersion,
+    threads: usize,
+}
+
+/// File found on disk, `components` are relative to the torrent root.
+struct ContentFile {
+    path: PathBuf,
+    components: Vec<String>,
+    length: u64,
+}
+
+/// Part of the v1 piece space.
+enum Segment<'a> {
+    File(&'a ContentFile),
+    Padding(u64),
+}
+
+impl Segment<'_> {
+    fn length(&self) -> u64 {
+        match self {
+            Segment::File(file) => file.length,
+            Segment::Padding(length) => *length,
+        }
+    }
+}
+
+impl TorrentBuilder {
+    pub fn new<P: AsRef<Path>>(root: P) -> Self {
+        Self {
+            root: root.as_ref().to_path_buf(),
+            trackers: Vec::new(),
+            web_seeds: Vec::new(),
+            comment: None,
+            created_by: Some(format!("bittoren-rust/{}", env!("CARGO_PKG_VERSION"))),
+            creation_date: Some(UnixTimestamp::now()),
+            private: false,
+            source: None,
+            piece_length: None,
+            version: TorrentVersion::default(),
+            threads: std::thread::available_parallelism()
+                .map(|threads| threads.get())
+                .unwrap_or(1),
+        }
+    }
+
+    /// Adds a tracker in its own tier.
+    pub fn tracker(mut self, url: impl Into<String>) -> Self {
+        self.trackers.push(vec![url.into()]);
+        self
+    }
+
+    /// Adds a tier of trackers that are tried in random order.
+    pub fn tracker_tier(mut self, urls: Vec<String>) -> Self {
+        if !urls.is_empty() {
+            self.trackers.push(urls);
+        }
+        self
+    }
+
+    pub fn web_seed(mut self, url: impl Into<String>) -> Self {
+        self.web_seeds.push(url.into());
+        self
+    }
+
+    pub fn comment(mut self, comment: impl Into<String>) -> Self {
+        self.comment = Some(comment.into());
+        self
+    }
+
+    pub fn created_by(mut self, created_by: Option<String>) -> Self {
+        self.created_by = created_by;
+        self
+    }
+
+    pub fn creation_date(mut self, creation_date: Option<UnixTimestamp>) -> Self {
+        self.creation_date = creation_date;
+        self
+    }
+
+    pub fn private(mut self, private: bool) -> Self {
+        self.private = private;
+        self
+    }
+
+    pub fn source(mut self, source: impl Into<String>) -> Self {
+        self.source = Some(source.into());
+        self
+    }
+
+    /// Overrides the automatically picked piece length.
+    pub fn piece_length(mut self, piece_length: usize) -> Self {
+        self.piece_length = Some(piece_length);
+        self
+    }
+
+    pub fn version(mut self, version: TorrentVersion) -> Self {
+        self.version = version;
+        self
+    }
+
+    pub fn threads(mut self, threads: usize) -> Self {
+        self.threads = threads.max(1);
+        self
+    }
+
+    pub fn build(self) -> Result<TorrentFile, TorrentBuildError> {
+        let announce = self
+            .trackers
+            .first()
+            .and_then(|tier| tier.first())
+            .cloned()
+            .ok_or(TorrentBuildError::NoTrackers)?;
+        let name = file_name(&self.root)?;
+
+        let is_single_file = std::fs::metadata(&self.root)?.is_file();
+        let files = if is_single_file {
+            vec![ContentFile {
+                path: self.root.clone(),
+                components: vec![name.clone()],
+                length: std::fs::metadata(&self.root)?.len(),
+            }]
+        } else {
+            let mut files = Vec::new();
+            collect_files(&self.root, &mut Vec::new(), &mut files)?;
+            files
+        };
+        if files.is_empty() {
+            return Err(TorrentBuildError::NoFiles(self.root.clone()));
+        }
+
+        let total_length: u64 = files.iter().map(|file| file.length).sum();
+        let piece_length = match self.piece_length {
+            Some(piece_length) => piece_length,
+            None => auto_piece_length(total_length),
+        };
+        let is_valid_v2_length =
+            piece_length.is_power_of_two() && piece_length >= MERKLE_BLOCK_SIZE as usize;
+        if piece_length == 0 || (self.version.has_v2() && !is_valid_v2_length) {
+            return Err(TorrentBuildError::InvalidPieceLength(piece_length));
+        }
+
+        let mut info = MetaInfo {
+            length: None,
+            files: None,
+            name,
+            piece_length,
+            pieces: Vec::new(),
+            meta_version: None,
+            file_tree: None,
+            private: self.private.then_some(true),
+            source: self.source.clone(),
+        };
+        let mut piece_layers = None;
+
+        if self.version.has_v1() {
+            let segments = self.v1_segments(&files, piece_length as u64);
+            info.pieces = hash_v1_pieces(&segments, piece_length, self.threads)?;
+            if is_single_file {
+                info.length = Some(total_length as usize);
+            } else {
+                info.files = Some(
+                    segments
+                        .iter()
+                        .map(|segment| match segment {
+                            Segment::File(file) => {
+                                FileEntry::new(file.components.clone(), file.length)
+                            }
+                            Segment::Padding(length) => FileEntry {
+                                attr: Some(FileAttributes::new("p")),
+                                ..FileEntry::new(
+                                    vec![".pad".to_string(), length.to_string()],
+                                    *length,
+                                )
+                            },
+                        })
+                        .collect(),
+                );
+            }
+        }
+
+        if self.version.has_v2() {
+            let roots = hash_v2_files(&files, piece_length as u64, self.threads)?;
+            let mut file_tree = FileTree::default();
+            let mut layers = BTreeMap::new();
+            for (file, (pieces_root, layer)) in files.iter().zip(roots) {
+                let pieces_root = pieces_root.map(|root| ByteBuf::from(root.to_vec()));
+                if let (Some(root), Some(layer)) = (&pieces_root, layer) {
+                    layers.insert(root.clone(), ByteBuf::from(layer.concat()));
+                }
+                file_tree.insert(
+                    &file.components,
+                    V2File {
+                        length: file.length,
+                        pieces_root,
+                    },
+                );
+            }
+            info.meta_version = Some(2);
+            info.file_tree = Some(file_tree);
+            piece_layers = Some(PieceLayers(layers));
+        }
+
+        let mut torrent_file = TorrentFile::new(announce, info);
+        let tracker_count: usize = self.trackers.iter().map(Vec::len).sum();
+        if tracker_count > 1 {
+            torrent_file.announce_list = Some(self.trackers);
+        }
+        if !self.web_seeds.is_empty() {
+            torrent_file.url_list = Some(UrlList::from(self.web_seeds));
+        }
+        torrent_file.comment = self.comment;
+        torrent_file.created_by = self.created_by;
+        torrent_file.creation_date = self.creation_date;
+        torrent_file.piece_layers = piece_layers;
+        Ok(torrent_file)
+    }
+
+    /// Lays files out in the v1 piece space. Hybrid torrents align every file to a piece
+    /// boundary with BEP 47 padding files, so v1 pieces match v2 ones.
+    fn v1_segments<'a>(&self, files: &'a [ContentFile], piece_length: u64) -> Vec<Segment<'a>> {
+        let mut segments = Vec::with_capacity(files.len());
+        for (index, file) in files.iter().enumerate() {
+            segments.push(Segment::File(file));
+            let unaligned = file.length % piece_length;
+            let is_last = index == files.len() - 1;
+            if self.version == TorrentVersion::Hybrid && unaligned != 0 && !is_last {
+                segments.push(Segment::Padding(piece_length - unaligned));
+            }
+        }
+        segments
+    }
+}
+
+/// Power of two piece length giving about [`TARGET_PIECE_COUNT`] pieces.
+pub fn auto_piece_length(total_length: u64) -> usize {
+    let target = (total_length / TARGET_PIECE_COUNT).max(1) as usize;
+    target
+        .next_power_of_two()
+        .clamp(MIN_PIECE_LENGTH, MAX_PIECE_LENGTH)
+}
+
+fn file_name(path: &Path) -> Result<String, TorrentBuildError> {
+    let canonical = path.canonicalize()?;
+    let name = canonical
+        .file_name()
+        .ok_or_else(|| TorrentBuildError::NonUtf8Path(path.to_path_buf()))?;
+    name.to_str()
+        .map(str::to_string)
+        .ok_or_else(|| TorrentBuildError::NonUtf8Path(path.to_path_buf()))
+}
+
+/// Recursively collects regular files, sorted by name for reproducible torrents.
+fn collect_files(
+    dir: &Path,
+    components: &mut Vec<String>,
+    files: &mut Vec<ContentFile>,
+) -> Result<(), TorrentBuildError> {
+    let mut entries = std::fs::read_dir(dir)?.collect::<Result<Vec<_>, _>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+
+    for entry in entries {
+        let path = entry.path();
+        let file_type = entry.file_type()?;
+        let name = entry
+            .file_name()
+            .into_string()
+            .map_err(|_| TorrentBuildError::NonUtf8Path(path.clone()))?;
+        components.push(name);
+        if file_type.is_dir() {
+            collect_files(&path, components, files)?;
+        } else if file_type.is_file() {
+            files.push(ContentFile {
+                length: entry.metadata()?.len(),
+                path,
+                components: components.clone(),
+            });
+        }
+        components.pop();
+    }
+    Ok(())
+}
+
+/// Reads ranges of the piece space, keeping the last opened file for the next read.
+struct SegmentReader<'a> {
+    segments: &'a [Segment<'a>],
+    open: Option<(usize, File)>,
+}
+
+impl<'a> SegmentReader<'a> {
+    fn new(segments: &'a [Segment<'a>]) -> Self {
+        Self {
+            segments,
+            open: None,
+        }
+    }
+
+    /// Reads `buffer.len()` bytes of the piece space starting at `offset`.
+    fn read_range(&mut self, offset: u64, buffer: &mut [u8]) -> std::io::Result<()> {
+        let mut segment_start = 0;
+        let mut written = 0;
+        for (index, segment) in self.segments.iter().enumerate() {
+            let segment_end = segment_start + segment.length();
+            let position = offset + written as u64;
+            if written == buffer.len() {
+                break;
+            }
+            if position < segment_end {
+                let in_segment = position - segment_start;
+                let to_copy = ((segment_end - position) as usize).min(buffer.len() - written);
+                let target = &mut buffer[written..written + to_copy];
+                match segment {
+                    Segment::File(file) => {
+                        let source = match &mut self.open {
+                            Some((open_index, source)) if *open_index == index => source,
+                            open => &mut open.insert((index, File::open(&file.path)?)).1,
+                        };
+                        source.seek(SeekFrom::Start(in_segment))?;
+                        source.read_exact(target)?;
+                    }
+                    Segment::Padding(_) => target.fill(0),
+                }
+                written += to_copy;
+            }
+            segment_start = segment_end;
+        }
+        Ok(())
+    }
+}
+
+fn hash_v1_pieces(
+    segments: &[Segment],
+    piece_length: usize,
+    threads: usize,
+) -> std::io::Result<Vec<u8>> {
+    let total_length: u64 = segments.iter().map(Segment::length).sum();
+    let num_pieces = total_length.div_ceil(piece_length as u64) as usize;
+
+    let init = || SegmentReader::new(segments);
+    let hashes = run_parallel_with(num_pieces, threads, init, |reader, index| {
+        let offset = index as u64 * piece_length as u64;
+        let length = (piece_length as u64).min(total_length - offset) as usize;
+        let mut buffer = vec![0; length];
+        reader.read_range(offset, &mut buffer)?;
+        Ok(<[u8; 20]>::from(Sha1::digest(&buffer)))
+    })?;
+    Ok(hashes.concat())
+}
+
+type V2Hashes = (Option<Sha256Hash>, Option<Vec<Sha256Hash>>);
+
+/// Pieces root of each file, and its piece layer when it spans more than one piece.
+fn hash_v2_files(
+    files: &[ContentFile],
+    piece_length: u64,
+    threads: usize,
+) -> std::io::Result<Vec<V2Hashes>> {
+    run_parallel(files.len(), threads, |index| {
+        let file = &files[index];
+        if file.length == 0 {
+            return Ok((None, None));
+        }
+        let mut source = File::open(&file.path)?;
+        let mut leaves = Vec::with_capacity(file.length.div_ceil(MERKLE_BLOCK_SIZE) as usize);
+        let mut block = vec![0; MERKLE_BLOCK_SIZE as usize];
+        let mut remaining = file.length;
+        while remaining > 0 {
+            let length = remaining.min(MERKLE_BLOCK_SIZE) as usize;
+            source.read_exact(&mut block[..length])?;
+            leaves.push(v2::sha256(&block[..length]));
+            remaining -= length as u64;
+        }
+
+        // The piece length was checked by `build`.
+        let invalid = |e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e);
+        let root = v2::pieces_root_from_leaves(&leaves, piece_length).map_err(invalid)?;
+        let layer = (file.length > piece_length)
+            .then(|| v2::piece_layer_from_leaves(&leaves, piece_length))
+            .transpose()
+            .map_err(invalid)?;
+        Ok((Some(root), layer))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bencode::BencodeDeserializer;
+    use crate::torrent::test_util::TempDir;
+    use serde::Deserialize;
+
+    fn content(length: usize, seed: u8) -> Vec<u8> {
+        (0..length).map(|i| (i as u8).wrapping_mul(seed)).collect()
+    }
+
+    #[test]
+    fn auto_piece_length_is_power_of_two_in_bounds() {
+        assert_eq!(auto_piece_length(0), MIN_PIECE_LENGTH);
+        assert_eq!(auto_piece_length(1_000_000), MIN_PIECE_LENGTH);
+        assert_eq!(auto_piece_length(4 * 1024 * 1024 * 1024), 4 * 1024 * 1024);
+        assert_eq!(auto_piece_length(u64::MAX / 2), MAX_PIECE_LENGTH);
+    }
+
+    #[test]
+    fn single_file_v1() {
+        let dir = TempDir::new("builder-single");
+        let data = content(100_000, 7);
+        let path = dir.0.join("data.bin");
+        std::fs::write(&path, &data).unwrap();
+
+        let torrent = TorrentBuilder::new(&path)
+            .tracker("http://t.example/announce")
+            .tracker("http://backup.example/announce")
+            .comment("dataset")
+            .private(true)
+            .source("INTERNAL")
+            .web_seed("http://mirror.example/data.bin")
+            .piece_length(32 * 1024)
+            .threads(3)
+            .build()
+            .unwrap();
+
+        assert_eq!(torrent.info().name, "data.bin");
+        assert_eq!(torrent.info().length, Some(data.len()));
+        assert_eq!(torrent.info().pieces.len(), 4 * 20);
+        for (index, piece) in data.chunks(32 * 1024).enumerate() {
+            assert!(torrent.info().verify_piece(index, piece));
+        }
+        assert!(torrent.info().is_private());
+        assert_eq!(torrent.info().source.as_deref(), Some("INTERNAL"));
+        assert_eq!(
+            torrent.announce_list,
+            Some(vec![
+                vec!["http://t.example/announce".to_string()],
+                vec!["http://backup.example/announce".to_string()],
+            ])
+        );
+
+        let bytes = torrent.to_bytes().unwrap();
+        let mut deserializer = BencodeDeserializer::new(&bytes);
+        let parsed = TorrentFile::deserialize(&mut deserializer).unwrap();
+        assert_eq!(parsed.info(), torrent.info());
+        assert_eq!(parsed.url_list, torrent.url_list);
+    }
+
+    #[test]
+    fn directory_hybrid() {
+        let dir = TempDir::new("builder-hybrid");
+        let root = dir.0.join("dataset");
+        std::fs::create_dir_all(root.join("nested")).unwrap();
+        let first = content(40_000, 3);
+        let second = content(70_000, 5);
+        std::fs::write(root.join("a.bin"), &first).unwrap();
+        std::fs::write(root.join("nested").join("b.bin"), &second).unwrap();
+        std::fs::write(root.join("nested").join("empty"), b"").unwrap();
+
+        let torrent = TorrentBuilder::new(&root)
+            .tracker("http://t.example/announce")
+            .piece_length(32 * 1024)
+            .version(TorrentVersion::Hybrid)
+            .build()
+            .unwrap();
+
+        assert!(torrent.info().is_hybrid());
+        assert_eq!(torrent.info().check_hybrid_layout(), Ok(()));
+        let files = torrent.info().files.as_ref().unwrap();
+        // The empty file is last, so `b.bin` is padded as well.
+        assert_eq!(files.len(), 5);
+        assert!(files[1].is_padding());
+        assert_eq!(files[1].length, 2 * 32 * 1024 - 40_000);
+        assert!(files[3].is_padding());
+
+        let tree = torrent.info().file_tree.as_ref().unwrap().files();
+        assert_eq!(tree[0].0, vec!["a.bin"]);
+        assert_eq!(torrent.verify_file_v2(tree[0].1, &first), Ok(()));
+        assert_eq!(tree[1].0, vec!["nested", "b.bin"]);
+        assert_eq!(torrent.verify_file_v2(tree[1].1, &second), Ok(()));
+        assert_eq!(tree[2].1.pieces_root, None);
+
+        let mut padded = first.clone();
+        padded.resize(2 * 32 * 1024, 0);
+        padded.extend_from_slice(&second);
+        padded.resize(torrent.info().total_length(), 0);
+        for (index, piece) in padded.chunks(32 * 1024).enumerate() {
+            assert!(torrent.info().verify_piece(index, piece), "piece {}", index);
+        }
+    }
+
+    #[test]
+    fn errors() {
+        let dir = TempDir::new("builder-errors");
+        std::fs::write(dir.0.join("file"), b"data").unwrap();
+
+        assert!(matches!(
+            TorrentBuilder::new(dir.0.join("file")).build(),
+            Err(TorrentBuildError::NoTrackers)
+        ));
+        assert!(matches!(
+            TorrentBuilder::new(dir.0.join("file"))
+                .tracker("http://t.example/announce")
+                .piece_length(20_000)
+                .version(TorrentVersion::V2)
+                .build(),
+            Err(TorrentBuildError::InvalidPieceLength(20_000))
+        ));
+    }
+}
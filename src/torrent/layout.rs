@@ -0,0 +1,273 @@
+//! Geometry of the piece space: which file bytes each piece covers.
+
+use crate::torrent::meta::MetaInfo;
+use crate::torrent::network::PieceInfo;
+use std::ops::Range;
+
+/// Position of a file in the piece space.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FileSpan {
+    pub offset: u64,
+    pub length: u64,
+}
+
+impl FileSpan {
+    pub fn end(&self) -> u64 {
+        self.offset + self.length
+    }
+}
+
+/// Part of a file covered by a range of the piece space.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FileSlice {
+    pub file_index: usize,
+    /// Offset inside the file.
+    pub file_offset: u64,
+    /// Offset inside the requested range, e.g. the piece.
+    pub offset: u64,
+    pub length: u64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TorrentLayout {
+    piece_length: u64,
+    files: Vec<FileSpan>,
+}
+
+impl TorrentLayout {
+    /// Lays out files in the same order as they are listed in `info`: v1 `files` including
+    /// padding, a single `length`, or the v2 `file tree` with every file aligned to a piece.
+    pub fn new(info: &MetaInfo) -> Self {
+        let piece_length = info.piece_length as u64;
+        let lengths: Vec<u64> = if let Some(entries) = &info.files {
+            entries.iter().map(|entry| entry.length).collect()
+        } else if let Some(length) = info.length {
+            vec![length as u64]
+        } else if let Some(file_tree) = &info.file_tree {
+            let lengths = file_tree.files().iter().map(|(_, f)| f.length).collect();
+            return Self::aligned(piece_length, lengths);
+        } else {
+            Vec::new()
+        };
+        Self::contiguous(piece_length, lengths)
+    }
+
+    /// Files follow each other without gaps, as in v1 torrents.
+    pub fn contiguous(piece_length: u64, lengths: Vec<u64>) -> Self {
+        let mut offset = 0;
+        let files = lengths
+            .into_iter()
+            .map(|length| {
+                let span = FileSpan { offset, length };
+                offset += length;
+                span
+            })
+            .collect();
+        Self {
+            piece_length,
+            files,
+        }
+    }
+
+    /// Every file starts at a piece boundary, as in v2 torrents.
+    pub fn aligned(piece_length: u64, lengths: Vec<u64>) -> Self {
+        let mut offset = 0;
+        let files = lengths
+            .into_iter()
+            .map(|length| {
+                let span = FileSpan { offset, length };
+                offset += length.div_ceil(piece_length.max(1)) * piece_length;
+                span
+            })
+            .collect();
+        Self {
+            piece_length,
+            files,
+        }
+    }
+
+    pub fn piece_length(&self) -> u64 {
+        self.piece_length
+    }
+
+    pub fn files(&self) -> &[FileSpan] {
+        &self.files
+    }
+
+    /// End of the last file in the piece space.
+    pub fn total_length(&self) -> u64 {
+        self.files.iter().map(FileSpan::end).max().unwrap_or(0)
+    }
+
+    pub fn num_pieces(&self) -> u32 {
+        if self.piece_length == 0 {
+            return 0;
+        }
+        self.total_length().div_ceil(self.piece_length) as u32
+    }
+
+    /// Byte range of piece `index` in the piece space, `None` past the last piece.
+    pub fn piece_range(&self, index: u32) -> Option<Range<u64>> {
+        if index >= self.num_pieces() {
+            return None;
+        }
+        let start = index as u64 * self.piece_length;
+        let limit = start + self.piece_length;
+        // A piece ends early at the end of the torrent, and in v2 at the end of its file.
+        let end = self
+            .files
+            .iter()
+            .filter(|file| file.offset < limit && file.end() > start)
+            .map(|file| file.end().min(limit))
+            .max()
+            .unwrap_or(start);
+        Some(start..end)
+    }
+
+    pub fn piece_size(&self, index: u32) -> Option<u64> {
+        self.piece_range(index).map(|range| range.end - range.start)
+    }
+
+    /// File byte ranges covered by piece `index`, in file order.
+    pub fn piece_slices(&self, index: u32) -> Vec<FileSlice> {
+        match self.piece_range(index) {
+            Some(range) => self.slices(range.start, range.end - range.start),
+            None => Vec::new(),
+        }
+    }
+
+    /// File byte ranges covered by `length` bytes starting at `offset` of the piece space.
+    pub fn slices(&self, offset: u64, length: u64) -> Vec<FileSlice> {
+        let end = offset + length;
+        self.files
+            .iter()
+            .enumerate()
+            .filter(|(_, file)| file.length > 0 && file.offset < end && file.end() > offset)
+            .map(|(file_index, file)| {
+                let start = offset.max(file.offset);
+                let stop = end.min(file.end());
+                FileSlice {
+                    file_index,
+                    file_offset: start - file.offset,
+                    offset: start - offset,
+                    length: stop - start,
+                }
+            })
+            .collect()
+    }
+
+    /// Pieces holding at least one byte of file `file_index`. Empty for empty files.
+    pub fn file_pieces(&self, file_index: usize) -> Range<u32> {
+        match self.files.get(file_index) {
+            Some(file) if file.length > 0 && self.piece_length > 0 => {
+                let first = file.offset / self.piece_length;
+                let last = (file.end() - 1) / self.piece_length;
+                first as u32..last as u32 + 1
+            }
+            _ => 0..0,
+        }
+    }
+
+    /// Block requests for piece `index`, the last block may be shorter.
+    ///
+    /// # Panics
+    ///
+    /// If `block_size` is zero.
+    pub fn blocks(&self, index: u32, block_size: u64) -> impl Iterator<Item = PieceInfo> {
+        assert!(block_size > 0, "block size must not be zero");
+        let piece_size = self.piece_size(index).unwrap_or(0);
+        let num_blocks = piece_size.div_ceil(block_size);
+        (0..num_blocks).map(move |block| {
+            let begin = block * block_size;
+            PieceInfo {
+                index,
+                begin_bytes_offset: begin as u32,
+                length_bytes: block_size.min(piece_size - begin) as u32,
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn multi_file_pieces() {
+        // Files of 5, 0, 12 and 3 bytes in pieces of 8 bytes.
+        let layout = TorrentLayout::contiguous(8, vec![5, 0, 12, 3]);
+        assert_eq!(layout.total_length(), 20);
+        assert_eq!(layout.num_pieces(), 3);
+        assert_eq!(layout.piece_size(0), Some(8));
+        assert_eq!(layout.piece_size(2), Some(4));
+        assert_eq!(layout.piece_size(3), None);
+
+        assert_eq!(
+            layout.piece_slices(0),
+            vec![
+                FileSlice {
+                    file_index: 0,
+                    file_offset: 0,
+                    offset: 0,
+                    length: 5
+                },
+                FileSlice {
+                    file_index: 2,
+                    file_offset: 0,
+                    offset: 5,
+                    length: 3
+                },
+            ]
+        );
+        assert_eq!(
+            layout.piece_slices(2),
+            vec![
+                FileSlice {
+                    file_index: 2,
+                    file_offset: 11,
+                    offset: 0,
+                    length: 1
+                },
+                FileSlice {
+                    file_index: 3,
+                    file_offset: 0,
+                    offset: 1,
+                    length: 3
+                },
+            ]
+        );
+
+        assert_eq!(layout.file_pieces(0), 0..1);
+        assert_eq!(layout.file_pieces(1), 0..0);
+        assert_eq!(layout.file_pieces(2), 0..3);
+        assert_eq!(layout.file_pieces(3), 2..3);
+    }
+
+    #[test]
+    fn aligned_files_end_pieces_early() {
+        let layout = TorrentLayout::aligned(8, vec![10, 3]);
+        assert_eq!(layout.files()[1].offset, 16);
+        assert_eq!(layout.num_pieces(), 3);
+        assert_eq!(layout.piece_size(1), Some(2));
+        assert_eq!(layout.piece_size(2), Some(3));
+        assert_eq!(layout.file_pieces(1), 2..3);
+    }
+
+    #[test]
+    fn blocks_of_a_piece() {
+        let layout = TorrentLayout::contiguous(10, vec![25]);
+        let blocks: Vec<(u32, u32, u32)> = layout
+            .blocks(2, 4)
+            .map(|b| (b.index, b.begin_bytes_offset, b.length_bytes))
+            .collect();
+        assert_eq!(blocks, vec![(2, 0, 4), (2, 4, 1)]);
+        assert_eq!(layout.blocks(1, 4).count(), 3);
+        assert_eq!(layout.blocks(3, 4).count(), 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "block size must not be zero")]
+    fn zero_block_size() {
+        let _ = TorrentLayout::contiguous(10, vec![25]).blocks(0, 0);
+    }
+}
@@ -0,0 +1,381 @@
+//! Magnet URIs (BEP 9, BEP 53 and the v2 `urn:btmh` form of BEP 52).
+
+use crate::torrent::meta::{InfoHash, TorrentFile, TorrentFileError, WebSeedKind};
+use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use std::ops::RangeInclusive;
+
+const MAGNET_PREFIX: &str = "magnet:?";
+const BTIH_PREFIX: &str = "urn:btih:";
+const BTMH_PREFIX: &str = "urn:btmh:";
+/// Multihash header of a SHA-256 digest: function code 0x12, 32 bytes long.
+const SHA256_MULTIHASH_HEADER: &str = "1220";
+const BASE32_ALPHABET: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+/// RFC 3986 unreserved characters are kept as is, everything else is escaped.
+const VALUE_ENCODE_SET: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~');
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MagnetLink {
+    /// `xt`: one hash for v1 or v2 links, both for hybrid ones.
+    pub info_hashes: Vec<InfoHash>,
+    /// `dn`
+    pub display_name: Option<String>,
+    /// `tr`
+    pub trackers: Vec<String>,
+    /// `ws`
+    pub web_seeds: Vec<String>,
+    /// `x.pe`: `host:port` of peers to connect to directly.
+    pub peers: Vec<String>,
+    /// `so` (BEP 53): indices of files to download.
+    pub select_only: Vec<RangeInclusive<usize>>,
+}
+
+#[derive(Debug, thiserror::Error, PartialEq)]
+pub enum MagnetError {
+    #[error("magnet link should start with {MAGNET_PREFIX}")]
+    MissingPrefix,
+    #[error("magnet link has no BitTorrent info hash")]
+    MissingInfoHash,
+    #[error("invalid info hash: {0}")]
+    InvalidInfoHash(String),
+    #[error("invalid file selection: {0}")]
+    InvalidSelectOnly(String),
+    #[error("parameter {0} is not valid UTF-8")]
+    InvalidEncoding(String),
+}
+
+impl MagnetLink {
+    pub fn parse(link: &str) -> Result<Self, MagnetError> {
+        let query = link
+            .strip_prefix(MAGNET_PREFIX)
+            .ok_or(MagnetError::MissingPrefix)?;
+
+        let mut magnet = MagnetLink::default();
+        for parameter in query.split('&').filter(|p| !p.is_empty()) {
+            let (key, raw_value) = parameter.split_once('=').unwrap_or((parameter, ""));
+            let value = decode_value(key, raw_value)?;
+            match parameter_name(key) {
+                "xt" => {
+                    if let Some(info_hash) = parse_exact_topic(&value)? {
+                        if !magnet.info_hashes.contains(&info_hash) {
+                            magnet.info_hashes.push(info_hash);
+                        }
+                    }
+                }
+                "dn" => magnet.display_name = Some(value),
+                "tr" => magnet.trackers.push(value),
+                "ws" => magnet.web_seeds.push(value),
+                "x.pe" => magnet.peers.push(value),
+                "so" => magnet.select_only.extend(parse_select_only(&value)?),
+                // Unknown parameters are allowed by the spec.
+                _ => {}
+            }
+        }
+
+        if magnet.info_hashes.is_empty() {
+            return Err(MagnetError::MissingInfoHash);
+        }
+        Ok(magnet)
+    }
+
+    /// Builds a magnet link for `torrent_file` with all its trackers and web seeds.
+    pub fn from_torrent(torrent_file: &TorrentFile) -> Result<Self, TorrentFileError> {
+        let mut trackers = vec![torrent_file.announce.clone()];
+        for tier in torrent_file.announce_list.iter().flatten() {
+            for tracker in tier {
+                if !trackers.contains(tracker) {
+                    trackers.push(tracker.clone());
+                }
+            }
+        }
+        Ok(Self {
+            info_hashes: torrent_file.info_hashes()?,
+            display_name: Some(torrent_file.info().name.clone()),
+            trackers,
+            web_seeds: torrent_file
+                .web_seeds()
+                .into_iter()
+                .filter(|web_seed| web_seed.kind == WebSeedKind::UrlList)
+                .map(|web_seed| web_seed.url)
+                .collect(),
+            peers: Vec::new(),
+            select_only: Vec::new(),
+        })
+    }
+}
+
+impl std::str::FromStr for MagnetLink {
+    type Err = MagnetError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::parse(s)
+    }
+}
+
+impl std::fmt::Display for MagnetLink {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut parameters = Vec::new();
+        for info_hash in &self.info_hashes {
+            match info_hash {
+                InfoHash::V1(hash) => {
+                    parameters.push(format!("xt={}{}", BTIH_PREFIX, hex::encode(hash)))
+                }
+                InfoHash::V2(hash) => parameters.push(format!(
+                    "xt={}{}{}",
+                    BTMH_PREFIX,
+                    SHA256_MULTIHASH_HEADER,
+                    hex::encode(hash)
+                )),
+            }
+        }
+        if let Some(display_name) = &self.display_name {
+            parameters.push(format!("dn={}", encode_value(display_name)));
+        }
+        for tracker in &self.trackers {
+            parameters.push(format!("tr={}", encode_value(tracker)));
+        }
+        for web_seed in &self.web_seeds {
+            parameters.push(format!("ws={}", encode_value(web_seed)));
+        }
+        for peer in &self.peers {
+            parameters.push(format!("x.pe={}", encode_value(peer)));
+        }
+        if !self.select_only.is_empty() {
+            let ranges: Vec<String> = self
+                .select_only
+                .iter()
+                .map(|range| match range.start() == range.end() {
+                    true => range.start().to_string(),
+                    false => format!("{}-{}", range.start(), range.end()),
+                })
+                .collect();
+            parameters.push(format!("so={}", ranges.join(",")));
+        }
+        write!(f, "{}{}", MAGNET_PREFIX, parameters.join("&"))
+    }
+}
+
+/// Multiple values of one parameter may be numbered, like `tr.1`.
+fn parameter_name(key: &str) -> &str {
+    match key.split_once('.') {
+        Some((name, index)) if index.chars().all(|c| c.is_ascii_digit()) => name,
+        _ => key,
+    }
+}
+
+/// Percent-decodes a value. Only `dn` is form-encoded text, a `+` in URLs is literal.
+fn decode_value(key: &str, raw_value: &str) -> Result<String, MagnetError> {
+    let raw_value = match parameter_name(key) {
+        "dn" => raw_value.replace('+', " "),
+        _ => raw_value.to_string(),
+    };
+    percent_decode_str(&raw_value)
+        .decode_utf8()
+        .map(|value| value.into_owned())
+        .map_err(|_| MagnetError::InvalidEncoding(key.to_string()))
+}
+
+fn encode_value(value: &str) -> String {
+    utf8_percent_encode(value, VALUE_ENCODE_SET).to_string()
+}
+
+/// Parses `xt`, returning `None` for non BitTorrent topics.
+fn parse_exact_topic(value: &str) -> Result<Option<InfoHash>, MagnetError> {
+    let invalid = || MagnetError::InvalidInfoHash(value.to_string());
+    if let Some(encoded) = value.strip_prefix(BTIH_PREFIX) {
+        let bytes = match encoded.len() {
+            40 => hex::decode(encoded).map_err(|_| invalid())?,
+            32 => base32_decode(encoded).ok_or_else(invalid)?,
+            _ => return Err(invalid()),
+        };
+        let hash: [u8; 20] = bytes.try_into().map_err(|_| invalid())?;
+        return Ok(Some(InfoHash::V1(hash)));
+    }
+    if let Some(encoded) = value.strip_prefix(BTMH_PREFIX) {
+        let digest = encoded
+            .strip_prefix(SHA256_MULTIHASH_HEADER)
+            .ok_or_else(invalid)?;
+        let bytes = hex::decode(digest).map_err(|_| invalid())?;
+        let hash: [u8; 32] = bytes.try_into().map_err(|_| invalid())?;
+        return Ok(Some(InfoHash::V2(hash)));
+    }
+    Ok(None)
+}
+
+/// RFC 4648 base32 without padding, as used by old magnet links.
+fn base32_decode(encoded: &str) -> Option<Vec<u8>> {
+    let mut output = Vec::with_capacity(encoded.len() * 5 / 8);
+    let mut buffer: u64 = 0;
+    let mut bits = 0;
+    for c in encoded.bytes() {
+        let value = BASE32_ALPHABET
+            .iter()
+            .position(|&a| a == c.to_ascii_uppercase())?;
+        buffer = (buffer << 5) | value as u64;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            output.push((buffer >> bits) as u8);
+        }
+    }
+    Some(output)
+}
+
+fn parse_select_only(value: &str) -> Result<Vec<RangeInclusive<usize>>, MagnetError> {
+    let invalid = || MagnetError::InvalidSelectOnly(value.to_string());
+    value
+        .split(',')
+        .map(|item| {
+            let (start, end) = item.split_once('-').unwrap_or((item, item));
+            let start: usize = start.parse().map_err(|_| invalid())?;
+            let end: usize = end.parse().map_err(|_| invalid())?;
+            if start > end {
+                return Err(invalid());
+            }
+            Ok(start..=end)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::torrent::meta::{MetaInfo, UrlList};
+
+    const HEX_HASH: &str = "c12fe1c06bba254a9dc9f519b335aa7c1367a88a";
+
+    #[test]
+    fn parse_v1_hex() {
+        let magnet = MagnetLink::parse(
+            "magnet:?xt=urn:btih:c12fe1c06bba254a9dc9f519b335aa7c1367a88a&dn=Ubuntu+22.04%20ISO&tr=http%3A%2F%2Ftracker.example%2Fannounce&tr.1=udp%3A%2F%2Fother.example%3A6969&ws=http%3A%2F%2Fmirror.example%2F&x.pe=10.0.0.1%3A6881&so=0,2,4-6",
+        )
+        .unwrap();
+
+        let expected_hash: [u8; 20] = hex::decode(HEX_HASH).unwrap().try_into().unwrap();
+        assert_eq!(magnet.info_hashes, vec![InfoHash::V1(expected_hash)]);
+        assert_eq!(magnet.display_name.as_deref(), Some("Ubuntu 22.04 ISO"));
+        assert_eq!(
+            magnet.trackers,
+            vec![
+                "http://tracker.example/announce",
+                "udp://other.example:6969"
+            ]
+        );
+        assert_eq!(magnet.web_seeds, vec!["http://mirror.example/"]);
+        assert_eq!(magnet.peers, vec!["10.0.0.1:6881"]);
+        assert_eq!(magnet.select_only, vec![0..=0, 2..=2, 4..=6]);
+    }
+
+    #[test]
+    fn plus_is_literal_outside_display_name() {
+        let magnet = MagnetLink::parse(&format!(
+            "magnet:?xt=urn:btih:{}&dn=a+b&tr=http://t.example/a+b&ws=http://m.example/c+d",
+            HEX_HASH
+        ))
+        .unwrap();
+        assert_eq!(magnet.display_name.as_deref(), Some("a b"));
+        assert_eq!(magnet.trackers, vec!["http://t.example/a+b"]);
+        assert_eq!(magnet.web_seeds, vec!["http://m.example/c+d"]);
+    }
+
+    #[test]
+    fn parse_v1_base32() {
+        let hex_magnet = MagnetLink::parse(&format!("magnet:?xt=urn:btih:{}", HEX_HASH)).unwrap();
+        let base32_magnet =
+            MagnetLink::parse("magnet:?xt=urn:btih:YEX6DQDLXISUVHOJ6UM3GNNKPQJWPKEK").unwrap();
+        assert_eq!(hex_magnet, base32_magnet);
+    }
+
+    #[test]
+    fn parse_hybrid() {
+        let v2_hash = [0xAB; 32];
+        let link = format!(
+            "magnet:?xt=urn:btih:{}&xt=urn:btmh:1220{}",
+            HEX_HASH,
+            hex::encode(v2_hash)
+        );
+        let magnet = MagnetLink::parse(&link).unwrap();
+        assert_eq!(magnet.info_hashes.len(), 2);
+        assert_eq!(magnet.info_hashes[1], InfoHash::V2(v2_hash));
+        assert_eq!(magnet.to_string(), link);
+    }
+
+    #[test]
+    fn parse_errors() {
+        let cases = [
+            ("http://example.com", MagnetError::MissingPrefix),
+            ("magnet:?dn=foo", MagnetError::MissingInfoHash),
+            (
+                "magnet:?xt=urn:btih:abc",
+                MagnetError::InvalidInfoHash("urn:btih:abc".to_string()),
+            ),
+            (
+                "magnet:?xt=urn:btmh:1114abcd",
+                MagnetError::InvalidInfoHash("urn:btmh:1114abcd".to_string()),
+            ),
+            (
+                &format!("magnet:?xt=urn:btih:{}&so=3-1", HEX_HASH),
+                MagnetError::InvalidSelectOnly("3-1".to_string()),
+            ),
+        ];
+        for (link, expected) in cases {
+            assert_eq!(MagnetLink::parse(link), Err(expected), "{}", link);
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let magnet = MagnetLink {
+            info_hashes: vec![InfoHash::V1([7; 20])],
+            display_name: Some("data set/ü".to_string()),
+            trackers: vec!["http://t.example/announce?passkey=a&b".to_string()],
+            web_seeds: vec!["http://mirror.example/".to_string()],
+            peers: vec!["[::1]:6881".to_string()],
+            select_only: vec![1..=1, 3..=5],
+        };
+        let link = magnet.to_string();
+        assert!(link.starts_with("magnet:?xt=urn:btih:0707"));
+        assert!(link.contains("&so=1,3-5"));
+        assert_eq!(link.parse::<MagnetLink>().unwrap(), magnet);
+    }
+
+    #[test]
+    fn from_torrent() {
+        let info = MetaInfo {
+            length: Some(1),
+            files: None,
+            name: "file.bin".to_string(),
+            piece_length: 16384,
+            pieces: vec![0; 20],
+            meta_version: None,
+            file_tree: None,
+            private: None,
+            source: None,
+        };
+        let mut torrent = TorrentFile::new("http://a.example/announce".to_string(), info);
+        torrent.announce_list = Some(vec![
+            vec!["http://a.example/announce".to_string()],
+            vec!["http://b.example/announce".to_string()],
+        ]);
+        torrent.url_list = Some(UrlList::Single(
+            "http://mirror.example/file.bin".to_string(),
+        ));
+        torrent.httpseeds = Some(vec!["http://seed.example/".to_string()]);
+
+        let magnet = MagnetLink::from_torrent(&torrent).unwrap();
+        assert_eq!(
+            magnet.info_hashes,
+            vec![InfoHash::V1(torrent.meta_hash().unwrap())]
+        );
+        assert_eq!(magnet.display_name.as_deref(), Some("file.bin"));
+        assert_eq!(
+            magnet.trackers,
+            vec!["http://a.example/announce", "http://b.example/announce"]
+        );
+        assert_eq!(magnet.web_seeds, vec!["http://mirror.example/file.bin"]);
+    }
+}
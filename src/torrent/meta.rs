@@ -1,18 +1,182 @@
-use crate::bencode::{to_bencode, BencodeDeserializationError, BencodeDeserializer};
+use crate::bencode::{
+    to_bencode, BencodeDeserializationError, BencodeDeserializer, BencodeSerializationError,
+};
+use crate::torrent::layout::TorrentLayout;
 use crate::torrent::network::PieceInfo;
+use crate::torrent::v2::{self, FileTree, PieceLayers, Sha256Hash, V2File, V2VerificationError};
 use crate::torrent::SIXTEEN_KIBIBYTES;
 use serde::Deserialize;
+use serde_bytes::ByteBuf;
 use sha1::Digest;
+use std::collections::BTreeMap;
 use std::fs::File;
 use std::io::Read;
 use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 #[derive(Debug, serde::Deserialize, serde::Serialize)]
 pub struct TorrentFile {
     // TODO: How to do `& str`
     pub(crate) announce: String,
-    // comment: &'a str,
-    pub(crate) info: MetaInfo,
+    /// BEP 12: tiers of trackers, tried in order.
+    #[serde(
+        rename = "announce-list",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub(crate) announce_list: Option<Vec<Vec<String>>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) comment: Option<String>,
+    #[serde(
+        rename = "created by",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub(crate) created_by: Option<String>,
+    #[serde(
+        rename = "creation date",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub(crate) creation_date: Option<UnixTimestamp>,
+    /// Character encoding of the string fields, usually `UTF-8`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) encoding: Option<String>,
+    /// Private so every edit goes through [`TorrentFile::info_mut`].
+    info: MetaInfo,
+    #[serde(
+        rename = "piece layers",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub(crate) piece_layers: Option<PieceLayers>,
+    /// BEP 19 web seeds.
+    #[serde(rename = "url-list", default, skip_serializing_if = "Option::is_none")]
+    pub(crate) url_list: Option<UrlList>,
+    /// BEP 17 HTTP seeds.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) httpseeds: Option<Vec<String>>,
+    /// `info` exactly as it was read, so saving an edited torrent keeps its info hash.
+    #[serde(skip)]
+    raw_info: Option<Vec<u8>>,
+    /// Top-level keys not listed above, kept encoded.
+    #[serde(skip)]
+    extra: BTreeMap<Vec<u8>, Vec<u8>>,
+}
+
+/// Top-level keys mapped to fields of [`TorrentFile`].
+const KNOWN_KEYS: &[&[u8]] = &[
+    b"announce",
+    b"announce-list",
+    b"comment",
+    b"created by",
+    b"creation date",
+    b"encoding",
+    b"info",
+    b"piece layers",
+    b"url-list",
+    b"httpseeds",
+];
+
+/// `url-list` is either a single URL or a list of them, the original form is kept.
+#[derive(Debug, Clone, PartialEq, serde::Deserialize, serde::Serialize)]
+#[serde(untagged)]
+pub enum UrlList {
+    Single(String),
+    Multiple(Vec<String>),
+}
+
+impl UrlList {
+    pub fn urls(&self) -> &[String] {
+        match self {
+            UrlList::Single(url) => std::slice::from_ref(url),
+            UrlList::Multiple(urls) => urls,
+        }
+    }
+}
+
+impl From<Vec<String>> for UrlList {
+    fn from(urls: Vec<String>) -> Self {
+        UrlList::Multiple(urls)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WebSeedKind {
+    /// BEP 19 (GetRight style): plain HTTP/FTP server with the files of the torrent.
+    UrlList,
+    /// BEP 17 (Hoffman style): script that serves pieces by info hash.
+    HttpSeed,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WebSeed {
+    pub kind: WebSeedKind,
+    pub url: String,
+}
+
+/// Seconds since the UNIX epoch, as stored in `creation date`.
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, serde::Deserialize, serde::Serialize,
+)]
+#[serde(transparent)]
+pub struct UnixTimestamp(pub i64);
+
+impl UnixTimestamp {
+    pub fn now() -> Self {
+        let since_epoch = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        Self(since_epoch.as_secs() as i64)
+    }
+
+    pub fn as_system_time(&self) -> SystemTime {
+        let offset = Duration::from_secs(self.0.unsigned_abs());
+        if self.0 >= 0 {
+            UNIX_EPOCH + offset
+        } else {
+            UNIX_EPOCH - offset
+        }
+    }
+}
+
+impl From<SystemTime> for UnixTimestamp {
+    fn from(time: SystemTime) -> Self {
+        match time.duration_since(UNIX_EPOCH) {
+            Ok(after) => Self(after.as_secs() as i64),
+            Err(before) => Self(-(before.duration().as_secs() as i64)),
+        }
+    }
+}
+
+impl std::fmt::Display for UnixTimestamp {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let days = self.0.div_euclid(86_400);
+        let seconds_of_day = self.0.rem_euclid(86_400);
+
+        // Civil from days, see http://howardhinnant.github.io/date_algorithms.html
+        let z = days + 719_468;
+        let era = z.div_euclid(146_097);
+        let day_of_era = z.rem_euclid(146_097);
+        let year_of_era =
+            (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+        let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        let mp = (5 * day_of_year + 2) / 153;
+        let day = day_of_year - (153 * mp + 2) / 5 + 1;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 };
+        let year = year_of_era + era * 400 + i64::from(month <= 2);
+
+        write!(
+            f,
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC",
+            year,
+            month,
+            day,
+            seconds_of_day / 3600,
+            seconds_of_day % 3600 / 60,
+            seconds_of_day % 60
+        )
+    }
 }
 
 #[derive(Debug, thiserror::Error)]
@@ -20,74 +184,811 @@ pub enum TorrentFileError {
     #[error(transparent)]
     DeserializeError(#[from] BencodeDeserializationError),
 
+    #[error(transparent)]
+    Serialize(#[from] BencodeSerializationError),
+
     #[error("failed to open torrent file")]
     OpenError(#[from] std::io::Error),
 }
 
 impl TorrentFile {
+    pub fn new(announce: String, info: MetaInfo) -> Self {
+        Self {
+            announce,
+            announce_list: None,
+            comment: None,
+            created_by: None,
+            creation_date: None,
+            encoding: None,
+            info,
+            piece_layers: None,
+            url_list: None,
+            httpseeds: None,
+            raw_info: None,
+            extra: BTreeMap::new(),
+        }
+    }
+
     pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, TorrentFileError> {
         let mut file = File::open(path)?;
         let mut bytes = Vec::new();
         file.read_to_end(&mut bytes)?;
+        Self::from_bytes(&bytes)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, TorrentFileError> {
+        let mut deserializer = BencodeDeserializer::new(bytes);
+        let mut torrent = Self::deserialize(&mut deserializer)?;
+
+        for (key, value) in BencodeDeserializer::new(bytes).parse_raw_dict()? {
+            if key == b"info" {
+                torrent.raw_info = Some(value.to_vec());
+            } else if !KNOWN_KEYS.contains(&key) {
+                torrent.extra.insert(key.to_vec(), value.to_vec());
+            }
+        }
+        Ok(torrent)
+    }
+
+    /// Bencode of the whole torrent. A torrent that was read keeps its original `info`
+    /// bytes and unknown keys, everything else is written canonically.
+    pub fn to_bytes(&self) -> Result<Vec<u8>, TorrentFileError> {
+        let encoded = to_bencode(self)?;
+        if self.raw_info.is_none() && self.extra.is_empty() {
+            return Ok(encoded);
+        }
+
+        let mut entries: BTreeMap<&[u8], &[u8]> = BencodeDeserializer::new(&encoded)
+            .parse_raw_dict()?
+            .into_iter()
+            .collect();
+        if let Some(raw_info) = &self.raw_info {
+            entries.insert(b"info", raw_info);
+        }
+        for (key, value) in &self.extra {
+            entries.entry(key).or_insert(value);
+        }
 
-        let mut deserializer = BencodeDeserializer::new(&bytes);
-        Ok(Self::deserialize(&mut deserializer)?)
+        let mut output = vec![b'd'];
+        for (key, value) in entries {
+            output.extend_from_slice(key.len().to_string().as_bytes());
+            output.push(b':');
+            output.extend_from_slice(key);
+            output.extend_from_slice(value);
+        }
+        output.push(b'e');
+        Ok(output)
     }
 
-    pub fn meta_hash(&self) -> [u8; 20] {
-        // TODO: Change this unwrap to error
-        let raw_meta = to_bencode(&self.info).unwrap();
+    /// Bencoded `info` dictionary the info hashes are computed from.
+    pub fn info_bytes(&self) -> Result<Vec<u8>, TorrentFileError> {
+        match &self.raw_info {
+            Some(raw_info) => Ok(raw_info.clone()),
+            None => Ok(to_bencode(&self.info)?),
+        }
+    }
+
+    pub fn info(&self) -> &MetaInfo {
+        &self.info
+    }
+
+    /// Mutable access to `info`. The original bytes are dropped, so the info hash
+    /// is recomputed from the edited fields.
+    pub fn info_mut(&mut self) -> &mut MetaInfo {
+        self.raw_info = None;
+        &mut self.info
+    }
+
+    pub fn set_announce(&mut self, announce: String) {
+        self.announce = announce;
+    }
+
+    pub fn set_announce_list(&mut self, announce_list: Option<Vec<Vec<String>>>) {
+        self.announce_list = announce_list;
+    }
+
+    pub fn set_comment(&mut self, comment: Option<String>) {
+        self.comment = comment;
+    }
+
+    pub fn set_created_by(&mut self, created_by: Option<String>) {
+        self.created_by = created_by;
+    }
+
+    pub fn set_creation_date(&mut self, creation_date: Option<UnixTimestamp>) {
+        self.creation_date = creation_date;
+    }
+
+    pub fn set_url_list(&mut self, url_list: Option<UrlList>) {
+        self.url_list = url_list;
+    }
+
+    pub fn set_httpseeds(&mut self, httpseeds: Option<Vec<String>>) {
+        self.httpseeds = httpseeds;
+    }
+
+    /// Unknown top-level keys with their bencoded values.
+    pub fn extra(&self) -> &BTreeMap<Vec<u8>, Vec<u8>> {
+        &self.extra
+    }
+
+    pub fn remove_extra(&mut self, key: &[u8]) -> Option<Vec<u8>> {
+        self.extra.remove(key)
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), TorrentFileError> {
+        let bytes = self.to_bytes()?;
+        std::fs::write(path, bytes)?;
+        Ok(())
+    }
+
+    pub fn meta_hash(&self) -> Result<[u8; 20], TorrentFileError> {
+        let raw_meta = self.info_bytes()?;
 
         let mut hasher = sha1::Sha1::new();
         hasher.update(&raw_meta);
         let hash = hasher.finalize();
-        hash.into()
+        Ok(hash.into())
+    }
+
+    /// SHA-256 info hash used by v2 torrents.
+    pub fn meta_hash_v2(&self) -> Result<Sha256Hash, TorrentFileError> {
+        let raw_meta = self.info_bytes()?;
+        Ok(v2::sha256(&raw_meta))
+    }
+
+    /// Web seeds from both `url-list` and `httpseeds`, empty URLs are skipped.
+    pub fn web_seeds(&self) -> Vec<WebSeed> {
+        let url_list = self
+            .url_list
+            .iter()
+            .flat_map(|url_list| url_list.urls())
+            .map(|url| (WebSeedKind::UrlList, url));
+        let httpseeds = self
+            .httpseeds
+            .iter()
+            .flatten()
+            .map(|url| (WebSeedKind::HttpSeed, url));
+
+        url_list
+            .chain(httpseeds)
+            .filter(|(_, url)| !url.is_empty())
+            .map(|(kind, url)| WebSeed {
+                kind,
+                url: url.clone(),
+            })
+            .collect()
+    }
+
+    /// Info hashes of every swarm this torrent can join: v1, v2 or both for hybrid torrents.
+    pub fn info_hashes(&self) -> Result<Vec<InfoHash>, TorrentFileError> {
+        let mut hashes = Vec::with_capacity(2);
+        if self.info.is_v1() {
+            hashes.push(InfoHash::V1(self.meta_hash()?));
+        }
+        if self.info.is_v2() {
+            hashes.push(InfoHash::V2(self.meta_hash_v2()?));
+        }
+        Ok(hashes)
+    }
+
+    /// Hash used when the caller has no preference: v1 if present, as it reaches both swarms of
+    /// hybrid torrents through v1-only peers.
+    pub fn primary_info_hash(&self) -> Result<InfoHash, TorrentFileError> {
+        match self.info_hashes()?.into_iter().next() {
+            Some(info_hash) => Ok(info_hash),
+            None => Ok(InfoHash::V1(self.meta_hash()?)),
+        }
+    }
+
+    /// Verifies the full contents of a v2 `file` against its merkle root and piece layer.
+    pub fn verify_file_v2(&self, file: &V2File, data: &[u8]) -> Result<(), V2VerificationError> {
+        let empty_layers = PieceLayers::default();
+        let piece_layers = self.piece_layers.as_ref().unwrap_or(&empty_layers);
+        v2::verify_file(file, self.info.piece_length as u64, piece_layers, data)
+    }
+}
+
+/// Info hash of either protocol version.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum InfoHash {
+    V1([u8; 20]),
+    V2(Sha256Hash),
+}
+
+impl InfoHash {
+    /// 20 bytes form used by trackers and the peer handshake, v2 hashes are truncated.
+    pub fn truncated(&self) -> [u8; 20] {
+        match self {
+            InfoHash::V1(hash) => *hash,
+            InfoHash::V2(hash) => hash[..20].try_into().expect("v2 hash is 32 bytes"),
+        }
+    }
+
+    pub fn is_v2(&self) -> bool {
+        matches!(self, InfoHash::V2(_))
+    }
+}
+
+impl std::fmt::Display for InfoHash {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            InfoHash::V1(hash) => write!(f, "{}", hex::encode(hash)),
+            InfoHash::V2(hash) => write!(f, "{}", hex::encode(hash)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, thiserror::Error, PartialEq)]
+pub enum HybridLayoutError {
+    #[error("v1 lists {v1} files, while v2 file tree has {v2}")]
+    FileCountMismatch { v1: usize, v2: usize },
+    #[error("file #{index} differs: v1 has {v1_path} ({v1_length} bytes), v2 has {v2_path} ({v2_length} bytes)")]
+    FileMismatch {
+        index: usize,
+        v1_path: String,
+        v1_length: u64,
+        v2_path: String,
+        v2_length: u64,
+    },
+    #[error("file {0} is not padded to a piece boundary")]
+    MissingPadding(String),
+}
+
+/// Entry of the v1 multi-file `files` list.
+#[derive(Debug, Clone, PartialEq, serde::Deserialize, serde::Serialize)]
+pub struct FileEntry {
+    pub length: u64,
+    pub path: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub attr: Option<FileAttributes>,
+    /// Target of a symlink (`l` attribute), relative to the torrent root.
+    #[serde(
+        rename = "symlink path",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub symlink_path: Option<Vec<String>>,
+    /// SHA-1 of the whole file, helps to deduplicate files across torrents.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sha1: Option<ByteBuf>,
+}
+
+impl FileEntry {
+    pub fn new(path: Vec<String>, length: u64) -> Self {
+        Self {
+            length,
+            path,
+            attr: None,
+            symlink_path: None,
+            sha1: None,
+        }
+    }
+
+    pub fn attributes(&self) -> FileAttributes {
+        self.attr.clone().unwrap_or_default()
+    }
+
+    pub fn is_padding(&self) -> bool {
+        self.attributes().is_padding()
     }
 }
 
-#[derive(Debug, PartialEq, serde::Deserialize, serde::Serialize)]
+/// BEP 47 `attr` flags. The original string is kept, so unknown flags survive re-encoding.
+#[derive(Debug, Clone, Default, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+#[serde(transparent)]
+pub struct FileAttributes(String);
+
+impl FileAttributes {
+    pub const PADDING: char = 'p';
+    pub const EXECUTABLE: char = 'x';
+    pub const HIDDEN: char = 'h';
+    pub const SYMLINK: char = 'l';
+
+    pub fn new(flags: &str) -> Self {
+        Self(flags.to_string())
+    }
+
+    pub fn is_padding(&self) -> bool {
+        self.0.contains(Self::PADDING)
+    }
+
+    pub fn is_executable(&self) -> bool {
+        self.0.contains(Self::EXECUTABLE)
+    }
+
+    pub fn is_hidden(&self) -> bool {
+        self.0.contains(Self::HIDDEN)
+    }
+
+    pub fn is_symlink(&self) -> bool {
+        self.0.contains(Self::SYMLINK)
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+#[derive(Debug, PartialEq, serde::Deserialize)]
 pub struct MetaInfo {
-    pub length: usize,
+    /// Absent for v2-only torrents, where lengths are in the `file tree`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub length: Option<usize>,
+    /// Multi-file v1 torrents list their files here instead of `length`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub files: Option<Vec<FileEntry>>,
     pub name: String,
     //
     #[serde(rename = "piece length")]
     pub piece_length: usize,
     // pieces: &'a [u8],
-    #[serde(with = "serde_bytes")]
+    /// Empty and left out for v2-only torrents.
+    #[serde(default, with = "serde_bytes")]
     pub pieces: Vec<u8>,
+    #[serde(
+        rename = "meta version",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub meta_version: Option<u64>,
+    #[serde(rename = "file tree", default, skip_serializing_if = "Option::is_none")]
+    pub file_tree: Option<FileTree>,
+    /// BEP 27: peers should only be obtained from the listed trackers.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub private: Option<bool>,
+    /// Tag that makes the info hash unique per tracker, used by private trackers.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source: Option<String>,
+}
+
+/// Derived but for `pieces`, which only v2-only torrents leave out: a v1 torrent
+/// with an empty `pieces` must keep it, or its info hash changes.
+impl serde::Serialize for MetaInfo {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use serde::ser::SerializeStruct;
+
+        let mut info = serializer.serialize_struct("MetaInfo", 9)?;
+        if let Some(length) = &self.length {
+            info.serialize_field("length", length)?;
+        }
+        if let Some(files) = &self.files {
+            info.serialize_field("files", files)?;
+        }
+        info.serialize_field("name", &self.name)?;
+        info.serialize_field("piece length", &self.piece_length)?;
+        if !(self.pieces.is_empty() && self.meta_version == Some(2)) {
+            info.serialize_field("pieces", serde_bytes::Bytes::new(&self.pieces))?;
+        }
+        if let Some(meta_version) = &self.meta_version {
+            info.serialize_field("meta version", meta_version)?;
+        }
+        if let Some(file_tree) = &self.file_tree {
+            info.serialize_field("file tree", file_tree)?;
+        }
+        if let Some(private) = &self.private {
+            info.serialize_field("private", private)?;
+        }
+        if let Some(source) = &self.source {
+            info.serialize_field("source", source)?;
+        }
+        info.end()
+    }
 }
 
 impl MetaInfo {
-    pub fn as_piece_infos(&self) -> impl Iterator<Item = PieceInfo> + '_ {
-        let piece_length = self.piece_length as u64;
-        let block_size = SIXTEEN_KIBIBYTES;
-
-        let num_pieces = (self.length as u64).div_ceil(piece_length);
-
-        (0..num_pieces).flat_map(move |piece_index| {
-            let piece_size = if piece_index == num_pieces - 1 {
-                self.length as u64 - (piece_index * piece_length)
-            } else {
-                piece_length
-            };
-
-            let num_blocks = piece_size.div_ceil(block_size);
-
-            // For each piece, create an iterator over block indices
-            (0..num_blocks).map(move |block_idx| {
-                let begin_offset = block_idx * block_size;
-                let block_length = if block_idx == num_blocks - 1 {
-                    piece_size - (block_idx * block_size)
-                } else {
-                    block_size
-                };
-
-                PieceInfo {
-                    index: piece_index as u32,
-                    begin_bytes_offset: begin_offset as u32,
-                    length_bytes: block_length as u32,
+    pub fn is_private(&self) -> bool {
+        self.private.unwrap_or(false)
+    }
+
+    pub fn is_v1(&self) -> bool {
+        !self.pieces.is_empty()
+    }
+
+    pub fn is_v2(&self) -> bool {
+        self.meta_version == Some(2) && self.file_tree.is_some()
+    }
+
+    pub fn is_hybrid(&self) -> bool {
+        self.is_v1() && self.is_v2()
+    }
+
+    /// Total length of the v1 piece space, padding files included.
+    pub fn total_length(&self) -> usize {
+        if let Some(length) = self.length {
+            return length;
+        }
+        if let Some(files) = &self.files {
+            return files.iter().map(|file| file.length as usize).sum();
+        }
+        match &self.file_tree {
+            Some(file_tree) => file_tree.total_length() as usize,
+            None => 0,
+        }
+    }
+
+    /// Checks that the v1 `files`/`length` and the v2 `file tree` of a hybrid torrent describe
+    /// the same content, with v1 files padded to piece boundaries.
+    pub fn check_hybrid_layout(&self) -> Result<(), HybridLayoutError> {
+        let Some(file_tree) = &self.file_tree else {
+            return Ok(());
+        };
+        let v2_files: Vec<(String, u64)> = file_tree
+            .files()
+            .into_iter()
+            .map(|(path, file)| (path.join("/"), file.length))
+            .collect();
+
+        let v1_files: Vec<(String, u64)> = match (&self.files, self.length) {
+            (Some(files), _) => {
+                let piece_length = self.piece_length as u64;
+                let mut files_iter = files.iter().peekable();
+                let mut v1_files = Vec::new();
+                while let Some(file) = files_iter.next() {
+                    if file.is_padding() {
+                        continue;
+                    }
+                    let path = file.path.join("/");
+                    let is_last = files_iter.peek().is_none();
+                    let unaligned = file.length % piece_length;
+                    if unaligned != 0 && !is_last {
+                        match files_iter.peek() {
+                            Some(padding)
+                                if padding.is_padding()
+                                    && padding.length == piece_length - unaligned => {}
+                            _ => return Err(HybridLayoutError::MissingPadding(path)),
+                        }
+                    }
+                    v1_files.push((path, file.length));
                 }
-            })
-        })
+                v1_files
+            }
+            // Single file torrents keep the file name in the root of the tree.
+            (None, Some(length)) => vec![(self.name.clone(), length as u64)],
+            (None, None) => Vec::new(),
+        };
+
+        if v1_files.len() != v2_files.len() {
+            return Err(HybridLayoutError::FileCountMismatch {
+                v1: v1_files.len(),
+                v2: v2_files.len(),
+            });
+        }
+        for (index, ((v1_path, v1_length), (v2_path, v2_length))) in
+            v1_files.into_iter().zip(v2_files).enumerate()
+        {
+            if v1_path != v2_path || v1_length != v2_length {
+                return Err(HybridLayoutError::FileMismatch {
+                    index,
+                    v1_path,
+                    v1_length,
+                    v2_path,
+                    v2_length,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks `data` of piece `index` against the SHA-1 hashes in `pieces`.
+    pub fn verify_piece(&self, index: usize, data: &[u8]) -> bool {
+        let Some(expected) = self.pieces.chunks(20).nth(index) else {
+            return false;
+        };
+        let hash = sha1::Sha1::digest(data);
+        hash[..] == *expected
+    }
+
+    pub fn as_piece_infos(&self) -> impl Iterator<Item = PieceInfo> + '_ {
+        let layout = TorrentLayout::new(self);
+        (0..layout.num_pieces()).flat_map(move |index| layout.blocks(index, SIXTEEN_KIBIBYTES))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::torrent::v2::FileTreeNode;
+
+    #[test]
+    fn optional_metainfo_fields() {
+        let data = b"d8:announce20:http://t.example/ann7:comment5:hello10:created by9:mktorrent13:creation datei1700000000e8:encoding5:UTF-84:infod6:lengthi10e4:name4:file12:piece lengthi16384e6:pieces0:7:privatei1e6:source4:TESTee";
+        let mut deserializer = BencodeDeserializer::new(&data[..]);
+        let torrent = TorrentFile::deserialize(&mut deserializer).unwrap();
+
+        assert_eq!(torrent.comment.as_deref(), Some("hello"));
+        assert_eq!(torrent.created_by.as_deref(), Some("mktorrent"));
+        assert_eq!(torrent.creation_date, Some(UnixTimestamp(1_700_000_000)));
+        assert_eq!(torrent.encoding.as_deref(), Some("UTF-8"));
+        assert!(torrent.info.is_private());
+        assert_eq!(torrent.info.source.as_deref(), Some("TEST"));
+
+        assert_eq!(to_bencode(&torrent).unwrap(), data.to_vec());
+    }
+
+    #[test]
+    fn optional_metainfo_fields_absent() {
+        let data = b"d8:announce20:http://t.example/ann4:infod6:lengthi10e4:name4:file12:piece lengthi16384e6:pieces0:ee";
+        let mut deserializer = BencodeDeserializer::new(&data[..]);
+        let torrent = TorrentFile::deserialize(&mut deserializer).unwrap();
+
+        assert_eq!(torrent.comment, None);
+        assert_eq!(torrent.creation_date, None);
+        assert!(!torrent.info.is_private());
+        assert_eq!(to_bencode(&torrent).unwrap(), data.to_vec());
+    }
+
+    #[test]
+    fn unix_timestamp_display() {
+        assert_eq!(UnixTimestamp(0).to_string(), "1970-01-01 00:00:00 UTC");
+        assert_eq!(
+            UnixTimestamp(1_700_000_000).to_string(),
+            "2023-11-14 22:13:20 UTC"
+        );
+        assert_eq!(UnixTimestamp(-1).to_string(), "1969-12-31 23:59:59 UTC");
+    }
+
+    #[test]
+    fn v2_torrent() {
+        let piece_length = v2::MERKLE_BLOCK_SIZE * 2;
+        let content: Vec<u8> = (0..v2::MERKLE_BLOCK_SIZE * 3)
+            .map(|i| (i % 251) as u8)
+            .collect();
+        let root = v2::pieces_root(&content, piece_length).unwrap();
+        let layer = v2::piece_layer_hashes(&content, piece_length)
+            .unwrap()
+            .concat();
+
+        let data = [
+            &b"d8:announce20:http://t.example/ann4:infod9:file treed4:datad0:d6:lengthi49152e11:pieces root32:"[..],
+            &root[..],
+            &b"eee12:meta versioni2e4:name4:data12:piece lengthi32768ee12:piece layersd32:"[..],
+            &root[..],
+            &b"64:"[..],
+            &layer[..],
+            &b"ee"[..],
+        ]
+        .concat();
+
+        let mut deserializer = BencodeDeserializer::new(&data);
+        let torrent = TorrentFile::deserialize(&mut deserializer).unwrap();
+        assert!(torrent.info.is_v2());
+        assert!(torrent.info.pieces.is_empty());
+        assert_eq!(torrent.info.length, None);
+        assert_eq!(torrent.info.total_length(), content.len());
+        assert_eq!(to_bencode(&torrent).unwrap(), data);
+
+        let info_start = data.windows(6).position(|w| w == b"4:info").unwrap() + 6;
+        let info_end = data
+            .windows(15)
+            .position(|w| w == b"12:piece layers")
+            .unwrap();
+        assert_eq!(
+            torrent.meta_hash_v2().unwrap(),
+            v2::sha256(&data[info_start..info_end])
+        );
+
+        let files = torrent.info.file_tree.as_ref().unwrap().files();
+        let (_, file) = files[0];
+        assert_eq!(torrent.verify_file_v2(file, &content), Ok(()));
+        let mut corrupted = content.clone();
+        corrupted[0] ^= 1;
+        assert_eq!(
+            torrent.verify_file_v2(file, &corrupted),
+            Err(V2VerificationError::BadPieces(vec![0]))
+        );
+    }
+
+    #[test]
+    fn verify_v1_piece() {
+        let piece = b"hello world";
+        let info = MetaInfo {
+            length: Some(piece.len()),
+            files: None,
+            name: "hello".to_string(),
+            piece_length: 16384,
+            pieces: sha1::Sha1::digest(piece).to_vec(),
+            meta_version: None,
+            file_tree: None,
+            private: None,
+            source: None,
+        };
+        assert!(info.verify_piece(0, piece));
+        assert!(!info.verify_piece(0, b"hello there"));
+        assert!(!info.verify_piece(1, piece));
+    }
+
+    fn hybrid_info(files: Vec<FileEntry>) -> MetaInfo {
+        let tree_file = |length| FileTreeNode {
+            file: Some(V2File {
+                length,
+                pieces_root: Some(serde_bytes::ByteBuf::from(vec![1; 32])),
+            }),
+            children: Default::default(),
+        };
+        let file_tree = FileTree(
+            [
+                ("a".to_string(), tree_file(10)),
+                ("b".to_string(), tree_file(5)),
+            ]
+            .into(),
+        );
+        MetaInfo {
+            length: None,
+            files: Some(files),
+            name: "dir".to_string(),
+            piece_length: 16,
+            pieces: vec![0; 40],
+            meta_version: Some(2),
+            file_tree: Some(file_tree),
+            private: None,
+            source: None,
+        }
+    }
+
+    fn file_entry(path: &str, length: u64, attr: Option<&str>) -> FileEntry {
+        FileEntry {
+            attr: attr.map(FileAttributes::new),
+            ..FileEntry::new(vec![path.to_string()], length)
+        }
+    }
+
+    #[test]
+    fn hybrid_layout() {
+        let info = hybrid_info(vec![
+            file_entry("a", 10, None),
+            file_entry(".pad/6", 6, Some("p")),
+            file_entry("b", 5, None),
+        ]);
+        assert!(info.is_hybrid());
+        assert_eq!(info.total_length(), 21);
+        assert_eq!(info.check_hybrid_layout(), Ok(()));
+
+        let missing_padding =
+            hybrid_info(vec![file_entry("a", 10, None), file_entry("b", 5, None)]);
+        assert_eq!(
+            missing_padding.check_hybrid_layout(),
+            Err(HybridLayoutError::MissingPadding("a".to_string()))
+        );
+
+        let different_file = hybrid_info(vec![
+            file_entry("a", 10, None),
+            file_entry(".pad/6", 6, Some("p")),
+            file_entry("c", 5, None),
+        ]);
+        assert!(matches!(
+            different_file.check_hybrid_layout(),
+            Err(HybridLayoutError::FileMismatch { index: 1, .. })
+        ));
+
+        let extra_file = hybrid_info(vec![file_entry("a", 10, None)]);
+        assert_eq!(
+            extra_file.check_hybrid_layout(),
+            Err(HybridLayoutError::FileCountMismatch { v1: 1, v2: 2 })
+        );
+    }
+
+    #[test]
+    fn hybrid_info_hashes() {
+        let torrent = TorrentFile::new(
+            "http://t.example/ann".to_string(),
+            hybrid_info(vec![
+                file_entry("a", 10, None),
+                file_entry(".pad/6", 6, Some("p")),
+                file_entry("b", 5, None),
+            ]),
+        );
+
+        let hashes = torrent.info_hashes().unwrap();
+        assert_eq!(
+            hashes,
+            vec![
+                InfoHash::V1(torrent.meta_hash().unwrap()),
+                InfoHash::V2(torrent.meta_hash_v2().unwrap())
+            ]
+        );
+        assert_eq!(torrent.primary_info_hash().unwrap(), hashes[0]);
+        assert_eq!(
+            hashes[1].truncated()[..],
+            torrent.meta_hash_v2().unwrap()[..20]
+        );
+    }
+
+    #[test]
+    fn web_seeds() {
+        let single = b"d8:announce20:http://t.example/ann4:infod6:lengthi10e4:name4:file12:piece lengthi16384e6:pieces20:aaaaaaaaaaaaaaaaaaaae8:url-list22:http://mirror.example/e";
+        let mut deserializer = BencodeDeserializer::new(&single[..]);
+        let torrent = TorrentFile::deserialize(&mut deserializer).unwrap();
+        assert_eq!(
+            torrent.url_list,
+            Some(UrlList::Single("http://mirror.example/".to_string()))
+        );
+        assert_eq!(
+            torrent.web_seeds(),
+            vec![WebSeed {
+                kind: WebSeedKind::UrlList,
+                url: "http://mirror.example/".to_string()
+            }]
+        );
+        assert_eq!(to_bencode(&torrent).unwrap(), single.to_vec());
+
+        let multiple = b"d8:announce20:http://t.example/ann9:httpseedsl24:http://seed.example/seede4:infod6:lengthi10e4:name4:file12:piece lengthi16384e6:pieces20:aaaaaaaaaaaaaaaaaaaae8:url-listl0:18:http://a.example/f18:http://b.example/fee";
+        let mut deserializer = BencodeDeserializer::new(&multiple[..]);
+        let torrent = TorrentFile::deserialize(&mut deserializer).unwrap();
+        let urls: Vec<(WebSeedKind, String)> = torrent
+            .web_seeds()
+            .into_iter()
+            .map(|seed| (seed.kind, seed.url))
+            .collect();
+        assert_eq!(
+            urls,
+            vec![
+                (WebSeedKind::UrlList, "http://a.example/f".to_string()),
+                (WebSeedKind::UrlList, "http://b.example/f".to_string()),
+                (
+                    WebSeedKind::HttpSeed,
+                    "http://seed.example/seed".to_string()
+                ),
+            ]
+        );
+        assert_eq!(to_bencode(&torrent).unwrap(), multiple.to_vec());
+    }
+
+    #[test]
+    fn editing_keeps_info_bytes_and_unknown_keys() {
+        // `info` has a key `MetaInfo` does not know, re-encoding it would change the hash.
+        let original = b"d8:announce20:http://t.example/ann4:infod6:lengthi10e4:name4:file12:piece lengthi16384e6:pieces20:aaaaaaaaaaaaaaaaaaaa7:x-extra3:abce5:nodesll9:127.0.0.1i6881eeee";
+        let mut torrent = TorrentFile::from_bytes(original).unwrap();
+        let info_hash = torrent.meta_hash().unwrap();
+        let info_start = b"d8:announce20:http://t.example/ann4:info".len();
+        let info_end = original.len() - b"5:nodesll9:127.0.0.1i6881eeee".len();
+        let raw_info = &original[info_start..info_end];
+        assert_eq!(info_hash, sha1::Sha1::digest(raw_info).as_slice());
+        assert_eq!(torrent.to_bytes().unwrap(), original.to_vec());
+
+        torrent.set_announce("udp://t2.example:6969".to_string());
+        torrent.set_comment(Some("edited".to_string()));
+        torrent.set_url_list(Some(UrlList::Single("http://m.example/".to_string())));
+        let edited = TorrentFile::from_bytes(&torrent.to_bytes().unwrap()).unwrap();
+        assert_eq!(edited.meta_hash().unwrap(), info_hash);
+        assert_eq!(edited.announce, "udp://t2.example:6969");
+        assert_eq!(edited.comment.as_deref(), Some("edited"));
+        assert_eq!(
+            edited.extra().get(&b"nodes"[..]).map(Vec::as_slice),
+            Some(&b"ll9:127.0.0.1i6881eee"[..])
+        );
+
+        torrent.info_mut().name = "renamed".to_string();
+        assert_ne!(torrent.meta_hash().unwrap(), info_hash);
+    }
+
+    #[test]
+    fn file_attributes() {
+        let data = [
+            &b"d5:filesl"[..],
+            &b"d4:attr2:xh6:lengthi3e4:pathl3:rune4:sha120:"[..],
+            &[9; 20][..],
+            &b"ed4:attr1:p6:lengthi13e4:pathl4:.pad2:13eed4:attr1:l6:lengthi0e4:pathl4:linke12:symlink pathl3:runee"[..],
+            &b"e4:name3:dir12:piece lengthi16e6:pieces20:aaaaaaaaaaaaaaaaaaaae"[..],
+        ]
+        .concat();
+        let mut deserializer = BencodeDeserializer::new(&data);
+        let info = MetaInfo::deserialize(&mut deserializer).unwrap();
+        let files = info.files.as_ref().unwrap();
+
+        let run = files[0].attributes();
+        assert!(run.is_executable() && run.is_hidden());
+        assert!(!run.is_padding() && !run.is_symlink());
+        assert_eq!(
+            files[0].sha1.as_ref().map(|sha1| sha1.to_vec()),
+            Some(vec![9; 20])
+        );
+
+        assert!(files[1].is_padding());
+
+        assert!(files[2].attributes().is_symlink());
+        assert_eq!(files[2].symlink_path, Some(vec!["run".to_string()]));
+
+        assert_eq!(to_bencode(&info).unwrap(), data);
     }
 }
@@ -2,8 +2,25 @@
 
 
 pub const SIXTEEN_KIBIBYTES: u64 = 16 * 1024;
+pub mod bitfield;
+pub mod builder;
+pub mod layout;
+pub mod magnet;
 pub mod meta;
 pub mod network;
+pub mod parallel;
+pub mod path;
+pub mod peer_id;
+pub mod proxy;
+pub mod random;
+pub mod recheck;
+pub mod session;
+pub mod storage;
+#[cfg(test)]
+pub(crate) mod test_util;
+pub mod tracker;
+pub mod v2;
+pub mod validation;
 
 #[cfg(test)]
 mod tests {
@@ -32,19 +49,19 @@ mod tests {
 
         let mut deserializer = BencodeDeserializer::new(&bytes);
         let torrent_file = TorrentFile::deserialize(&mut deserializer).unwrap();
-        println!("Filename: {:#?}", torrent_file.info.name);
+        println!("Filename: {:#?}", torrent_file.info().name);
         println!("Tracker URL: {:#?}", torrent_file.announce);
-        println!("Length: {}", torrent_file.info.length);
-        let raw_meta = to_bencode(&torrent_file.info).unwrap();
+        println!("Length: {}", torrent_file.info().total_length());
+        let raw_meta = to_bencode(torrent_file.info()).unwrap();
 
         let mut hasher = sha1::Sha1::new();
         hasher.update(&raw_meta);
         let hash = hasher.finalize();
         println!("Info Hash: {}", hex::encode(&hash[..]));
-        println!("Piece Length: {}", torrent_file.info.piece_length);
+        println!("Piece Length: {}", torrent_file.info().piece_length);
 
         println!("Piece Hashes: ");
-        for piece in torrent_file.info.pieces.chunks(20) {
+        for piece in torrent_file.info().pieces.chunks(20) {
             println!("{}", hex::encode(piece));
         }
 
@@ -63,7 +80,7 @@ mod tests {
             info_hash_encoded,
             peer_id_encoded,
             6881,
-            torrent_file.info.length
+            torrent_file.info().total_length()
         );
 
         let response = client.get(&url).send().expect("Failed to send GET request");
@@ -1,106 +1,38 @@
-use crate::bencode::BencodeDeserializer;
-use crate::torrent::meta::TorrentFile;
+use crate::torrent::meta::InfoHash;
+use crate::torrent::proxy::{self, ProxyConfig};
 use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
-use percent_encoding::{percent_encode, NON_ALPHANUMERIC};
-use serde::Deserialize;
 use std::io::{Read, Write};
 
-const PEER_ID: &[u8; 20] = b"-GT0001-NGO456789012";
-
-pub struct TorrentTrackerClient {
-    tracker_client: reqwest::blocking::Client,
-}
-
-impl TorrentTrackerClient {
-    pub fn new() -> Self {
-        Self {
-            tracker_client: reqwest::blocking::Client::new(),
-        }
-    }
-
-    pub fn get_peers(&self, torrent_file: &TorrentFile) -> Vec<std::net::SocketAddrV4> {
-        let hash = torrent_file.meta_hash();
-        let info_hash_encoded = percent_encode(&hash[..], NON_ALPHANUMERIC);
-        let peer_id = b"-GT0001-NGO456789012";
-        let peer_id_encoded = percent_encode(peer_id, NON_ALPHANUMERIC);
-
-        let url = format!(
-            "{}?info_hash={}&peer_id={}&port={}&uploaded=0&downloaded=0&left={}&compact=1",
-            torrent_file.announce,
-            info_hash_encoded,
-            peer_id_encoded,
-            6881,
-            torrent_file.info.length
-        );
-
-        let response = self
-            .tracker_client
-            .get(&url)
-            .send()
-            .expect("Failed to send GET request");
-        println!("Response Status: {}", response.status());
-
-        let response_bytes = response.bytes().unwrap();
-
-        let mut deserializer = BencodeDeserializer::new(response_bytes.as_ref());
-        let tracker_response = RawTrackerResponse::deserialize(&mut deserializer)
-            .expect("Failed to deserialize tracker response");
-
-        let tracker_response = TrackerResponse::from(tracker_response);
-        tracker_response.peers
-    }
-}
-
-#[derive(Debug, PartialEq, serde::Deserialize, serde::Serialize)]
-pub struct RawTrackerResponse {
-    interval: u64,
-    #[serde(with = "serde_bytes")]
-    peers: Vec<u8>,
-    complete: u64,
-    incomplete: u64,
-}
-
-pub struct TrackerResponse {
-    interval: std::time::Duration,
-    peers: Vec<std::net::SocketAddrV4>,
-    complete: u64,
-    incomplete: u64,
-}
-
-// TODO: Convert to try_from with proper error
-impl From<RawTrackerResponse> for TrackerResponse {
-    fn from(tracker_response: RawTrackerResponse) -> Self {
-        let mut peers = Vec::new();
-        for chunk in tracker_response.peers.chunks(6) {
-            let octets: [u8; 4] = chunk[0..4].try_into().unwrap();
-            let port: [u8; 2] = chunk[4..6].try_into().unwrap();
-            let port: u16 = u16::from_be_bytes(port);
-            let ip_address = std::net::Ipv4Addr::from(octets);
-            peers.push(std::net::SocketAddrV4::new(ip_address, port));
-        }
-        let interval = std::time::Duration::from_secs(tracker_response.interval);
-
-        Self {
-            interval,
-            peers,
-            complete: tracker_response.complete,
-            incomplete: tracker_response.incomplete,
-        }
-    }
-}
+/// BEP 52: reserved bit announcing support of the v2 protocol.
+const V2_RESERVED_BIT: u8 = 0x10;
 
 pub struct PeerClient {
     stream: std::net::TcpStream,
 }
 
 impl PeerClient {
-    pub fn new(peer: std::net::SocketAddrV4, info_hash: [u8; 20]) -> Self {
-        let mut stream = std::net::TcpStream::connect(peer).expect("Failed to connect to peer");
-        let mut handshake = [0; 68];
-        handshake[0] = 19;
-        handshake[1..20].copy_from_slice(&b"BitTorrent protocol"[..]);
-        handshake[28..48].copy_from_slice(&info_hash);
-        handshake[48..68].copy_from_slice(&PEER_ID[..]);
+    /// Connects and shakes hands as `peer_id`, which should be the one announced to trackers.
+    /// `v2` tells whether the torrent has v2 metadata, hybrid torrents advertise it under
+    /// either info hash.
+    pub fn new(
+        peer: std::net::SocketAddr,
+        info_hash: InfoHash,
+        peer_id: [u8; 20],
+        v2: bool,
+    ) -> Self {
+        Self::connect(peer, info_hash, peer_id, v2, None)
+    }
+
+    /// Like [`Self::new`], through `proxy` if it covers peer connections.
+    pub fn connect(
+        peer: std::net::SocketAddr,
+        info_hash: InfoHash,
+        peer_id: [u8; 20],
+        v2: bool,
+        proxy: Option<&ProxyConfig>,
+    ) -> Self {
+        let mut stream = proxy::connect_peer(peer, proxy).expect("Failed to connect to peer");
+        let handshake = handshake(&info_hash, &peer_id, v2);
         println!("Sending bytes : {}", hex::encode(handshake));
         stream
             .write_all(&handshake)
@@ -122,6 +54,18 @@ impl PeerClient {
     }
 }
 
+fn handshake(info_hash: &InfoHash, peer_id: &[u8; 20], v2: bool) -> [u8; 68] {
+    let mut handshake = [0; 68];
+    handshake[0] = 19;
+    handshake[1..20].copy_from_slice(&b"BitTorrent protocol"[..]);
+    if v2 {
+        handshake[27] |= V2_RESERVED_BIT;
+    }
+    handshake[28..48].copy_from_slice(&info_hash.truncated());
+    handshake[48..68].copy_from_slice(peer_id);
+    handshake
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(u8)]
 pub enum PeerMessageType {
@@ -250,3 +194,21 @@ impl PeerMessage {
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hybrid_torrents_advertise_v2_under_the_v1_hash() {
+        let peer_id = *b"-BR0100-abcdefghijkl";
+        let v1_only = handshake(&InfoHash::V1([0xAA; 20]), &peer_id, false);
+        assert_eq!(v1_only[20..28], [0; 8]);
+        assert_eq!(v1_only[28..48], [0xAA; 20]);
+        assert_eq!(v1_only[48..], peer_id);
+
+        let hybrid = handshake(&InfoHash::V1([0xAA; 20]), &peer_id, true);
+        assert_eq!(hybrid[27], V2_RESERVED_BIT);
+        assert_eq!(hybrid[28..48], [0xAA; 20]);
+    }
+}
@@ -0,0 +1,79 @@
+//! Work queue over a fixed number of scoped threads.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Runs `job` for indices `0..count` on `threads` threads, collecting results in order.
+pub fn run_parallel<T, F>(count: usize, threads: usize, job: F) -> std::io::Result<Vec<T>>
+where
+    T: Send,
+    F: Fn(usize) -> std::io::Result<T> + Sync,
+{
+    run_parallel_with(count, threads, || (), |_, index| job(index))
+}
+
+/// Same as [`run_parallel`], with state created by `init` for each thread.
+pub fn run_parallel_with<S, T, I, F>(
+    count: usize,
+    threads: usize,
+    init: I,
+    job: F,
+) -> std::io::Result<Vec<T>>
+where
+    T: Send,
+    I: Fn() -> S + Sync,
+    F: Fn(&mut S, usize) -> std::io::Result<T> + Sync,
+{
+    let next = AtomicUsize::new(0);
+    let chunks: Vec<std::io::Result<Vec<(usize, T)>>> = std::thread::scope(|scope| {
+        let handles: Vec<_> = (0..threads.min(count).max(1))
+            .map(|_| {
+                scope.spawn(|| {
+                    let mut state = init();
+                    let mut results = Vec::new();
+                    loop {
+                        let index = next.fetch_add(1, Ordering::Relaxed);
+                        if index >= count {
+                            return Ok(results);
+                        }
+                        results.push((index, job(&mut state, index)?));
+                    }
+                })
+            })
+            .collect();
+        handles
+            .into_iter()
+            .map(|handle| handle.join().expect("worker thread panicked"))
+            .collect()
+    });
+
+    let mut indexed = Vec::with_capacity(count);
+    for chunk in chunks {
+        indexed.extend(chunk?);
+    }
+    indexed.sort_unstable_by_key(|(index, _)| *index);
+    Ok(indexed.into_iter().map(|(_, result)| result).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn results_keep_index_order() {
+        let squares = run_parallel(100, 4, |index| Ok(index * index)).unwrap();
+        assert_eq!(squares, (0..100).map(|i| i * i).collect::<Vec<_>>());
+
+        let calls = run_parallel_with(
+            10,
+            3,
+            || 0,
+            |calls: &mut usize, _| {
+                *calls += 1;
+                Ok(*calls)
+            },
+        )
+        .unwrap();
+        assert_eq!(calls.len(), 10);
+        assert!(run_parallel(0, 4, |_| Ok(())).unwrap().is_empty());
+    }
+}
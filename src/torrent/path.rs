@@ -0,0 +1,296 @@
+//! Mapping of untrusted torrent file names to paths under a download root.
+
+use std::collections::HashSet;
+use std::path::{Component, Path, PathBuf};
+
+/// Most file systems limit a single path component to 255 bytes.
+pub const MAX_COMPONENT_BYTES: usize = 255;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PathPolicy {
+    /// Replace offending characters and components, rename case collisions.
+    #[default]
+    Sanitize,
+    /// Fail on the first offending component.
+    Reject,
+}
+
+#[derive(Debug, thiserror::Error, PartialEq)]
+pub enum PathError {
+    #[error("path has no components")]
+    Empty,
+    #[error("empty path component in {0:?}")]
+    EmptyComponent(String),
+    #[error("relative component {component:?} in {path:?}")]
+    RelativeComponent { path: String, component: String },
+    #[error("path separator in component {0:?}")]
+    Separator(String),
+    #[error("control character in component {0:?}")]
+    ControlCharacter(String),
+    #[error("component {0:?} is longer than {MAX_COMPONENT_BYTES} bytes")]
+    TooLong(String),
+    #[error("{0:?} collides with another file when case is ignored")]
+    CaseCollision(String),
+    #[error("{0:?} does not stay inside the download root")]
+    OutsideRoot(PathBuf),
+}
+
+/// Resolves torrent paths below a download root, remembering what was handed out
+/// so files differing only in case do not overwrite each other.
+#[derive(Debug)]
+pub struct PathResolver {
+    root: PathBuf,
+    policy: PathPolicy,
+    used: HashSet<String>,
+}
+
+impl PathResolver {
+    pub fn new(root: &Path) -> Self {
+        Self {
+            root: root.to_path_buf(),
+            policy: PathPolicy::default(),
+            used: HashSet::new(),
+        }
+    }
+
+    pub fn policy(mut self, policy: PathPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Resolves a file path given as components relative to the download root,
+    /// usually the torrent name followed by the file path.
+    pub fn resolve(&mut self, components: &[String]) -> Result<PathBuf, PathError> {
+        let mut relative = self.sanitize_components(components)?;
+        let key = relative.join("/").to_lowercase();
+        if !self.used.insert(key) {
+            match self.policy {
+                PathPolicy::Reject => return Err(PathError::CaseCollision(relative.join("/"))),
+                PathPolicy::Sanitize => {
+                    let last = relative.pop().unwrap_or_default();
+                    let renamed = self.deduplicate(&relative, &last);
+                    relative.push(renamed);
+                }
+            }
+        }
+        self.join(&relative)
+    }
+
+    /// Resolves a path that refers to another file, such as a symlink target,
+    /// without reserving it.
+    pub fn resolve_reference(&self, components: &[String]) -> Result<PathBuf, PathError> {
+        let relative = self.sanitize_components(components)?;
+        self.join(&relative)
+    }
+
+    fn sanitize_components(&self, components: &[String]) -> Result<Vec<String>, PathError> {
+        if components.is_empty() {
+            return Err(PathError::Empty);
+        }
+        components
+            .iter()
+            .map(|component| self.sanitize(component, components))
+            .collect()
+    }
+
+    fn sanitize(&self, component: &str, path: &[String]) -> Result<String, PathError> {
+        let reject = self.policy == PathPolicy::Reject;
+        if component.is_empty() {
+            return match reject {
+                true => Err(PathError::EmptyComponent(path.join("/"))),
+                false => Ok("_".to_string()),
+            };
+        }
+        if component == "." || component == ".." {
+            return match reject {
+                true => Err(PathError::RelativeComponent {
+                    path: path.join("/"),
+                    component: component.to_string(),
+                }),
+                false => Ok(component.replace('.', "_")),
+            };
+        }
+        if reject {
+            if component.contains(['/', '\\']) {
+                return Err(PathError::Separator(component.to_string()));
+            }
+            if component.chars().any(char::is_control) {
+                return Err(PathError::ControlCharacter(component.to_string()));
+            }
+            if component.len() > MAX_COMPONENT_BYTES {
+                return Err(PathError::TooLong(component.to_string()));
+            }
+            return Ok(component.to_string());
+        }
+
+        let cleaned: String = component
+            .chars()
+            .map(|c| match c {
+                '/' | '\\' => '_',
+                c if c.is_control() => '_',
+                c => c,
+            })
+            .collect();
+        Ok(truncate(&cleaned, MAX_COMPONENT_BYTES))
+    }
+
+    /// Appends ` (n)` before the extension until the name is unused.
+    fn deduplicate(&mut self, parent: &[String], name: &str) -> String {
+        let (stem, extension) = match split_extension(name) {
+            // Same limit as `truncate`, a long "extension" is part of the name.
+            (stem, extension) if extension.len() <= MAX_COMPONENT_BYTES / 8 => (stem, extension),
+            _ => (name, ""),
+        };
+        for n in 1.. {
+            let suffix = format!(" ({}){}", n, extension);
+            let candidate =
+                truncate(stem, MAX_COMPONENT_BYTES.saturating_sub(suffix.len())) + &suffix;
+            let mut key = parent.to_vec();
+            key.push(candidate.clone());
+            if self.used.insert(key.join("/").to_lowercase()) {
+                return candidate;
+            }
+        }
+        unreachable!()
+    }
+
+    fn join(&self, relative: &[String]) -> Result<PathBuf, PathError> {
+        let mut path = self.root.clone();
+        for component in relative {
+            // Catches what sanitizing cannot see, like drive prefixes on Windows.
+            let mut parsed = Path::new(component).components();
+            match (parsed.next(), parsed.next()) {
+                (Some(Component::Normal(_)), None) => path.push(component),
+                _ => return Err(PathError::OutsideRoot(PathBuf::from(relative.join("/")))),
+            }
+        }
+        Ok(path)
+    }
+}
+
+/// Cuts `name` to at most `max` bytes on a char boundary, keeping a short extension.
+fn truncate(name: &str, max: usize) -> String {
+    if name.len() <= max {
+        return name.to_string();
+    }
+    let (stem, extension) = split_extension(name);
+    let (stem, extension) = match extension.len() <= max / 8 {
+        true => (stem, extension),
+        false => (name, ""),
+    };
+    let mut end = max - extension.len();
+    while !stem.is_char_boundary(end) {
+        end -= 1;
+    }
+    format!("{}{}", &stem[..end], extension)
+}
+
+fn split_extension(name: &str) -> (&str, &str) {
+    match name.rfind('.') {
+        Some(index) if index > 0 => name.split_at(index),
+        _ => (name, ""),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn components(path: &[&str]) -> Vec<String> {
+        path.iter().map(|c| c.to_string()).collect()
+    }
+
+    #[test]
+    fn stays_under_root() {
+        let root = Path::new("/downloads");
+        let mut resolver = PathResolver::new(root);
+        let cases = [
+            (
+                vec!["..", "..", "etc", "passwd"],
+                "/downloads/__/__/etc/passwd",
+            ),
+            (vec!["/etc", "shadow"], "/downloads/_etc/shadow"),
+            (vec!["a\\..\\b", "c\0d\n"], "/downloads/a_.._b/c_d_"),
+            (vec!["", "."], "/downloads/_/_"),
+        ];
+        for (path, expected) in cases {
+            let resolved = resolver.resolve(&components(&path)).unwrap();
+            assert_eq!(resolved, Path::new(expected));
+            assert!(resolved.starts_with(root));
+        }
+    }
+
+    #[test]
+    fn rejects_unsafe_components() {
+        let mut resolver = PathResolver::new(Path::new("root")).policy(PathPolicy::Reject);
+        assert_eq!(
+            resolver.resolve(&components(&["name", "..", "x"])),
+            Err(PathError::RelativeComponent {
+                path: "name/../x".to_string(),
+                component: "..".to_string()
+            })
+        );
+        assert_eq!(
+            resolver.resolve(&components(&["/abs"])),
+            Err(PathError::Separator("/abs".to_string()))
+        );
+        assert_eq!(
+            resolver.resolve(&components(&["a\0"])),
+            Err(PathError::ControlCharacter("a\0".to_string()))
+        );
+        assert_eq!(resolver.resolve(&[]), Err(PathError::Empty));
+        let long = "a".repeat(300);
+        assert_eq!(
+            resolver.resolve(std::slice::from_ref(&long)),
+            Err(PathError::TooLong(long))
+        );
+        assert!(resolver.resolve(&components(&["name", "File"])).is_ok());
+        assert_eq!(
+            resolver.resolve(&components(&["name", "FILE"])),
+            Err(PathError::CaseCollision("name/FILE".to_string()))
+        );
+    }
+
+    #[test]
+    fn renames_case_collisions() {
+        let mut resolver = PathResolver::new(Path::new("root"));
+        let first = resolver.resolve(&components(&["t", "Read.me"])).unwrap();
+        let second = resolver.resolve(&components(&["t", "READ.ME"])).unwrap();
+        let third = resolver.resolve(&components(&["t", "read.me"])).unwrap();
+        assert_eq!(first, Path::new("root/t/Read.me"));
+        assert_eq!(second, Path::new("root/t/READ (1).ME"));
+        assert_eq!(third, Path::new("root/t/read (2).me"));
+        // References do not reserve names.
+        let target = resolver
+            .resolve_reference(&components(&["t", "Read.me"]))
+            .unwrap();
+        assert_eq!(target, first);
+    }
+
+    #[test]
+    fn truncates_long_components() {
+        let mut resolver = PathResolver::new(Path::new("root"));
+        let name = format!("{}.mkv", "é".repeat(200));
+        let path = resolver.resolve(&[name]).unwrap();
+        let file_name = path.file_name().unwrap().to_str().unwrap();
+        assert!(file_name.len() <= MAX_COMPONENT_BYTES);
+        assert!(file_name.ends_with("é.mkv"));
+    }
+
+    #[test]
+    fn renames_collisions_with_long_extensions() {
+        let mut resolver = PathResolver::new(Path::new("root"));
+        let name = format!("a.{}", "x".repeat(253));
+        resolver.resolve(&components(&["t", &name])).unwrap();
+        let renamed = resolver
+            .resolve(&components(&["t", &name.to_uppercase()]))
+            .unwrap();
+        let file_name = renamed.file_name().unwrap().to_str().unwrap();
+        assert!(file_name.len() <= MAX_COMPONENT_BYTES);
+        assert!(file_name.ends_with(" (1)"));
+    }
+}
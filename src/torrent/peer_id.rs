@@ -0,0 +1,161 @@
+//! Azureus-style peer IDs: `-GT0100-` followed by 12 random characters.
+
+use crate::torrent::meta::InfoHash;
+use crate::torrent::random::random_u64;
+use std::collections::HashMap;
+use std::sync::{Mutex, OnceLock};
+
+/// Two-letter client code of the prefix.
+pub const CLIENT_CODE: [u8; 2] = *b"GT";
+
+const SUFFIX_ALPHABET: &[u8; 62] =
+    b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz";
+
+/// `-GT` and four version characters from the crate version, e.g. `-GT0100-` for 0.1.0.
+pub fn client_prefix() -> [u8; 8] {
+    let version = |component: &str| version_char(component.parse().unwrap_or(0));
+    [
+        b'-',
+        CLIENT_CODE[0],
+        CLIENT_CODE[1],
+        version(env!("CARGO_PKG_VERSION_MAJOR")),
+        version(env!("CARGO_PKG_VERSION_MINOR")),
+        version(env!("CARGO_PKG_VERSION_PATCH")),
+        b'0',
+        b'-',
+    ]
+}
+
+/// `0-9`, then `A-Z` for 10 to 35, larger components are capped at `Z`.
+fn version_char(component: u32) -> u8 {
+    match component {
+        0..=9 => b'0' + component as u8,
+        10..=35 => b'A' + (component - 10) as u8,
+        _ => b'Z',
+    }
+}
+
+/// A new peer ID with the client prefix and a random alphanumeric suffix.
+pub fn generate() -> [u8; 20] {
+    let mut peer_id = [0; 20];
+    peer_id[..8].copy_from_slice(&client_prefix());
+    let mut random = random_u64();
+    for (n, byte) in peer_id[8..].iter_mut().enumerate() {
+        if n == 10 {
+            // 62^10 is just below 2^60, the last two characters need fresh bits.
+            random = random_u64();
+        }
+        *byte = SUFFIX_ALPHABET[(random % 62) as usize];
+        random /= 62;
+    }
+    peer_id
+}
+
+/// Peer ID of this process, used wherever none is configured.
+pub fn session_peer_id() -> [u8; 20] {
+    static SESSION_PEER_ID: OnceLock<[u8; 20]> = OnceLock::new();
+    *SESSION_PEER_ID.get_or_init(generate)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PeerIdPolicy {
+    /// The same random ID for every torrent until the process exits.
+    #[default]
+    PerSession,
+    /// A random ID per torrent, so swarms cannot link our torrents.
+    PerTorrent,
+    Fixed([u8; 20]),
+}
+
+/// Hands out peer IDs by policy. Each torrent keeps its ID, so the tracker and the
+/// handshake see the same one.
+#[derive(Debug, Default)]
+pub struct PeerIdGenerator {
+    policy: PeerIdPolicy,
+    per_torrent: Mutex<HashMap<InfoHash, [u8; 20]>>,
+}
+
+impl PeerIdGenerator {
+    pub fn new(policy: PeerIdPolicy) -> Self {
+        Self {
+            policy,
+            per_torrent: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn policy(&self) -> PeerIdPolicy {
+        self.policy
+    }
+
+    /// Peer ID for the torrent with `info_hashes`. A hybrid torrent gets the same one
+    /// under its v1 and v2 hash.
+    pub fn peer_id_for(&self, info_hashes: &[InfoHash]) -> [u8; 20] {
+        match self.policy {
+            PeerIdPolicy::PerSession => session_peer_id(),
+            PeerIdPolicy::PerTorrent => {
+                let mut per_torrent = self.per_torrent.lock().expect("peer IDs lock poisoned");
+                let peer_id = info_hashes
+                    .iter()
+                    .find_map(|info_hash| per_torrent.get(info_hash).copied())
+                    .unwrap_or_else(generate);
+                for info_hash in info_hashes {
+                    per_torrent.insert(*info_hash, peer_id);
+                }
+                peer_id
+            }
+            PeerIdPolicy::Fixed(peer_id) => peer_id,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn prefix_follows_crate_version() {
+        let prefix = client_prefix();
+        assert_eq!(&prefix[..3], b"-GT");
+        assert_eq!(prefix[7], b'-');
+        let major: u32 = env!("CARGO_PKG_VERSION_MAJOR").parse().unwrap();
+        assert_eq!(prefix[3], version_char(major));
+        assert_eq!(version_char(12), b'C');
+
+        let peer_id = generate();
+        assert_eq!(peer_id[..8], prefix);
+        assert!(peer_id[8..].iter().all(u8::is_ascii_alphanumeric));
+        assert_ne!(peer_id, generate());
+    }
+
+    #[test]
+    fn policies() {
+        let first = InfoHash::V1([0x01; 20]);
+        let second = InfoHash::V1([0x02; 20]);
+
+        let session = PeerIdGenerator::new(PeerIdPolicy::PerSession);
+        assert_eq!(
+            session.peer_id_for(&[first]),
+            session.peer_id_for(&[second])
+        );
+        assert_eq!(session.peer_id_for(&[first]), session_peer_id());
+
+        let per_torrent = PeerIdGenerator::new(PeerIdPolicy::PerTorrent);
+        let id = per_torrent.peer_id_for(&[first]);
+        assert_eq!(per_torrent.peer_id_for(&[first]), id);
+        assert_ne!(per_torrent.peer_id_for(&[second]), id);
+
+        let fixed = PeerIdGenerator::new(PeerIdPolicy::Fixed(*b"-XX0000-000000000000"));
+        assert_eq!(&fixed.peer_id_for(&[second]), b"-XX0000-000000000000");
+    }
+
+    #[test]
+    fn hybrid_torrents_have_one_peer_id() {
+        let v1 = InfoHash::V1([0x01; 20]);
+        let v2 = InfoHash::V2([0x02; 32]);
+        let per_torrent = PeerIdGenerator::new(PeerIdPolicy::PerTorrent);
+        let id = per_torrent.peer_id_for(&[v1, v2]);
+        assert_eq!(per_torrent.peer_id_for(&[v2]), id);
+        assert_eq!(per_torrent.peer_id_for(&[v1]), id);
+        assert_eq!(per_torrent.peer_id_for(&[v2, v1]), id);
+    }
+}
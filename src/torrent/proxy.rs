@@ -0,0 +1,764 @@
+//! Outgoing connections through a SOCKS5 (RFC 1928) or HTTP CONNECT proxy.
+
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, SocketAddr, TcpStream, ToSocketAddrs, UdpSocket};
+use std::time::Duration;
+
+const SOCKS_VERSION: u8 = 5;
+const NO_AUTHENTICATION: u8 = 0x00;
+const USERNAME_PASSWORD: u8 = 0x02;
+const NO_ACCEPTABLE_METHOD: u8 = 0xFF;
+const COMMAND_CONNECT: u8 = 0x01;
+const COMMAND_UDP_ASSOCIATE: u8 = 0x03;
+const ADDRESS_IPV4: u8 = 0x01;
+const ADDRESS_DOMAIN: u8 = 0x03;
+const ADDRESS_IPV6: u8 = 0x04;
+const MAX_HTTP_REPLY_BYTES: usize = 8 * 1024;
+/// Default bound on connecting to the proxy and on each read or write of a handshake.
+pub const PROXY_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, thiserror::Error)]
+pub enum ProxyError {
+    #[error("proxy connection failed: {0}")]
+    Io(#[from] io::Error),
+    #[error("proxy accepts none of our authentication methods")]
+    NoAcceptableAuthentication,
+    #[error("proxy rejected the credentials")]
+    AuthenticationFailed,
+    #[error("SOCKS proxy refused the request with reply code {0}")]
+    Refused(u8),
+    #[error("HTTP proxy refused CONNECT: {0}")]
+    HttpStatus(String),
+    #[error("malformed proxy reply: {0}")]
+    InvalidReply(&'static str),
+    #[error("host name of {0} bytes is too long for SOCKS5")]
+    HostTooLong(usize),
+    #[error("SOCKS5 user names and passwords are limited to 255 bytes")]
+    CredentialsTooLong,
+    #[error("UDP needs a SOCKS5 proxy")]
+    UdpUnsupported,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProxyKind {
+    Socks5,
+    HttpConnect,
+}
+
+/// Which connections go through the proxy.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ProxyScope {
+    #[default]
+    Trackers,
+    TrackersAndPeers,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProxyConfig {
+    pub kind: ProxyKind,
+    /// `host:port` of the proxy.
+    pub address: String,
+    pub credentials: Option<(String, String)>,
+    pub scope: ProxyScope,
+    pub timeout: Duration,
+}
+
+impl ProxyConfig {
+    pub fn socks5(address: impl Into<String>) -> Self {
+        Self::new(ProxyKind::Socks5, address.into())
+    }
+
+    pub fn http_connect(address: impl Into<String>) -> Self {
+        Self::new(ProxyKind::HttpConnect, address.into())
+    }
+
+    fn new(kind: ProxyKind, address: String) -> Self {
+        Self {
+            kind,
+            address,
+            credentials: None,
+            scope: ProxyScope::default(),
+            timeout: PROXY_TIMEOUT,
+        }
+    }
+
+    pub fn credentials(mut self, username: impl Into<String>, password: impl Into<String>) -> Self {
+        self.credentials = Some((username.into(), password.into()));
+        self
+    }
+
+    pub fn scope(mut self, scope: ProxyScope) -> Self {
+        self.scope = scope;
+        self
+    }
+
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn proxies_peers(&self) -> bool {
+        self.scope == ProxyScope::TrackersAndPeers
+    }
+
+    /// The same proxy for reqwest. SOCKS5 leaves name resolution to the proxy.
+    pub fn to_reqwest(&self) -> Result<reqwest::Proxy, reqwest::Error> {
+        match self.kind {
+            ProxyKind::Socks5 => {
+                let credentials = match &self.credentials {
+                    Some((username, password)) => format!(
+                        "{}:{}@",
+                        utf8_percent_encode(username, NON_ALPHANUMERIC),
+                        utf8_percent_encode(password, NON_ALPHANUMERIC)
+                    ),
+                    None => String::new(),
+                };
+                reqwest::Proxy::all(format!("socks5h://{}{}", credentials, self.address))
+            }
+            ProxyKind::HttpConnect => {
+                let proxy = reqwest::Proxy::all(format!("http://{}", self.address))?;
+                Ok(match &self.credentials {
+                    Some((username, password)) => proxy.basic_auth(username, password),
+                    None => proxy,
+                })
+            }
+        }
+    }
+
+    /// A TCP stream to `host:port` through the proxy. `host` may be an IP address or
+    /// a name the proxy resolves.
+    pub fn connect(&self, host: &str, port: u16) -> Result<TcpStream, ProxyError> {
+        let mut stream = self.connect_to_proxy()?;
+        match self.kind {
+            ProxyKind::Socks5 => {
+                self.socks5_handshake(&mut stream)?;
+                socks5_request(&mut stream, COMMAND_CONNECT, host, port)?;
+            }
+            ProxyKind::HttpConnect => self.http_connect_handshake(&mut stream, host, port)?,
+        }
+        // The tunnel behaves like a direct connection from here on.
+        stream.set_read_timeout(None)?;
+        stream.set_write_timeout(None)?;
+        Ok(stream)
+    }
+
+    /// Opens a SOCKS5 UDP relay. It stays usable while the returned value lives.
+    pub fn udp_associate(&self) -> Result<Socks5UdpRelay, ProxyError> {
+        if self.kind != ProxyKind::Socks5 {
+            return Err(ProxyError::UdpUnsupported);
+        }
+        let mut control = self.connect_to_proxy()?;
+        self.socks5_handshake(&mut control)?;
+
+        let proxy_ip = control.peer_addr()?.ip();
+        let socket = UdpSocket::bind(SocketAddr::new(unspecified_like(proxy_ip), 0))?;
+        // We do not know our address as the proxy sees it, so announce none.
+        let local = socket.local_addr()?;
+        // A host name in the reply is not resolved here, it names the proxy itself.
+        let relay = match socks5_request(
+            &mut control,
+            COMMAND_UDP_ASSOCIATE,
+            &unspecified_like(proxy_ip).to_string(),
+            local.port(),
+        )? {
+            Address::Ip(relay) if !relay.ip().is_unspecified() => relay,
+            Address::Ip(relay) => SocketAddr::new(proxy_ip, relay.port()),
+            Address::Domain(_, port) => SocketAddr::new(proxy_ip, port),
+        };
+        control.set_read_timeout(None)?;
+        control.set_write_timeout(None)?;
+        Ok(Socks5UdpRelay {
+            _control: control,
+            socket,
+            relay,
+        })
+    }
+
+    /// A stream to the proxy itself, bounded by [`Self::timeout`] until the handshake
+    /// is done.
+    fn connect_to_proxy(&self) -> Result<TcpStream, ProxyError> {
+        let mut last_error = None;
+        for address in self.address.to_socket_addrs()? {
+            match TcpStream::connect_timeout(&address, self.timeout) {
+                Ok(stream) => {
+                    stream.set_read_timeout(Some(self.timeout))?;
+                    stream.set_write_timeout(Some(self.timeout))?;
+                    return Ok(stream);
+                }
+                Err(e) => last_error = Some(e),
+            }
+        }
+        Err(last_error
+            .unwrap_or_else(|| {
+                io::Error::new(io::ErrorKind::NotFound, "proxy address resolves to nothing")
+            })
+            .into())
+    }
+
+    fn socks5_handshake(&self, stream: &mut TcpStream) -> Result<(), ProxyError> {
+        let method = if self.credentials.is_some() {
+            USERNAME_PASSWORD
+        } else {
+            NO_AUTHENTICATION
+        };
+        stream.write_all(&[SOCKS_VERSION, 1, method])?;
+        let mut reply = [0; 2];
+        stream.read_exact(&mut reply)?;
+        if reply[0] != SOCKS_VERSION {
+            return Err(ProxyError::InvalidReply("not a SOCKS5 proxy"));
+        }
+        match reply[1] {
+            NO_AUTHENTICATION => Ok(()),
+            USERNAME_PASSWORD => {
+                let (username, password) = self
+                    .credentials
+                    .as_ref()
+                    .ok_or(ProxyError::NoAcceptableAuthentication)?;
+                // RFC 1929.
+                let mut request = vec![1];
+                for field in [username, password] {
+                    let length =
+                        u8::try_from(field.len()).map_err(|_| ProxyError::CredentialsTooLong)?;
+                    request.push(length);
+                    request.extend_from_slice(field.as_bytes());
+                }
+                stream.write_all(&request)?;
+                stream.read_exact(&mut reply)?;
+                if reply[1] != 0 {
+                    return Err(ProxyError::AuthenticationFailed);
+                }
+                Ok(())
+            }
+            NO_ACCEPTABLE_METHOD => Err(ProxyError::NoAcceptableAuthentication),
+            _ => Err(ProxyError::InvalidReply("unknown authentication method")),
+        }
+    }
+
+    fn http_connect_handshake(
+        &self,
+        stream: &mut TcpStream,
+        host: &str,
+        port: u16,
+    ) -> Result<(), ProxyError> {
+        let authority = match host.parse::<IpAddr>() {
+            Ok(IpAddr::V6(ip)) => format!("[{}]:{}", ip, port),
+            _ => format!("{}:{}", host, port),
+        };
+        let mut request = format!("CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n", authority);
+        if let Some((username, password)) = &self.credentials {
+            let token = base64(format!("{}:{}", username, password).as_bytes());
+            request.push_str(&format!("Proxy-Authorization: Basic {}\r\n", token));
+        }
+        request.push_str("\r\n");
+        stream.write_all(request.as_bytes())?;
+
+        // Read byte by byte, whatever follows the header belongs to the tunnel.
+        let mut reply = Vec::new();
+        let mut byte = [0; 1];
+        while !reply.ends_with(b"\r\n\r\n") {
+            if reply.len() > MAX_HTTP_REPLY_BYTES {
+                return Err(ProxyError::InvalidReply("HTTP reply header too long"));
+            }
+            stream.read_exact(&mut byte)?;
+            reply.push(byte[0]);
+        }
+        let reply = String::from_utf8_lossy(&reply);
+        let status_line = reply.lines().next().unwrap_or_default();
+        match status_line.split(' ').nth(1) {
+            Some(code) if code.starts_with('2') => Ok(()),
+            Some(_) => Err(ProxyError::HttpStatus(status_line.to_string())),
+            None => Err(ProxyError::InvalidReply("no HTTP status line")),
+        }
+    }
+}
+
+/// Opens a TCP stream to a peer, through `proxy` if it covers peers.
+pub fn connect_peer(
+    peer: SocketAddr,
+    proxy: Option<&ProxyConfig>,
+) -> Result<TcpStream, ProxyError> {
+    match proxy.filter(|proxy| proxy.proxies_peers()) {
+        Some(proxy) => proxy.connect(&peer.ip().to_string(), peer.port()),
+        None => Ok(TcpStream::connect(peer)?),
+    }
+}
+
+/// Sends a SOCKS5 request and returns the bound address of the reply.
+fn socks5_request(
+    stream: &mut TcpStream,
+    command: u8,
+    host: &str,
+    port: u16,
+) -> Result<Address, ProxyError> {
+    let mut request = vec![SOCKS_VERSION, command, 0];
+    write_address(&mut request, host, port)?;
+    stream.write_all(&request)?;
+
+    let mut header = [0; 3];
+    stream.read_exact(&mut header)?;
+    if header[0] != SOCKS_VERSION {
+        return Err(ProxyError::InvalidReply("not a SOCKS5 reply"));
+    }
+    if header[1] != 0 {
+        return Err(ProxyError::Refused(header[1]));
+    }
+    read_address(stream)
+}
+
+fn write_address(buffer: &mut Vec<u8>, host: &str, port: u16) -> Result<(), ProxyError> {
+    match host.parse::<IpAddr>() {
+        Ok(IpAddr::V4(ip)) => {
+            buffer.push(ADDRESS_IPV4);
+            buffer.extend_from_slice(&ip.octets());
+        }
+        Ok(IpAddr::V6(ip)) => {
+            buffer.push(ADDRESS_IPV6);
+            buffer.extend_from_slice(&ip.octets());
+        }
+        Err(_) => {
+            let length =
+                u8::try_from(host.len()).map_err(|_| ProxyError::HostTooLong(host.len()))?;
+            buffer.push(ADDRESS_DOMAIN);
+            buffer.push(length);
+            buffer.extend_from_slice(host.as_bytes());
+        }
+    }
+    buffer.extend_from_slice(&port.to_be_bytes());
+    Ok(())
+}
+
+/// An address as carried in SOCKS5 messages.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Address {
+    Ip(SocketAddr),
+    /// Left unresolved, so no name reaches the local resolver.
+    Domain(String, u16),
+}
+
+/// Reads `ATYP ADDR PORT`.
+fn read_address(reader: &mut impl Read) -> Result<Address, ProxyError> {
+    let mut kind = [0; 1];
+    reader.read_exact(&mut kind)?;
+    let ip: IpAddr = match kind[0] {
+        ADDRESS_IPV4 => {
+            let mut octets = [0; 4];
+            reader.read_exact(&mut octets)?;
+            octets.into()
+        }
+        ADDRESS_IPV6 => {
+            let mut octets = [0; 16];
+            reader.read_exact(&mut octets)?;
+            octets.into()
+        }
+        ADDRESS_DOMAIN => {
+            let mut length = [0; 1];
+            reader.read_exact(&mut length)?;
+            let mut host = vec![0; length[0] as usize];
+            reader.read_exact(&mut host)?;
+            let mut port = [0; 2];
+            reader.read_exact(&mut port)?;
+            let host = String::from_utf8(host)
+                .map_err(|_| ProxyError::InvalidReply("domain is not UTF-8"))?;
+            return Ok(Address::Domain(host, u16::from_be_bytes(port)));
+        }
+        _ => return Err(ProxyError::InvalidReply("unknown address type")),
+    };
+    let mut port = [0; 2];
+    reader.read_exact(&mut port)?;
+    Ok(Address::Ip(SocketAddr::new(ip, u16::from_be_bytes(port))))
+}
+
+fn unspecified_like(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V4(_) => IpAddr::V4(std::net::Ipv4Addr::UNSPECIFIED),
+        IpAddr::V6(_) => IpAddr::V6(std::net::Ipv6Addr::UNSPECIFIED),
+    }
+}
+
+/// UDP through a SOCKS5 relay: every datagram carries the real target in a header.
+#[derive(Debug)]
+pub struct Socks5UdpRelay {
+    /// The association ends when this connection closes.
+    _control: TcpStream,
+    socket: UdpSocket,
+    relay: SocketAddr,
+}
+
+impl Socks5UdpRelay {
+    /// Sends `data` to `host:port`. A host name is resolved by the proxy.
+    pub fn send_to(&self, data: &[u8], host: &str, port: u16) -> Result<usize, ProxyError> {
+        let mut datagram = vec![0, 0, 0];
+        write_address(&mut datagram, host, port)?;
+        datagram.extend_from_slice(data);
+        self.socket.send_to(&datagram, self.relay)?;
+        Ok(data.len())
+    }
+
+    /// Receives into `buffer` and returns the payload length with the original sender.
+    /// Datagrams not coming from the relay, fragmented ones and those naming the sender
+    /// by host name are skipped.
+    pub fn recv_from(&self, buffer: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        let mut datagram = vec![0; buffer.len() + 262];
+        loop {
+            let (length, from) = self.socket.recv_from(&mut datagram)?;
+            if from != self.relay || length < 4 || datagram[2] != 0 {
+                continue;
+            }
+            let mut reader = &datagram[3..length];
+            let Ok(Address::Ip(sender)) = read_address(&mut reader) else {
+                continue;
+            };
+            let payload = reader.len().min(buffer.len());
+            buffer[..payload].copy_from_slice(&reader[..payload]);
+            return Ok((payload, sender));
+        }
+    }
+
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.socket.set_read_timeout(timeout)
+    }
+}
+
+/// Standard base64 with padding, for `Proxy-Authorization`.
+fn base64(input: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut output = String::with_capacity(input.len().div_ceil(3) * 4);
+    for chunk in input.chunks(3) {
+        let bytes = [
+            chunk[0],
+            *chunk.get(1).unwrap_or(&0),
+            *chunk.get(2).unwrap_or(&0),
+        ];
+        let bits = u32::from_be_bytes([0, bytes[0], bytes[1], bytes[2]]);
+        for n in 0..4 {
+            if n <= chunk.len() {
+                output.push(ALPHABET[(bits >> (18 - 6 * n)) as usize & 63] as char);
+            } else {
+                output.push('=');
+            }
+        }
+    }
+    output
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::thread::JoinHandle;
+
+    /// Echoes everything back on each connection.
+    fn echo_server() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        std::thread::spawn(move || {
+            for mut stream in listener.incoming().flatten() {
+                std::thread::spawn(move || {
+                    let mut reader = stream.try_clone().unwrap();
+                    let _ = io::copy(&mut reader, &mut stream);
+                });
+            }
+        });
+        address
+    }
+
+    fn pipe(client: TcpStream, target: TcpStream) {
+        for (mut from, mut to) in [
+            (client.try_clone().unwrap(), target.try_clone().unwrap()),
+            (target, client),
+        ] {
+            std::thread::spawn(move || {
+                let _ = io::copy(&mut from, &mut to);
+                let _ = to.shutdown(std::net::Shutdown::Write);
+            });
+        }
+    }
+
+    /// SOCKS5 stand-in supporting CONNECT and UDP ASSOCIATE, optionally requiring
+    /// credentials. Returns its address and the commands it received.
+    pub(crate) fn socks5_proxy(
+        credentials: Option<(&'static str, &'static str)>,
+    ) -> (SocketAddr, std::sync::mpsc::Receiver<u8>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let (commands, received) = std::sync::mpsc::channel();
+        std::thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let commands = commands.clone();
+                std::thread::spawn(move || {
+                    let _ = serve_socks5(stream, credentials, commands);
+                });
+            }
+        });
+        (address, received)
+    }
+
+    fn serve_socks5(
+        mut stream: TcpStream,
+        credentials: Option<(&str, &str)>,
+        commands: std::sync::mpsc::Sender<u8>,
+    ) -> Result<(), ProxyError> {
+        let mut header = [0; 2];
+        stream.read_exact(&mut header)?;
+        let mut methods = vec![0; header[1] as usize];
+        stream.read_exact(&mut methods)?;
+        let wanted = if credentials.is_some() {
+            USERNAME_PASSWORD
+        } else {
+            NO_AUTHENTICATION
+        };
+        if !methods.contains(&wanted) {
+            stream.write_all(&[SOCKS_VERSION, NO_ACCEPTABLE_METHOD])?;
+            return Ok(());
+        }
+        stream.write_all(&[SOCKS_VERSION, wanted])?;
+        if let Some((username, password)) = credentials {
+            let field = |stream: &mut TcpStream| -> io::Result<Vec<u8>> {
+                let mut length = [0; 1];
+                stream.read_exact(&mut length)?;
+                let mut value = vec![0; length[0] as usize];
+                stream.read_exact(&mut value)?;
+                Ok(value)
+            };
+            let mut version = [0; 1];
+            stream.read_exact(&mut version)?;
+            let ok = field(&mut stream)? == username.as_bytes()
+                && field(&mut stream)? == password.as_bytes();
+            stream.write_all(&[1, u8::from(!ok)])?;
+            if !ok {
+                return Ok(());
+            }
+        }
+
+        let mut request = [0; 3];
+        stream.read_exact(&mut request)?;
+        let target = ip_address(&mut stream)?;
+        let _ = commands.send(request[1]);
+        let mut reply = vec![SOCKS_VERSION, 0, 0];
+        match request[1] {
+            COMMAND_CONNECT => {
+                let upstream = TcpStream::connect(target)?;
+                let bound = upstream.local_addr()?;
+                write_address(&mut reply, &bound.ip().to_string(), bound.port())?;
+                stream.write_all(&reply)?;
+                pipe(stream, upstream);
+            }
+            COMMAND_UDP_ASSOCIATE => {
+                let relay = UdpSocket::bind("127.0.0.1:0")?;
+                // Like many servers, reply with an unspecified address.
+                write_address(&mut reply, "0.0.0.0", relay.local_addr()?.port())?;
+                stream.write_all(&reply)?;
+                relay_udp(relay)?;
+            }
+            _ => stream.write_all(&[SOCKS_VERSION, 7, 0, ADDRESS_IPV4, 0, 0, 0, 0, 0, 0])?,
+        }
+        Ok(())
+    }
+
+    fn ip_address(reader: &mut impl Read) -> Result<SocketAddr, ProxyError> {
+        match read_address(reader)? {
+            Address::Ip(address) => Ok(address),
+            Address::Domain(..) => Err(ProxyError::InvalidReply("test proxy resolves nothing")),
+        }
+    }
+
+    /// Forwards datagrams of the first client to their targets and wraps replies.
+    fn relay_udp(relay: UdpSocket) -> Result<(), ProxyError> {
+        let mut client = None;
+        let mut buffer = [0; 2048];
+        loop {
+            let (length, from) = relay.recv_from(&mut buffer)?;
+            if client.is_none() || client == Some(from) {
+                client = Some(from);
+                let mut reader = &buffer[3..length];
+                let target = ip_address(&mut reader)?;
+                relay.send_to(reader, target)?;
+            } else if let Some(client) = client {
+                let mut datagram = vec![0, 0, 0];
+                write_address(&mut datagram, &from.ip().to_string(), from.port())?;
+                datagram.extend_from_slice(&buffer[..length]);
+                relay.send_to(&datagram, client)?;
+            }
+        }
+    }
+
+    /// HTTP CONNECT stand-in answering `status` to every request.
+    fn http_proxy(status: &'static str) -> (SocketAddr, JoinHandle<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let handle = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = Vec::new();
+            let mut byte = [0; 1];
+            while !request.ends_with(b"\r\n\r\n") {
+                stream.read_exact(&mut byte).unwrap();
+                request.push(byte[0]);
+            }
+            let request = String::from_utf8(request).unwrap();
+            let target = request.split(' ').nth(1).unwrap().to_string();
+            stream
+                .write_all(format!("HTTP/1.1 {}\r\n\r\n", status).as_bytes())
+                .unwrap();
+            if status.starts_with('2') {
+                pipe(stream, TcpStream::connect(target).unwrap());
+            }
+            request
+        });
+        (address, handle)
+    }
+
+    fn assert_echoes(mut stream: TcpStream) {
+        stream.write_all(b"hello").unwrap();
+        let mut reply = [0; 5];
+        stream.read_exact(&mut reply).unwrap();
+        assert_eq!(&reply, b"hello");
+    }
+
+    #[test]
+    fn socks5_connect_with_credentials() {
+        let echo = echo_server();
+        let (address, commands) = socks5_proxy(Some(("user", "secret")));
+        let proxy = ProxyConfig::socks5(address.to_string()).credentials("user", "secret");
+        assert_echoes(proxy.connect("127.0.0.1", echo.port()).unwrap());
+        assert_eq!(commands.recv().unwrap(), COMMAND_CONNECT);
+
+        let wrong = ProxyConfig::socks5(address.to_string()).credentials("user", "wrong");
+        assert!(matches!(
+            wrong.connect("127.0.0.1", echo.port()),
+            Err(ProxyError::AuthenticationFailed)
+        ));
+        assert!(matches!(
+            ProxyConfig::socks5(address.to_string()).connect("127.0.0.1", echo.port()),
+            Err(ProxyError::NoAcceptableAuthentication)
+        ));
+    }
+
+    #[test]
+    fn peers_use_the_proxy_only_when_configured() {
+        let echo = echo_server();
+        let (address, commands) = socks5_proxy(None);
+        let trackers_only = ProxyConfig::socks5(address.to_string());
+        assert_echoes(connect_peer(echo, Some(&trackers_only)).unwrap());
+        assert!(commands.try_recv().is_err());
+
+        let everything = trackers_only.scope(ProxyScope::TrackersAndPeers);
+        assert_echoes(connect_peer(echo, Some(&everything)).unwrap());
+        assert_eq!(commands.recv().unwrap(), COMMAND_CONNECT);
+    }
+
+    #[test]
+    fn http_connect() {
+        let echo = echo_server();
+        let (address, proxy) = http_proxy("200 Connection established");
+        let config = ProxyConfig::http_connect(address.to_string()).credentials("user", "pass");
+        assert_echoes(config.connect("127.0.0.1", echo.port()).unwrap());
+        let request = proxy.join().unwrap();
+        assert!(request.starts_with(&format!("CONNECT 127.0.0.1:{} HTTP/1.1\r\n", echo.port())));
+        assert!(request.contains("Proxy-Authorization: Basic dXNlcjpwYXNz\r\n"));
+
+        let (address, proxy) = http_proxy("407 Proxy Authentication Required");
+        assert!(matches!(
+            ProxyConfig::http_connect(address.to_string()).connect("127.0.0.1", echo.port()),
+            Err(ProxyError::HttpStatus(status)) if status.contains("407")
+        ));
+        proxy.join().unwrap();
+        assert!(matches!(
+            ProxyConfig::http_connect(address.to_string()).udp_associate(),
+            Err(ProxyError::UdpUnsupported)
+        ));
+    }
+
+    #[test]
+    fn silent_proxies_time_out() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let started = std::time::Instant::now();
+        for config in [
+            ProxyConfig::socks5(address.to_string()),
+            ProxyConfig::http_connect(address.to_string()),
+        ] {
+            let config = config.timeout(Duration::from_millis(200));
+            assert!(matches!(
+                config.connect("127.0.0.1", 6881),
+                Err(ProxyError::Io(_))
+            ));
+        }
+        assert!(matches!(
+            ProxyConfig::socks5(address.to_string())
+                .timeout(Duration::from_millis(200))
+                .udp_associate(),
+            Err(ProxyError::Io(_))
+        ));
+        assert!(started.elapsed() < Duration::from_secs(5));
+        drop(listener);
+    }
+
+    #[test]
+    fn udp_relay_leaves_host_names_to_the_proxy() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let proxy_side = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let relay = Socks5UdpRelay {
+            _control: TcpStream::connect(listener.local_addr().unwrap()).unwrap(),
+            socket: UdpSocket::bind("127.0.0.1:0").unwrap(),
+            relay: proxy_side.local_addr().unwrap(),
+        };
+        relay.send_to(b"ping", "tracker.example", 6969).unwrap();
+
+        let mut datagram = [0; 64];
+        let length = proxy_side.recv(&mut datagram).unwrap();
+        let mut expected = vec![0, 0, 0, ADDRESS_DOMAIN, 15];
+        expected.extend_from_slice(b"tracker.example");
+        expected.extend_from_slice(&6969u16.to_be_bytes());
+        expected.extend_from_slice(b"ping");
+        assert_eq!(&datagram[..length], &expected[..]);
+    }
+
+    #[test]
+    fn udp_relay_skips_replies_from_host_names() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let proxy_side = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let relay = Socks5UdpRelay {
+            _control: TcpStream::connect(listener.local_addr().unwrap()).unwrap(),
+            socket: UdpSocket::bind("127.0.0.1:0").unwrap(),
+            relay: proxy_side.local_addr().unwrap(),
+        };
+        relay
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let client = relay.socket.local_addr().unwrap();
+
+        let mut named = vec![0, 0, 0];
+        write_address(&mut named, "localhost", 6969).unwrap();
+        named.extend_from_slice(b"named");
+        proxy_side.send_to(&named, client).unwrap();
+        let mut numeric = vec![0, 0, 0];
+        write_address(&mut numeric, "127.0.0.1", 6969).unwrap();
+        numeric.extend_from_slice(b"numeric");
+        proxy_side.send_to(&numeric, client).unwrap();
+
+        let mut buffer = [0; 64];
+        let (length, sender) = relay.recv_from(&mut buffer).unwrap();
+        assert_eq!(&buffer[..length], b"numeric");
+        assert_eq!(sender, "127.0.0.1:6969".parse().unwrap());
+    }
+
+    #[test]
+    fn reads_domain_addresses_without_resolving() {
+        let mut message = Vec::new();
+        write_address(&mut message, "tracker.invalid", 6969).unwrap();
+        assert_eq!(
+            read_address(&mut &message[..]).unwrap(),
+            Address::Domain("tracker.invalid".to_string(), 6969)
+        );
+    }
+
+    #[test]
+    fn encodes_base64() {
+        assert_eq!(base64(b""), "");
+        assert_eq!(base64(b"f"), "Zg==");
+        assert_eq!(base64(b"fo"), "Zm8=");
+        assert_eq!(base64(b"foo"), "Zm9v");
+        assert_eq!(base64(b"user:pass"), "dXNlcjpwYXNz");
+    }
+}
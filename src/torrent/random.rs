@@ -0,0 +1,36 @@
+//! Non-cryptographic randomness for transaction IDs, keys and peer IDs.
+
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// Mixes the per-process random hasher keys with time and a counter, so
+/// consecutive calls differ even within the same clock tick.
+pub fn random_u64() -> u64 {
+    let mut hasher = RandomState::new().build_hasher();
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|time| time.as_nanos())
+        .unwrap_or_default();
+    hasher.write_u128(nanos);
+    hasher.write_u64(COUNTER.fetch_add(1, Ordering::Relaxed));
+    hasher.finish()
+}
+
+pub fn random_u32() -> u32 {
+    random_u64() as u32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn values_differ() {
+        let values: std::collections::HashSet<u64> = (0..100).map(|_| random_u64()).collect();
+        assert_eq!(values.len(), 100);
+    }
+}
@@ -0,0 +1,199 @@
+//! Verifying data already on disk against the v1 piece hashes.
+
+use crate::torrent::bitfield::Bitfield;
+use crate::torrent::meta::MetaInfo;
+use crate::torrent::parallel::run_parallel;
+use crate::torrent::storage::TorrentStorage;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU32, Ordering};
+
+#[derive(Debug, thiserror::Error)]
+pub enum RecheckError {
+    #[error("torrent has no v1 piece hashes to check against")]
+    NoPieceHashes,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RecheckProgress {
+    pub checked: u32,
+    pub total: u32,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FileCompletion {
+    pub path: PathBuf,
+    pub length: u64,
+    /// Bytes of the file covered by valid pieces.
+    pub verified: u64,
+}
+
+impl FileCompletion {
+    pub fn percent(&self) -> f64 {
+        match self.length {
+            0 => 100.0,
+            length => self.verified as f64 * 100.0 / length as f64,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RecheckReport {
+    pub pieces: Bitfield,
+    /// Pieces that are missing, short or do not match their hash.
+    pub bad_pieces: Vec<u32>,
+    /// Every file except padding, in torrent order.
+    pub files: Vec<FileCompletion>,
+}
+
+impl RecheckReport {
+    pub fn is_complete(&self) -> bool {
+        self.bad_pieces.is_empty()
+    }
+}
+
+/// Hashes every piece of a torrent from storage.
+pub struct Recheck<'a> {
+    storage: &'a TorrentStorage,
+    info: &'a MetaInfo,
+    threads: usize,
+    progress: Option<Box<dyn Fn(RecheckProgress) + Sync + 'a>>,
+}
+
+impl<'a> Recheck<'a> {
+    pub fn new(storage: &'a TorrentStorage, info: &'a MetaInfo) -> Self {
+        Self {
+            storage,
+            info,
+            threads: std::thread::available_parallelism()
+                .map(|threads| threads.get())
+                .unwrap_or(1),
+            progress: None,
+        }
+    }
+
+    pub fn threads(mut self, threads: usize) -> Self {
+        self.threads = threads.max(1);
+        self
+    }
+
+    /// Called from the hashing threads after every piece.
+    pub fn progress(mut self, progress: impl Fn(RecheckProgress) + Sync + 'a) -> Self {
+        self.progress = Some(Box::new(progress));
+        self
+    }
+
+    pub fn run(&self) -> Result<RecheckReport, RecheckError> {
+        if !self.info.is_v1() {
+            return Err(RecheckError::NoPieceHashes);
+        }
+        let layout = self.storage.layout();
+        let total = layout.num_pieces();
+        let checked = AtomicU32::new(0);
+
+        let results = run_parallel(total as usize, self.threads, |index| {
+            // Missing or short files simply make the piece bad.
+            let valid = match self.storage.read_piece(index as u32) {
+                Ok(data) => self.info.verify_piece(index, &data),
+                Err(_) => false,
+            };
+            if let Some(progress) = &self.progress {
+                let checked = checked.fetch_add(1, Ordering::Relaxed) + 1;
+                progress(RecheckProgress { checked, total });
+            }
+            Ok(valid)
+        })
+        .expect("piece checks do not fail");
+
+        let mut pieces = Bitfield::new(total as usize);
+        let mut bad_pieces = Vec::new();
+        let mut verified = vec![0; layout.files().len()];
+        for (index, valid) in results.into_iter().enumerate() {
+            if !valid {
+                bad_pieces.push(index as u32);
+                continue;
+            }
+            pieces.set(index, true);
+            for slice in layout.piece_slices(index as u32) {
+                verified[slice.file_index] += slice.length;
+            }
+        }
+
+        let files = self
+            .storage
+            .files()
+            .iter()
+            .zip(verified)
+            .filter(|(file, _)| !file.attributes.is_padding())
+            .map(|(file, verified)| FileCompletion {
+                path: file.path.clone(),
+                length: file.length,
+                verified,
+            })
+            .collect();
+
+        Ok(RecheckReport {
+            pieces,
+            bad_pieces,
+            files,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::torrent::meta::FileEntry;
+    use crate::torrent::test_util::TempDir;
+    use sha1::Digest;
+    use std::sync::atomic::AtomicUsize;
+
+    #[test]
+    fn reports_bad_pieces_and_file_completion() {
+        let dir = TempDir::new("recheck-files");
+        let content = b"0123456789abcdefghij";
+        let pieces: Vec<u8> = content
+            .chunks(8)
+            .flat_map(|piece| sha1::Sha1::digest(piece).to_vec())
+            .collect();
+        let info = MetaInfo {
+            length: None,
+            files: Some(vec![
+                FileEntry::new(vec!["a".to_string()], 12),
+                FileEntry::new(vec!["b".to_string()], 8),
+            ]),
+            name: "data".to_string(),
+            piece_length: 8,
+            pieces,
+            meta_version: None,
+            file_tree: None,
+            private: None,
+            source: None,
+        };
+        let storage = TorrentStorage::new(&dir.0, &info).unwrap();
+        storage.write(0, content).unwrap();
+        // Corrupt the second piece, which spans both files.
+        storage.write(9, b"X").unwrap();
+
+        let calls = AtomicUsize::new(0);
+        let report = Recheck::new(&storage, &info)
+            .threads(2)
+            .progress(|progress| {
+                assert_eq!(progress.total, 3);
+                calls.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+            })
+            .run()
+            .unwrap();
+
+        assert_eq!(calls.into_inner(), 3);
+        assert_eq!(report.bad_pieces, vec![1]);
+        assert_eq!(report.pieces.as_bytes(), &[0b1010_0000]);
+        assert_eq!(report.files[0].verified, 8);
+        assert_eq!(report.files[1].verified, 4);
+        assert_eq!(report.files[1].percent(), 50.0);
+
+        std::fs::remove_file(dir.0.join("data").join("b")).unwrap();
+        let report = Recheck::new(&storage, &info).run().unwrap();
+        assert_eq!(report.bad_pieces, vec![1, 2]);
+        assert!(!report.is_complete());
+    }
+}
@@ -0,0 +1,297 @@
+//! State shared by all torrents of a process: our public address and the DHT node ID
+//! derived from it.
+
+use crate::torrent::random::random_u64;
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Mutex;
+
+/// Address as reported by trackers, and everything that depends on it. IPv4 and
+/// IPv6 are tracked separately, a dual-stack host has one of each.
+#[derive(Debug)]
+pub struct Session {
+    listen_port: u16,
+    state: Mutex<State>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum AddressFamily {
+    V4,
+    V6,
+}
+
+impl AddressFamily {
+    pub fn of(ip: IpAddr) -> Self {
+        match ip {
+            IpAddr::V4(_) => AddressFamily::V4,
+            IpAddr::V6(_) => AddressFamily::V6,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct State {
+    /// The latest address each tracker reported, per family.
+    votes: HashMap<(String, AddressFamily), IpAddr>,
+    /// The winning address of each family and its node ID.
+    external: HashMap<AddressFamily, (IpAddr, [u8; 20])>,
+    random_node_id: [u8; 20],
+}
+
+impl State {
+    fn votes_for(&self, ip: IpAddr) -> usize {
+        self.votes.values().filter(|vote| **vote == ip).count()
+    }
+}
+
+impl Session {
+    /// `listen_port` is the port peers connect to. Until a tracker reports our
+    /// address, the node ID is random.
+    pub fn new(listen_port: u16) -> Self {
+        let mut random_node_id = [0; 20];
+        fill_random(&mut random_node_id);
+        Self {
+            listen_port,
+            state: Mutex::new(State {
+                votes: HashMap::new(),
+                external: HashMap::new(),
+                random_node_id,
+            }),
+        }
+    }
+
+    pub fn listen_port(&self) -> u16 {
+        self.listen_port
+    }
+
+    pub fn external_ip(&self, family: AddressFamily) -> Option<IpAddr> {
+        self.lock().external.get(&family).map(|(ip, _)| *ip)
+    }
+
+    /// BEP 42 node ID for the `family` address, or a random one while it is unknown.
+    pub fn node_id(&self, family: AddressFamily) -> [u8; 20] {
+        let state = self.lock();
+        match state.external.get(&family) {
+            Some((_, node_id)) => *node_id,
+            None => state.random_node_id,
+        }
+    }
+
+    /// Counts `tracker`'s view of our address, replacing its earlier one of the same
+    /// family. The address most trackers agree on wins, and a new one gets a new node
+    /// ID. Addresses that cannot be public are ignored. Returns whether it changed.
+    pub fn report_external_ip(&self, tracker: &str, ip: IpAddr) -> bool {
+        if !is_public(ip) {
+            return false;
+        }
+        let family = AddressFamily::of(ip);
+        let mut state = self.lock();
+        state.votes.insert((tracker.to_string(), family), ip);
+        let current = state.external.get(&family).map(|(current, _)| *current);
+        if current == Some(ip)
+            || current.is_some_and(|current| state.votes_for(ip) <= state.votes_for(current))
+        {
+            return false;
+        }
+        state
+            .external
+            .insert(family, (ip, node_id_for(ip, random_u64() as u8)));
+        true
+    }
+
+    /// Whether `peer` is this process, as trackers hand out our own address too.
+    pub fn is_self(&self, peer: &SocketAddr) -> bool {
+        peer.port() == self.listen_port
+            && self.external_ip(AddressFamily::of(peer.ip())) == Some(peer.ip())
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, State> {
+        self.state.lock().expect("session lock poisoned")
+    }
+}
+
+/// Whether `ip` could be our address on the internet, unlike a private, link-local or
+/// unique-local one a tracker on the same network would see.
+fn is_public(ip: IpAddr) -> bool {
+    if ip.is_unspecified() || ip.is_loopback() {
+        return false;
+    }
+    match ip {
+        IpAddr::V4(ip) => !ip.is_private() && !ip.is_link_local(),
+        IpAddr::V6(ip) => !ip.is_unicast_link_local() && !ip.is_unique_local(),
+    }
+}
+
+/// BEP 42: the first 21 bits are tied to `ip`, the last byte is `rand`, the rest is random.
+pub fn node_id_for(ip: IpAddr, rand: u8) -> [u8; 20] {
+    let mut masked = match ip {
+        IpAddr::V4(ip) => ip.octets().to_vec(),
+        IpAddr::V6(ip) => ip.octets()[..8].to_vec(),
+    };
+    let mask: &[u8] = match ip {
+        IpAddr::V4(_) => &[0x03, 0x0f, 0x3f, 0xff],
+        IpAddr::V6(_) => &[0x01, 0x03, 0x07, 0x0f, 0x1f, 0x3f, 0x7f, 0xff],
+    };
+    for (byte, mask) in masked.iter_mut().zip(mask) {
+        *byte &= mask;
+    }
+    masked[0] |= (rand & 0x07) << 5;
+    let crc = crc32c(&masked).to_be_bytes();
+
+    let mut node_id = [0; 20];
+    fill_random(&mut node_id[2..19]);
+    node_id[0] = crc[0];
+    node_id[1] = crc[1];
+    node_id[2] = (crc[2] & 0xf8) | (node_id[2] & 0x07);
+    node_id[19] = rand;
+    node_id
+}
+
+/// Whether `node_id` is one a node at `ip` may use under BEP 42.
+pub fn is_valid_node_id(ip: IpAddr, node_id: &[u8; 20]) -> bool {
+    let expected = node_id_for(ip, node_id[19]);
+    expected[..2] == node_id[..2] && expected[2] & 0xf8 == node_id[2] & 0xf8
+}
+
+/// CRC-32C (Castagnoli), bit by bit: only a few bytes are ever hashed.
+fn crc32c(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in bytes {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0x82f6_3b78
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+fn fill_random(bytes: &mut [u8]) {
+    for chunk in bytes.chunks_mut(8) {
+        let random = random_u64().to_le_bytes();
+        chunk.copy_from_slice(&random[..chunk.len()]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn crc32c_check_value() {
+        assert_eq!(crc32c(b"123456789"), 0xe306_9283);
+    }
+
+    #[test]
+    fn node_ids_match_bep42_vectors() {
+        let vectors: [([u8; 4], u8, [u8; 3]); 5] = [
+            ([124, 31, 75, 21], 1, [0x5f, 0xbf, 0xbf]),
+            ([21, 75, 31, 124], 86, [0x5a, 0x3c, 0xe9]),
+            ([65, 23, 51, 170], 22, [0xa5, 0xd4, 0x32]),
+            ([84, 124, 73, 14], 65, [0x1b, 0x03, 0x21]),
+            ([43, 213, 53, 83], 90, [0xe5, 0x6f, 0x6c]),
+        ];
+        for (ip, rand, prefix) in vectors {
+            let ip = IpAddr::from(ip);
+            let node_id = node_id_for(ip, rand);
+            assert_eq!(node_id[..2], prefix[..2], "{}", ip);
+            assert_eq!(node_id[2] & 0xf8, prefix[2] & 0xf8, "{}", ip);
+            assert_eq!(node_id[19], rand);
+            assert!(is_valid_node_id(ip, &node_id));
+            assert!(!is_valid_node_id(
+                Ipv4Addr::new(1, 2, 3, 4).into(),
+                &node_id
+            ));
+        }
+    }
+
+    #[test]
+    fn external_ip_by_majority_of_trackers() {
+        let session = Session::new(6881);
+        let random_id = session.node_id(AddressFamily::V4);
+        let first = IpAddr::from([203, 0, 113, 7]);
+        let second = IpAddr::from([198, 51, 100, 1]);
+
+        assert!(!session.report_external_ip("a", Ipv4Addr::LOCALHOST.into()));
+        assert!(session.report_external_ip("a", first));
+        assert_eq!(session.external_ip(AddressFamily::V4), Some(first));
+        assert_ne!(session.node_id(AddressFamily::V4), random_id);
+        assert!(is_valid_node_id(first, &session.node_id(AddressFamily::V4)));
+
+        // A dissenting tracker does not move us however often it announces.
+        for _ in 0..3 {
+            assert!(!session.report_external_ip("b", second));
+        }
+        assert_eq!(session.external_ip(AddressFamily::V4), Some(first));
+        // A majority of trackers does.
+        assert!(session.report_external_ip("c", second));
+        assert_eq!(session.external_ip(AddressFamily::V4), Some(second));
+        assert!(is_valid_node_id(
+            second,
+            &session.node_id(AddressFamily::V4)
+        ));
+    }
+
+    #[test]
+    fn ignores_addresses_that_cannot_be_public() {
+        let session = Session::new(6881);
+        for ip in [
+            "0.0.0.0",
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.16.0.1",
+            "172.31.255.254",
+            "192.168.1.1",
+            "169.254.0.1",
+            "::",
+            "::1",
+            "fe80::1",
+            "fc00::1",
+            "fd12:3456::1",
+        ] {
+            assert!(
+                !session.report_external_ip("a", ip.parse().unwrap()),
+                "{ip}"
+            );
+        }
+        assert_eq!(session.external_ip(AddressFamily::V4), None);
+        assert_eq!(session.external_ip(AddressFamily::V6), None);
+
+        assert!(session.report_external_ip("a", IpAddr::from([172, 32, 0, 1])));
+        assert!(session.report_external_ip("a", "2001:db8::7".parse().unwrap()));
+    }
+
+    #[test]
+    fn address_families_are_independent() {
+        let session = Session::new(6881);
+        let v4 = IpAddr::from([203, 0, 113, 7]);
+        let v6: IpAddr = "2001:db8::7".parse().unwrap();
+
+        assert!(session.report_external_ip("a", v4));
+        let node_id = session.node_id(AddressFamily::V4);
+        assert!(session.report_external_ip("a", v6));
+        assert!(!session.report_external_ip("b", v6));
+        assert!(!session.report_external_ip("b", v4));
+
+        assert_eq!(session.external_ip(AddressFamily::V4), Some(v4));
+        assert_eq!(session.external_ip(AddressFamily::V6), Some(v6));
+        assert_eq!(session.node_id(AddressFamily::V4), node_id);
+        assert!(is_valid_node_id(v6, &session.node_id(AddressFamily::V6)));
+        assert!(session.is_self(&SocketAddr::new(v4, 6881)));
+        assert!(session.is_self(&SocketAddr::new(v6, 6881)));
+    }
+
+    #[test]
+    fn recognizes_own_address() {
+        let session = Session::new(6881);
+        let ip = IpAddr::from([203, 0, 113, 7]);
+        assert!(!session.is_self(&SocketAddr::new(ip, 6881)));
+        session.report_external_ip("a", ip);
+        assert!(session.is_self(&SocketAddr::new(ip, 6881)));
+        assert!(!session.is_self(&SocketAddr::new(ip, 6882)));
+    }
+}
@@ -0,0 +1,351 @@
+//! Reading and writing pieces of a torrent in its files on disk.
+
+use crate::torrent::layout::TorrentLayout;
+use crate::torrent::meta::{FileAttributes, MetaInfo};
+use crate::torrent::path::{PathError, PathResolver};
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Component, Path, PathBuf};
+
+/// File of the torrent placed in the v1 piece space.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StorageFile {
+    pub path: PathBuf,
+    /// Offset of the first byte of the file in the piece space.
+    pub offset: u64,
+    pub length: u64,
+    pub attributes: FileAttributes,
+    pub symlink_target: Option<PathBuf>,
+}
+
+impl StorageFile {
+    /// Padding and symlinks only exist in the piece space, nothing is written for them.
+    fn has_content(&self) -> bool {
+        !self.attributes.is_padding() && !self.attributes.is_symlink()
+    }
+}
+
+pub struct TorrentStorage {
+    files: Vec<StorageFile>,
+    layout: TorrentLayout,
+}
+
+impl TorrentStorage {
+    /// Maps files of `info` under `download_root`, sanitizing unsafe names.
+    pub fn new(download_root: &Path, info: &MetaInfo) -> Result<Self, PathError> {
+        Self::with_resolver(PathResolver::new(download_root), info)
+    }
+
+    pub fn with_resolver(mut resolver: PathResolver, info: &MetaInfo) -> Result<Self, PathError> {
+        let layout = TorrentLayout::new(info);
+        let mut files = Vec::new();
+
+        if let Some(entries) = &info.files {
+            for (entry, span) in entries.iter().zip(layout.files()) {
+                let symlink_target = match &entry.symlink_path {
+                    Some(target) => {
+                        Some(resolver.resolve_reference(&prefixed(&info.name, target))?)
+                    }
+                    None => None,
+                };
+                // Padding never touches the disk, so it may not claim a name.
+                let path = match entry.is_padding() {
+                    true => resolver.resolve_reference(&prefixed(&info.name, &entry.path))?,
+                    false => resolver.resolve(&prefixed(&info.name, &entry.path))?,
+                };
+                files.push(StorageFile {
+                    path,
+                    offset: span.offset,
+                    length: span.length,
+                    attributes: entry.attributes(),
+                    symlink_target,
+                });
+            }
+        } else if let Some(length) = info.length {
+            files.push(StorageFile {
+                path: resolver.resolve(std::slice::from_ref(&info.name))?,
+                offset: 0,
+                length: length as u64,
+                attributes: FileAttributes::default(),
+                symlink_target: None,
+            });
+        } else if let Some(file_tree) = &info.file_tree {
+            let tree_files = file_tree.files();
+            let is_single_file = tree_files.len() == 1 && tree_files[0].0 == [info.name.as_str()];
+            for ((path, _), span) in tree_files.iter().zip(layout.files()) {
+                let components: Vec<String> = path.iter().map(|c| c.to_string()).collect();
+                let path = match is_single_file {
+                    true => resolver.resolve(std::slice::from_ref(&info.name))?,
+                    false => resolver.resolve(&prefixed(&info.name, &components))?,
+                };
+                files.push(StorageFile {
+                    path,
+                    offset: span.offset,
+                    length: span.length,
+                    attributes: FileAttributes::default(),
+                    symlink_target: None,
+                });
+            }
+        }
+
+        Ok(Self { files, layout })
+    }
+
+    pub fn files(&self) -> &[StorageFile] {
+        &self.files
+    }
+
+    pub fn layout(&self) -> &TorrentLayout {
+        &self.layout
+    }
+
+    /// Writes `data` starting at `offset` of the piece space, skipping padding files.
+    pub fn write(&self, offset: u64, data: &[u8]) -> std::io::Result<()> {
+        for slice in self.layout.slices(offset, data.len() as u64) {
+            let file = &self.files[slice.file_index];
+            if !file.has_content() {
+                continue;
+            }
+            let start = slice.offset as usize;
+            let chunk = &data[start..start + slice.length as usize];
+
+            let mut output = open_for_write(&file.path)?;
+            output.seek(SeekFrom::Start(slice.file_offset))?;
+            output.write_all(chunk)?;
+        }
+        Ok(())
+    }
+
+    /// Reads `length` bytes starting at `offset` of the piece space. Padding reads as zeros.
+    pub fn read(&self, offset: u64, length: u64) -> std::io::Result<Vec<u8>> {
+        let mut data = vec![0; length as usize];
+        for slice in self.layout.slices(offset, length) {
+            let file = &self.files[slice.file_index];
+            if !file.has_content() {
+                continue;
+            }
+            let start = slice.offset as usize;
+            let mut input = File::open(&file.path)?;
+            input.seek(SeekFrom::Start(slice.file_offset))?;
+            input.read_exact(&mut data[start..start + slice.length as usize])?;
+        }
+        Ok(data)
+    }
+
+    pub fn read_piece(&self, index: u32) -> std::io::Result<Vec<u8>> {
+        match self.layout.piece_range(index) {
+            Some(range) => self.read(range.start, range.end - range.start),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    pub fn write_piece(&self, index: u32, data: &[u8]) -> std::io::Result<()> {
+        let offset = index as u64 * self.layout.piece_length();
+        self.write(offset, data)
+    }
+
+    /// Creates empty files and symlinks and applies file attributes once data is complete.
+    pub fn finalize(&self) -> std::io::Result<()> {
+        for file in &self.files {
+            if file.attributes.is_padding() {
+                continue;
+            }
+            if file.attributes.is_symlink() {
+                if let Some(target) = &file.symlink_target {
+                    create_symlink(&relative_target(target, &file.path), &file.path)?;
+                }
+                continue;
+            }
+
+            let output = open_for_write(&file.path)?;
+            output.set_len(file.length)?;
+            if file.attributes.is_executable() {
+                set_executable(&output)?;
+            }
+            // Hidden files need no special handling on unix, their names start with a dot.
+        }
+        Ok(())
+    }
+}
+
+fn prefixed(name: &str, path: &[String]) -> Vec<String> {
+    std::iter::once(name.to_string())
+        .chain(path.iter().cloned())
+        .collect()
+}
+
+/// `target` as seen from the directory of `link`, so links survive moving the download.
+fn relative_target(target: &Path, link: &Path) -> PathBuf {
+    let base: Vec<_> = link
+        .parent()
+        .map(Path::components)
+        .into_iter()
+        .flatten()
+        .collect();
+    let target: Vec<_> = target.components().collect();
+    let common = base
+        .iter()
+        .zip(&target)
+        .take_while(|(base, target)| base == target)
+        .count();
+    std::iter::repeat_n(Component::ParentDir, base.len() - common)
+        .chain(target[common..].iter().copied())
+        .collect()
+}
+
+fn open_for_write(path: &Path) -> std::io::Result<File> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)
+}
+
+#[cfg(unix)]
+fn set_executable(file: &File) -> std::io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    let mut permissions = file.metadata()?.permissions();
+    permissions.set_mode(permissions.mode() | 0o111);
+    file.set_permissions(permissions)
+}
+
+#[cfg(not(unix))]
+fn set_executable(_file: &File) -> std::io::Result<()> {
+    Ok(())
+}
+
+#[cfg(unix)]
+fn create_symlink(target: &Path, link: &Path) -> std::io::Result<()> {
+    if let Some(parent) = link.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    if link.symlink_metadata().is_ok() {
+        std::fs::remove_file(link)?;
+    }
+    std::os::unix::fs::symlink(target, link)
+}
+
+#[cfg(not(unix))]
+fn create_symlink(_target: &Path, _link: &Path) -> std::io::Result<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::torrent::meta::FileEntry;
+    use crate::torrent::test_util::TempDir;
+
+    fn entry(path: &[&str], length: u64, attr: Option<&str>) -> FileEntry {
+        FileEntry {
+            attr: attr.map(FileAttributes::new),
+            ..FileEntry::new(path.iter().map(|c| c.to_string()).collect(), length)
+        }
+    }
+
+    #[test]
+    fn padding_is_not_written_and_attributes_are_applied() {
+        let dir = TempDir::new("storage-attributes");
+        let mut link = entry(&["bin", "latest"], 0, Some("l"));
+        link.symlink_path = Some(vec!["bin".to_string(), "run".to_string()]);
+        let info = MetaInfo {
+            length: None,
+            files: Some(vec![
+                entry(&["bin", "run"], 6, Some("x")),
+                entry(&[".pad", "2"], 2, Some("p")),
+                entry(&["data"], 4, None),
+                link,
+                entry(&["empty"], 0, None),
+            ]),
+            name: "torrent".to_string(),
+            piece_length: 8,
+            pieces: vec![0; 40],
+            meta_version: None,
+            file_tree: None,
+            private: None,
+            source: None,
+        };
+
+        let storage = TorrentStorage::new(&dir.0, &info).unwrap();
+        storage.write_piece(0, b"run!\n\n\0\0").unwrap();
+        storage.write_piece(1, b"data").unwrap();
+        storage.finalize().unwrap();
+
+        let root = dir.0.join("torrent");
+        assert_eq!(
+            std::fs::read(root.join("bin").join("run")).unwrap(),
+            b"run!\n\n"
+        );
+        assert_eq!(std::fs::read(root.join("data")).unwrap(), b"data");
+        assert_eq!(std::fs::read(root.join("empty")).unwrap(), b"");
+        assert!(!root.join(".pad").exists());
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(root.join("bin").join("run"))
+                .unwrap()
+                .permissions()
+                .mode();
+            assert_eq!(mode & 0o111, 0o111);
+            assert_eq!(
+                std::fs::read_link(root.join("bin").join("latest")).unwrap(),
+                PathBuf::from("run")
+            );
+        }
+    }
+
+    #[test]
+    fn symlink_targets_are_relative_to_the_link() {
+        let root = Path::new("/downloads/torrent");
+        assert_eq!(
+            relative_target(&root.join("a/d/e"), &root.join("a/b/link")),
+            PathBuf::from("../d/e")
+        );
+        assert_eq!(
+            relative_target(&root.join("a/b"), &root.join("link")),
+            PathBuf::from("a/b")
+        );
+    }
+
+    #[test]
+    fn crafted_names_stay_inside_download_root() {
+        let dir = TempDir::new("storage-escape");
+        let info = MetaInfo {
+            length: None,
+            files: Some(vec![
+                entry(&["..", "..", "escaped"], 4, None),
+                entry(&["Same"], 4, None),
+                entry(&["same"], 4, None),
+            ]),
+            name: "..".to_string(),
+            piece_length: 16,
+            pieces: vec![0; 20],
+            meta_version: None,
+            file_tree: None,
+            private: None,
+            source: None,
+        };
+
+        let storage = TorrentStorage::new(&dir.0, &info).unwrap();
+        storage.write(0, b"evilSAMEsame").unwrap();
+        for file in storage.files() {
+            assert!(file.path.starts_with(&dir.0));
+        }
+        assert_eq!(
+            std::fs::read(dir.0.join("__").join("__").join("__").join("escaped")).unwrap(),
+            b"evil"
+        );
+        assert_eq!(
+            std::fs::read(dir.0.join("__").join("Same")).unwrap(),
+            b"SAME"
+        );
+        assert_eq!(
+            std::fs::read(dir.0.join("__").join("same (1)")).unwrap(),
+            b"same"
+        );
+    }
+}
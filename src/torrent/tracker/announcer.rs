@@ -0,0 +1,476 @@
+//! Re-announcing to every tracker of a torrent on the tracker's own schedule.
+
+use crate::torrent::meta::TorrentFile;
+use crate::torrent::session::Session;
+use crate::torrent::tracker::{
+    supported_trackers, AnnounceEvent, AnnounceRequest, TorrentTrackerClient, TrackerResponse,
+    RESOLVE_TIMEOUT,
+};
+use std::collections::HashSet;
+use std::net::SocketAddr;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender, TryRecvError};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+/// Delay before the first retry of a failed announce, doubled on every further failure.
+pub const RETRY_DELAY: Duration = Duration::from_secs(15);
+pub const MAX_RETRY_DELAY: Duration = Duration::from_secs(60 * 60);
+/// Bound of a single announce, so one silent tracker delays the others and commands
+/// only this long.
+pub const ANNOUNCE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// When and with which event the next announce to one tracker happens.
+#[derive(Debug, Clone)]
+pub struct TrackerSchedule {
+    pub url: String,
+    pub next_announce: Instant,
+    pub failures: u32,
+    pending_event: Option<AnnounceEvent>,
+    last_announce: Option<Instant>,
+    min_interval: Duration,
+    tracker_id: Option<String>,
+}
+
+impl TrackerSchedule {
+    pub fn new(url: String, now: Instant) -> Self {
+        Self {
+            url,
+            next_announce: now,
+            failures: 0,
+            pending_event: Some(AnnounceEvent::Started),
+            last_announce: None,
+            min_interval: Duration::ZERO,
+            tracker_id: None,
+        }
+    }
+
+    pub fn is_due(&self, now: Instant) -> bool {
+        self.next_announce <= now
+    }
+
+    /// Event to send with the next announce, `None` for a regular one.
+    pub fn event(&self) -> Option<AnnounceEvent> {
+        self.pending_event
+    }
+
+    pub fn has_started(&self) -> bool {
+        self.pending_event != Some(AnnounceEvent::Started)
+    }
+
+    pub fn succeeded(&mut self, now: Instant, response: &TrackerResponse) {
+        self.failures = 0;
+        self.pending_event = None;
+        self.last_announce = Some(now);
+        self.min_interval = response.min_interval.unwrap_or_default();
+        if response.tracker_id.is_some() {
+            self.tracker_id = response.tracker_id.clone();
+        }
+        self.next_announce = now + response.interval.max(self.min_interval);
+    }
+
+    pub fn failed(&mut self, now: Instant) {
+        let delay = RETRY_DELAY
+            .saturating_mul(2u32.saturating_pow(self.failures))
+            .min(MAX_RETRY_DELAY);
+        self.failures = self.failures.saturating_add(1);
+        self.next_announce = now + delay;
+    }
+
+    /// Sends `completed` as soon as `min interval` allows. A tracker that never saw
+    /// `started` gets that one instead, with `left=0`.
+    pub fn complete(&mut self, now: Instant) {
+        if self.has_started() {
+            self.pending_event = Some(AnnounceEvent::Completed);
+        }
+        let earliest = match self.last_announce {
+            Some(last) => (last + self.min_interval).max(now),
+            None => now,
+        };
+        self.next_announce = self.next_announce.min(earliest);
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AnnouncerCommand {
+    Progress {
+        uploaded: u64,
+        downloaded: u64,
+        left: u64,
+    },
+    Completed,
+    Stop,
+}
+
+/// Announces to all trackers of a torrent and publishes peers not seen before.
+pub struct Announcer {
+    client: TorrentTrackerClient,
+    request: AnnounceRequest,
+    trackers: Vec<TrackerSchedule>,
+    known_peers: HashSet<SocketAddr>,
+    peers: Sender<Vec<SocketAddr>>,
+    session: Option<Arc<Session>>,
+}
+
+impl Announcer {
+    /// `request` is the template of every announce: info hash, peer id, port and counters.
+    pub fn new(
+        trackers: Vec<String>,
+        request: AnnounceRequest,
+        peers: Sender<Vec<SocketAddr>>,
+    ) -> Self {
+        let now = Instant::now();
+        Self {
+            client: TorrentTrackerClient::new().timeout(ANNOUNCE_TIMEOUT),
+            request,
+            trackers: trackers
+                .into_iter()
+                .map(|url| TrackerSchedule::new(url, now))
+                .collect(),
+            known_peers: HashSet::new(),
+            peers,
+            session: None,
+        }
+    }
+
+    /// Every supported tracker of `announce` and `announce-list`, each announced separately.
+    pub fn for_torrent(
+        torrent_file: &TorrentFile,
+        request: AnnounceRequest,
+        peers: Sender<Vec<SocketAddr>>,
+    ) -> Self {
+        Self::new(supported_trackers(torrent_file), request, peers)
+    }
+
+    /// Reports the tracker's view of our address to `session` and leaves our own
+    /// address out of the published peers.
+    pub fn session(mut self, session: Arc<Session>) -> Self {
+        self.session = Some(session);
+        self
+    }
+
+    /// Replaces [`ANNOUNCE_TIMEOUT`].
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.client = self.client.timeout(timeout);
+        self
+    }
+
+    pub fn trackers(&self) -> &[TrackerSchedule] {
+        &self.trackers
+    }
+
+    /// Runs the announcer on its own thread until [`AnnouncerHandle::stop`].
+    pub fn spawn(self) -> AnnouncerHandle {
+        let (commands, receiver) = mpsc::channel();
+        let thread = std::thread::spawn(move || self.run(receiver));
+        AnnouncerHandle {
+            commands,
+            thread: Some(thread),
+        }
+    }
+
+    fn run(mut self, commands: Receiver<AnnouncerCommand>) {
+        loop {
+            if !self.announce_due(Instant::now(), &commands) {
+                self.announce_stopped();
+                return;
+            }
+
+            let command = match self.trackers.iter().map(|t| t.next_announce).min() {
+                Some(next) => commands.recv_timeout(next.saturating_duration_since(Instant::now())),
+                None => commands.recv().map_err(|_| RecvTimeoutError::Disconnected),
+            };
+            let keep_running = match command {
+                Ok(command) => self.handle(command),
+                Err(RecvTimeoutError::Timeout) => true,
+                Err(RecvTimeoutError::Disconnected) => false,
+            };
+            if !keep_running {
+                self.announce_stopped();
+                return;
+            }
+        }
+    }
+
+    /// Applies `command`, returns `false` once the announcer is to stop.
+    fn handle(&mut self, command: AnnouncerCommand) -> bool {
+        match command {
+            AnnouncerCommand::Progress {
+                uploaded,
+                downloaded,
+                left,
+            } => {
+                self.request.uploaded = uploaded;
+                self.request.downloaded = downloaded;
+                self.request.left = left;
+            }
+            AnnouncerCommand::Completed => {
+                self.request.left = 0;
+                let now = Instant::now();
+                for tracker in &mut self.trackers {
+                    tracker.complete(now);
+                }
+            }
+            AnnouncerCommand::Stop => return false,
+        }
+        true
+    }
+
+    /// Announces to the trackers due at `now`, taking commands in between so a stop
+    /// waits for one announce at most. Returns `false` once the announcer is to stop.
+    fn announce_due(&mut self, now: Instant, commands: &Receiver<AnnouncerCommand>) -> bool {
+        for index in 0..self.trackers.len() {
+            if self.trackers[index].is_due(now) {
+                self.announce_to(index);
+            }
+            loop {
+                match commands.try_recv() {
+                    Ok(command) => {
+                        if !self.handle(command) {
+                            return false;
+                        }
+                    }
+                    Err(TryRecvError::Empty) => break,
+                    Err(TryRecvError::Disconnected) => return false,
+                }
+            }
+        }
+        true
+    }
+
+    fn announce_to(&mut self, index: usize) {
+        let tracker = &self.trackers[index];
+        let mut request = self.request.clone();
+        request.event = tracker.event();
+        request.tracker_id = tracker.tracker_id.clone();
+
+        let result = self
+            .client
+            .announce(&tracker.url, &request)
+            .map(|mut response| {
+                response.resolve_peer_hosts(RESOLVE_TIMEOUT);
+                response
+            });
+        let now = Instant::now();
+        match result {
+            Ok(response) => {
+                if let (Some(session), Some(ip)) = (&self.session, response.external_ip) {
+                    session.report_external_ip(&self.trackers[index].url, ip);
+                }
+                self.publish(&response.peers);
+                self.trackers[index].succeeded(now, &response);
+            }
+            Err(_) => self.trackers[index].failed(now),
+        }
+    }
+
+    fn publish(&mut self, peers: &[SocketAddr]) {
+        let new_peers: Vec<SocketAddr> = peers
+            .iter()
+            .filter(|peer| !self.session.as_ref().is_some_and(|s| s.is_self(peer)))
+            .filter(|peer| self.known_peers.insert(**peer))
+            .copied()
+            .collect();
+        if !new_peers.is_empty() {
+            // Nobody listening any more is not a reason to stop announcing.
+            let _ = self.peers.send(new_peers);
+        }
+    }
+
+    /// Trackers that know about us are told we leave, failures no longer matter.
+    /// A `completed` not sent yet goes first, so the tracker still counts it.
+    fn announce_stopped(&mut self) {
+        for tracker in self.trackers.iter().filter(|t| t.has_started()) {
+            let mut request = self.request.clone();
+            request.tracker_id = tracker.tracker_id.clone();
+            if tracker.event() == Some(AnnounceEvent::Completed) {
+                request.event = Some(AnnounceEvent::Completed);
+                let _ = self.client.announce(&tracker.url, &request);
+            }
+            request.event = Some(AnnounceEvent::Stopped);
+            let _ = self.client.announce(&tracker.url, &request);
+        }
+    }
+}
+
+/// Controls an announcer running on its own thread.
+pub struct AnnouncerHandle {
+    commands: Sender<AnnouncerCommand>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl AnnouncerHandle {
+    /// Transfer counters for the following announces.
+    pub fn progress(&self, uploaded: u64, downloaded: u64, left: u64) {
+        let _ = self.commands.send(AnnouncerCommand::Progress {
+            uploaded,
+            downloaded,
+            left,
+        });
+    }
+
+    pub fn completed(&self) {
+        let _ = self.commands.send(AnnouncerCommand::Completed);
+    }
+
+    /// Sends `stopped` to the trackers and waits for the announcer to finish.
+    pub fn stop(mut self) {
+        self.shutdown();
+    }
+
+    fn shutdown(&mut self) {
+        let _ = self.commands.send(AnnouncerCommand::Stop);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Drop for AnnouncerHandle {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::torrent::meta::InfoHash;
+    use crate::torrent::session::AddressFamily;
+    use crate::torrent::tracker::tests::serve;
+    use std::net::Ipv4Addr;
+
+    fn response(interval: u64, min_interval: Option<u64>) -> TrackerResponse {
+        TrackerResponse {
+            interval: Duration::from_secs(interval),
+            min_interval: min_interval.map(Duration::from_secs),
+            peers: Vec::new(),
+            peer_ids: Default::default(),
+            peer_hosts: Vec::new(),
+            malformed_peers: Vec::new(),
+            complete: None,
+            incomplete: None,
+            warning_message: None,
+            tracker_id: Some("id".to_string()),
+            external_ip: None,
+        }
+    }
+
+    #[test]
+    fn schedule_honors_intervals_and_backs_off() {
+        let start = Instant::now();
+        let mut schedule = TrackerSchedule::new("http://t.example/announce".to_string(), start);
+        assert!(schedule.is_due(start));
+        assert_eq!(schedule.event(), Some(AnnounceEvent::Started));
+
+        schedule.failed(start);
+        assert_eq!(schedule.next_announce, start + Duration::from_secs(15));
+        schedule.failed(start);
+        assert_eq!(schedule.next_announce, start + Duration::from_secs(30));
+        schedule.failed(start);
+        assert_eq!(schedule.next_announce, start + Duration::from_secs(60));
+        for _ in 0..20 {
+            schedule.failed(start);
+        }
+        assert_eq!(schedule.next_announce, start + MAX_RETRY_DELAY);
+
+        schedule.succeeded(start, &response(1800, Some(300)));
+        assert_eq!(schedule.failures, 0);
+        assert_eq!(schedule.event(), None);
+        assert_eq!(schedule.next_announce, start + Duration::from_secs(1800));
+
+        // `completed` waits for `min interval`, but not for the regular interval.
+        schedule.complete(start + Duration::from_secs(10));
+        assert_eq!(schedule.event(), Some(AnnounceEvent::Completed));
+        assert_eq!(schedule.next_announce, start + Duration::from_secs(300));
+
+        // An interval below `min interval` is raised to it.
+        schedule.succeeded(start, &response(5, Some(300)));
+        assert_eq!(schedule.next_announce, start + Duration::from_secs(300));
+    }
+
+    #[test]
+    fn announces_events_and_publishes_new_peers() {
+        let peers = b"d8:intervali1800e5:peers12:\x7f\x00\x00\x01\x1a\xe1\x7f\x00\x00\x02\x1a\xe1e";
+        let (url, server) = serve(vec![
+            ("200 OK", peers.to_vec()),
+            ("200 OK", peers.to_vec()),
+            ("200 OK", b"d8:intervali1800e5:peers0:e".to_vec()),
+        ]);
+        let (sender, receiver) = mpsc::channel();
+        let request = AnnounceRequest::new(InfoHash::V1([0x01; 20]), 100);
+        let handle = Announcer::new(vec![url], request, sender).spawn();
+
+        let published = receiver.recv_timeout(Duration::from_secs(10)).unwrap();
+        assert_eq!(
+            published,
+            vec![
+                SocketAddr::new(Ipv4Addr::new(127, 0, 0, 1).into(), 6881),
+                SocketAddr::new(Ipv4Addr::new(127, 0, 0, 2).into(), 6881),
+            ]
+        );
+        handle.progress(10, 100, 0);
+        handle.completed();
+        handle.stop();
+
+        let requests = server.join().unwrap();
+        assert!(requests[0].contains("&left=100&") && requests[0].contains("event=started"));
+        assert!(requests[1].contains("&left=0&") && requests[1].contains("event=completed"));
+        assert!(requests[1].contains("uploaded=10&downloaded=100"));
+        assert!(requests[2].contains("event=stopped"));
+        // The same peers from the second announce are not published again.
+        assert!(receiver.try_recv().is_err());
+    }
+
+    #[test]
+    fn silent_trackers_do_not_hold_up_stop() {
+        let first = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let second = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let trackers = [&first, &second]
+            .map(|socket| format!("udp://{}", socket.local_addr().unwrap()))
+            .to_vec();
+        let (sender, _receiver) = mpsc::channel();
+        let request = AnnounceRequest::new(InfoHash::V1([0x01; 20]), 100);
+        let started = Instant::now();
+        let handle = Announcer::new(trackers, request, sender)
+            .timeout(Duration::from_millis(500))
+            .spawn();
+
+        let mut buffer = [0; 64];
+        first.recv(&mut buffer).unwrap();
+        handle.stop();
+        assert!(started.elapsed() < Duration::from_secs(5));
+        // The stop is seen before the next tracker is tried.
+        second.set_nonblocking(true).unwrap();
+        assert!(second.recv(&mut buffer).is_err());
+    }
+
+    #[test]
+    fn reports_external_ip_and_skips_own_address() {
+        let body = b"d11:external ip4:\xcb\x00\x71\x078:intervali1800e5:peers12:\xcb\x00\x71\x07\x1a\xe1\x7f\x00\x00\x02\x1a\xe1e";
+        let (url, server) = serve(vec![
+            ("200 OK", body.to_vec()),
+            ("200 OK", b"d8:intervali1800e5:peers0:e".to_vec()),
+        ]);
+        let (sender, receiver) = mpsc::channel();
+        let session = Arc::new(Session::new(6881));
+        let request = AnnounceRequest::new(InfoHash::V1([0x01; 20]), 100);
+        let handle = Announcer::new(vec![url], request, sender)
+            .session(session.clone())
+            .spawn();
+
+        let published = receiver.recv_timeout(Duration::from_secs(10)).unwrap();
+        assert_eq!(
+            published,
+            vec![SocketAddr::new(Ipv4Addr::new(127, 0, 0, 2).into(), 6881)]
+        );
+        let external_ip = Ipv4Addr::new(203, 0, 113, 7).into();
+        assert_eq!(session.external_ip(AddressFamily::V4), Some(external_ip));
+        assert!(crate::torrent::session::is_valid_node_id(
+            external_ip,
+            &session.node_id(AddressFamily::V4)
+        ));
+        handle.stop();
+        server.join().unwrap();
+    }
+}
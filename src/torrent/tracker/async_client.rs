@@ -0,0 +1,516 @@
+//! Tracker client for async code: every request is bounded by timeouts, retried when
+//! the failure may be transient, and can be cancelled.
+
+use crate::torrent::meta::{InfoHash, TorrentFile};
+use crate::torrent::peer_id::{PeerIdGenerator, PeerIdPolicy};
+use crate::torrent::proxy::ProxyConfig;
+use crate::torrent::tracker::udp::{Interrupt, UdpTrackerClient};
+use crate::torrent::tracker::{
+    supported_trackers, url, AnnounceEvent, AnnounceRequest, TrackerError, TrackerResponse,
+};
+use std::collections::HashMap;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
+use tokio::task::JoinSet;
+
+pub const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+/// Limit of a whole request, from connecting to the last byte of the reply.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+pub const DEFAULT_RETRIES: u32 = 2;
+/// Delay before the first retry, doubled on every further one.
+pub const DEFAULT_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// Cancels every request it was passed to. Clones cancel together.
+#[derive(Debug, Clone)]
+pub struct CancellationToken {
+    sender: Arc<watch::Sender<bool>>,
+}
+
+impl Default for CancellationToken {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self {
+            sender: Arc::new(watch::Sender::new(false)),
+        }
+    }
+
+    pub fn cancel(&self) {
+        self.sender.send_replace(true);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        *self.sender.borrow()
+    }
+
+    /// Completes once [`Self::cancel`] was called.
+    pub async fn cancelled(&self) {
+        let mut receiver = self.sender.subscribe();
+        // The sender lives in `self`, so waiting cannot fail.
+        let _ = receiver.wait_for(|cancelled| *cancelled).await;
+    }
+}
+
+/// Cheap to clone, clones share connections and UDP connection IDs.
+#[derive(Debug, Clone)]
+pub struct AsyncTrackerClient {
+    http: reqwest::Client,
+    udp_clients: Arc<Mutex<HashMap<String, Arc<Mutex<UdpTrackerClient>>>>>,
+    peer_ids: Arc<PeerIdGenerator>,
+    connect_timeout: Duration,
+    proxy: Option<ProxyConfig>,
+    timeout: Duration,
+    retries: u32,
+    retry_delay: Duration,
+}
+
+impl AsyncTrackerClient {
+    pub fn new() -> Result<Self, TrackerError> {
+        Ok(Self {
+            http: http_client(DEFAULT_CONNECT_TIMEOUT, None)?,
+            udp_clients: Arc::new(Mutex::new(HashMap::new())),
+            peer_ids: Arc::new(PeerIdGenerator::default()),
+            connect_timeout: DEFAULT_CONNECT_TIMEOUT,
+            proxy: None,
+            timeout: DEFAULT_TIMEOUT,
+            retries: DEFAULT_RETRIES,
+            retry_delay: DEFAULT_RETRY_DELAY,
+        })
+    }
+
+    pub fn connect_timeout(mut self, connect_timeout: Duration) -> Result<Self, TrackerError> {
+        self.http = http_client(connect_timeout, self.proxy.as_ref())?;
+        self.connect_timeout = connect_timeout;
+        Ok(self)
+    }
+
+    /// Sends HTTP announces and, for SOCKS5, UDP ones through `proxy`.
+    pub fn proxy(mut self, proxy: ProxyConfig) -> Result<Self, TrackerError> {
+        self.http = http_client(self.connect_timeout, Some(&proxy))?;
+        self.udp_clients = Arc::new(Mutex::new(HashMap::new()));
+        self.proxy = Some(proxy);
+        Ok(self)
+    }
+
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Attempts after the first one, only for failures that may be transient.
+    pub fn retries(mut self, retries: u32) -> Self {
+        self.retries = retries;
+        self
+    }
+
+    pub fn retry_delay(mut self, retry_delay: Duration) -> Self {
+        self.retry_delay = retry_delay;
+        self
+    }
+
+    pub fn peer_id_policy(mut self, policy: PeerIdPolicy) -> Self {
+        self.peer_ids = Arc::new(PeerIdGenerator::new(policy));
+        self
+    }
+
+    /// Peer ID announced for the torrent with `info_hashes`, to be used in the handshake
+    /// with its peers too.
+    pub fn peer_id_for(&self, info_hashes: &[InfoHash]) -> [u8; 20] {
+        self.peer_ids.peer_id_for(info_hashes)
+    }
+
+    /// Announces `started` to every supported tracker of the torrent at once and merges
+    /// their peers. Fails only if no tracker answered.
+    pub async fn get_peers(
+        &self,
+        torrent_file: &TorrentFile,
+        cancel: &CancellationToken,
+    ) -> Result<Vec<SocketAddr>, TrackerError> {
+        let info_hash = torrent_file.primary_info_hash()?;
+        let request = AnnounceRequest::new(info_hash, torrent_file.info().total_length() as u64)
+            .peer_id(self.peer_id_for(&torrent_file.info_hashes()?))
+            .event(AnnounceEvent::Started);
+
+        let mut peers = Vec::new();
+        let mut last_error = TrackerError::NoTracker;
+        for (_, result) in self
+            .announce_all(&supported_trackers(torrent_file), &request, cancel)
+            .await
+        {
+            match result {
+                Ok(mut response) => {
+                    cancellable(cancel, async {
+                        resolve_peer_hosts(&mut response, self.timeout).await;
+                        Ok(())
+                    })
+                    .await?;
+                    for peer in response.peers {
+                        if !peers.contains(&peer) {
+                            peers.push(peer);
+                        }
+                    }
+                }
+                Err(e) => last_error = e,
+            }
+        }
+        if peers.is_empty() && !matches!(last_error, TrackerError::NoTracker) {
+            return Err(last_error);
+        }
+        Ok(peers)
+    }
+
+    /// Announces to all `urls` concurrently. Results are in the order of `urls`, a slow
+    /// tracker delays only its own result.
+    pub async fn announce_all(
+        &self,
+        urls: &[String],
+        request: &AnnounceRequest,
+        cancel: &CancellationToken,
+    ) -> Vec<(String, Result<TrackerResponse, TrackerError>)> {
+        let mut tasks = JoinSet::new();
+        let mut indices = HashMap::new();
+        for (index, url) in urls.iter().enumerate() {
+            let client = self.clone();
+            let url = url.clone();
+            let request = request.clone();
+            let cancel = cancel.clone();
+            let task = tasks.spawn(async move { client.announce(&url, &request, &cancel).await });
+            indices.insert(task.id(), index);
+        }
+
+        let mut results: Vec<Option<Result<TrackerResponse, TrackerError>>> =
+            urls.iter().map(|_| None).collect();
+        while let Some(joined) = tasks.join_next_with_id().await {
+            // A panicked or aborted task still gets an entry for its URL.
+            let (id, result) = match joined {
+                Ok((id, result)) => (id, result),
+                Err(e) => (e.id(), Err(TrackerError::Io(std::io::Error::other(e)))),
+            };
+            results[indices[&id]] = Some(result);
+        }
+        urls.iter()
+            .cloned()
+            .zip(results)
+            .map(|(url, result)| (url, result.unwrap_or(Err(TrackerError::Cancelled))))
+            .collect()
+    }
+
+    /// Announces over HTTP or UDP, retrying transient failures until `cancel`.
+    pub async fn announce(
+        &self,
+        announce_url: &str,
+        request: &AnnounceRequest,
+        cancel: &CancellationToken,
+    ) -> Result<TrackerResponse, TrackerError> {
+        if announce_url.starts_with("udp://") {
+            let request = request.clone();
+            return self
+                .with_udp_client(announce_url, cancel, move |client| {
+                    client.announce(&request)
+                })
+                .await;
+        }
+
+        let url = url::with_query(announce_url, &request.query());
+        self.with_retries(cancel, || async {
+            let response = self.http.get(&url).timeout(self.timeout).send().await?;
+            if !response.status().is_success() {
+                return Err(TrackerError::Status(response.status()));
+            }
+            TrackerResponse::from_bytes(&response.bytes().await?)
+        })
+        .await
+    }
+
+    async fn with_retries<T, F, Fut>(
+        &self,
+        cancel: &CancellationToken,
+        attempt: F,
+    ) -> Result<T, TrackerError>
+    where
+        F: Fn() -> Fut,
+        Fut: Future<Output = Result<T, TrackerError>>,
+    {
+        let mut delay = self.retry_delay;
+        let mut retries_left = self.retries;
+        loop {
+            match cancellable(cancel, attempt()).await {
+                Err(e) if retries_left > 0 && e.is_transient() => {
+                    retries_left -= 1;
+                    cancellable(cancel, async {
+                        tokio::time::sleep(delay).await;
+                        Ok(())
+                    })
+                    .await?;
+                    delay = delay.saturating_mul(2);
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Runs `f` with the client of a UDP tracker on the blocking pool, creating the
+    /// client there as that resolves the host and may connect to a proxy. The exchange
+    /// ends with [`TrackerError::Timeout`] after our timeout and stops when `cancel`
+    /// fires or the future is dropped.
+    async fn with_udp_client<T: Send + 'static>(
+        &self,
+        url: &str,
+        cancel: &CancellationToken,
+        f: impl FnOnce(&mut UdpTrackerClient) -> Result<T, TrackerError> + Send + 'static,
+    ) -> Result<T, TrackerError> {
+        let deadline = Instant::now() + self.timeout;
+        let interrupt = Interrupt::new().deadline(deadline);
+        let _stop = StopOnDrop(interrupt.clone());
+        let clients = Arc::clone(&self.udp_clients);
+        let url = url.to_string();
+        let proxy = self.proxy.clone();
+        // Retransmissions of the UDP client fit into our timeout: t + 2t + 4t + ...
+        let attempts = 2u32.saturating_pow(self.retries.saturating_add(1)) - 1;
+        let base_timeout = self.timeout / attempts.max(1);
+        let retries = self.retries;
+
+        let task = tokio::task::spawn_blocking(move || {
+            interrupt.check()?;
+            let existing = clients
+                .lock()
+                .expect("UDP clients lock poisoned")
+                .get(&url)
+                .cloned();
+            let client = match existing {
+                Some(client) => client,
+                None => {
+                    // The proxy handshake gets no more than what is left of the deadline.
+                    let remaining = deadline.saturating_duration_since(Instant::now());
+                    let proxy = proxy.map(|proxy| {
+                        let timeout = proxy.timeout.min(remaining);
+                        proxy.timeout(timeout)
+                    });
+                    let client = UdpTrackerClient::connect_via(&url, proxy.as_ref())?
+                        .base_timeout(base_timeout)
+                        .max_retransmissions(retries);
+                    let mut clients = clients.lock().expect("UDP clients lock poisoned");
+                    Arc::clone(
+                        clients
+                            .entry(url)
+                            .or_insert_with(|| Arc::new(Mutex::new(client))),
+                    )
+                }
+            };
+            let mut client = client.lock().expect("UDP client lock poisoned");
+            interrupt.check()?;
+            client.set_interrupt(Some(interrupt));
+            let result = f(&mut client);
+            client.set_interrupt(None);
+            result
+        });
+        // A name lookup cannot be interrupted, so stop waiting for it at the deadline.
+        let deadline = tokio::time::Instant::from_std(deadline);
+        cancellable(cancel, async {
+            match tokio::time::timeout_at(deadline, task).await {
+                Ok(joined) => joined.map_err(|e| TrackerError::Io(std::io::Error::other(e)))?,
+                Err(_) => Err(TrackerError::Timeout),
+            }
+        })
+        .await
+    }
+}
+
+/// Stops a blocking UDP exchange once its future is gone.
+struct StopOnDrop(Interrupt);
+
+impl Drop for StopOnDrop {
+    fn drop(&mut self) {
+        self.0.cancel();
+    }
+}
+
+/// Moves `peer_hosts` into `peers`, dropping what is not resolved within `timeout`.
+async fn resolve_peer_hosts(response: &mut TrackerResponse, timeout: Duration) {
+    let hosts = std::mem::take(&mut response.peer_hosts);
+    let _ = tokio::time::timeout(timeout, async {
+        for host in hosts {
+            let resolved = tokio::net::lookup_host((host.host.as_str(), host.port)).await;
+            if let Some(address) = resolved.ok().and_then(|mut addresses| addresses.next()) {
+                response.add_peer(address, host.peer_id);
+            }
+        }
+    })
+    .await;
+}
+
+fn http_client(
+    connect_timeout: Duration,
+    proxy: Option<&ProxyConfig>,
+) -> Result<reqwest::Client, TrackerError> {
+    let mut builder = reqwest::Client::builder().connect_timeout(connect_timeout);
+    if let Some(proxy) = proxy {
+        builder = builder.proxy(proxy.to_reqwest()?);
+    }
+    Ok(builder.build()?)
+}
+
+async fn cancellable<T>(
+    cancel: &CancellationToken,
+    future: impl Future<Output = Result<T, TrackerError>>,
+) -> Result<T, TrackerError> {
+    tokio::select! {
+        result = future => result,
+        _ = cancel.cancelled() => Err(TrackerError::Cancelled),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::torrent::tracker::tests::serve;
+    use std::net::TcpListener;
+    use std::time::Instant;
+
+    /// Accepts connections into its backlog but never answers.
+    fn silent_tracker() -> (TcpListener, String) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/announce", listener.local_addr().unwrap());
+        (listener, url)
+    }
+
+    fn client() -> AsyncTrackerClient {
+        AsyncTrackerClient::new()
+            .unwrap()
+            .timeout(Duration::from_millis(300))
+            .retry_delay(Duration::from_millis(10))
+    }
+
+    fn request() -> AnnounceRequest {
+        AnnounceRequest::new(InfoHash::V1([0x01; 20]), 10)
+    }
+
+    #[tokio::test]
+    async fn retries_transient_failures_only() {
+        let (url, server) = serve(vec![
+            ("503 Service Unavailable", b"busy".to_vec()),
+            ("200 OK", b"d8:intervali60e5:peers0:e".to_vec()),
+        ]);
+        let cancel = CancellationToken::new();
+        let response = client().announce(&url, &request(), &cancel).await.unwrap();
+        assert_eq!(response.interval, Duration::from_secs(60));
+        assert_eq!(server.join().unwrap().len(), 2);
+
+        let (url, server) = serve(vec![("200 OK", b"d14:failure reason6:bannede".to_vec())]);
+        assert!(matches!(
+            client().announce(&url, &request(), &cancel).await,
+            Err(TrackerError::Failure(_))
+        ));
+        server.join().unwrap();
+    }
+
+    #[tokio::test]
+    async fn slow_trackers_time_out_concurrently() {
+        let (_first, first_url) = silent_tracker();
+        let (_second, second_url) = silent_tracker();
+        let (url, server) = serve(vec![(
+            "200 OK",
+            b"d8:intervali60e5:peers6:\x7f\x00\x00\x01\x1a\xe1e".to_vec(),
+        )]);
+        let client = client().retries(0);
+
+        let started = Instant::now();
+        let results = client
+            .announce_all(
+                &[first_url.clone(), url.clone(), second_url],
+                &request(),
+                &CancellationToken::new(),
+            )
+            .await;
+        // One timeout instead of two after each other.
+        assert!(started.elapsed() < Duration::from_millis(550));
+        assert_eq!(results[0].0, first_url);
+        assert!(matches!(&results[0].1, Err(TrackerError::Http(e)) if e.is_timeout()));
+        assert_eq!(results[1].1.as_ref().unwrap().peers.len(), 1);
+        assert!(results[2].1.is_err());
+        server.join().unwrap();
+    }
+
+    #[tokio::test]
+    async fn cancellation_stops_waiting() {
+        let (_listener, url) = silent_tracker();
+        let client = client().timeout(Duration::from_secs(30));
+        let cancel = CancellationToken::new();
+        let canceller = cancel.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            canceller.cancel();
+        });
+
+        let started = Instant::now();
+        assert!(matches!(
+            client.announce(&url, &request(), &cancel).await,
+            Err(TrackerError::Cancelled)
+        ));
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert!(cancel.is_cancelled());
+    }
+
+    #[tokio::test]
+    async fn resolves_peer_hostnames() {
+        let mut response =
+            TrackerResponse::from_bytes(b"d8:intervali60e5:peersld2:ip9:localhost4:porti6881eeee")
+                .unwrap();
+        assert!(response.peers.is_empty());
+        resolve_peer_hosts(&mut response, Duration::from_secs(5)).await;
+        assert!(response.peer_hosts.is_empty());
+        assert_eq!(response.peers.len(), 1);
+        assert!(response.peers[0].ip().is_loopback());
+    }
+
+    #[tokio::test]
+    async fn udp_announces_are_bounded_and_cancellable() {
+        let silent = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let url = format!("udp://{}", silent.local_addr().unwrap());
+
+        let started = Instant::now();
+        assert!(matches!(
+            client()
+                .retries(1)
+                .announce(&url, &request(), &CancellationToken::new())
+                .await,
+            Err(TrackerError::Timeout)
+        ));
+        assert!(started.elapsed() < Duration::from_secs(2));
+
+        // A cancelled exchange stops and releases the tracker for the next announce.
+        let patient = client().timeout(Duration::from_secs(30));
+        let cancel = CancellationToken::new();
+        let canceller = cancel.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            canceller.cancel();
+        });
+        assert!(matches!(
+            patient.announce(&url, &request(), &cancel).await,
+            Err(TrackerError::Cancelled)
+        ));
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        let udp_client = patient.udp_clients.lock().unwrap()[&url].clone();
+        assert!(udp_client.try_lock().is_ok());
+
+        // So is connecting through a proxy that never answers.
+        let proxy = TcpListener::bind("127.0.0.1:0").unwrap();
+        let proxied = client()
+            .proxy(ProxyConfig::socks5(proxy.local_addr().unwrap().to_string()))
+            .unwrap();
+        let started = Instant::now();
+        assert!(proxied
+            .announce(&url, &request(), &CancellationToken::new())
+            .await
+            .is_err());
+        assert!(started.elapsed() < Duration::from_secs(2));
+    }
+}
@@ -0,0 +1,1090 @@
+//! Announcing to trackers to find peers of a swarm.
+
+pub mod announcer;
+pub mod async_client;
+pub mod scrape;
+pub mod server;
+pub mod udp;
+pub mod url;
+
+use crate::bencode::{BencodeDeserializationError, BencodeDeserializer};
+use crate::torrent::magnet::MagnetLink;
+use crate::torrent::meta::{InfoHash, TorrentFile, TorrentFileError};
+use crate::torrent::peer_id::{self, PeerIdGenerator, PeerIdPolicy};
+use crate::torrent::proxy::{ProxyConfig, ProxyError};
+use crate::torrent::tracker::scrape::ScrapeStats;
+use crate::torrent::tracker::udp::{Interrupt, UdpTrackerClient};
+use serde::Deserialize;
+use serde_bytes::ByteBuf;
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs};
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::{Duration, Instant};
+
+#[derive(Debug, thiserror::Error)]
+pub enum TrackerError {
+    #[error("tracker request failed: {0}")]
+    Http(#[from] reqwest::Error),
+    #[error("tracker replied with HTTP status {0}")]
+    Status(reqwest::StatusCode),
+    #[error("cannot decode tracker response: {0}")]
+    Decode(#[from] BencodeDeserializationError),
+    #[error("tracker refused the announce: {0}")]
+    Failure(String),
+    #[error("tracker response has no `{0}`")]
+    MissingField(&'static str),
+    #[error("compact peer list of {0} bytes is not a multiple of 6")]
+    InvalidPeers(usize),
+    #[error("compact IPv6 peer list of {0} bytes is not a multiple of 18")]
+    InvalidPeers6(usize),
+    #[error("no supported tracker to announce to")]
+    NoTracker,
+    #[error("invalid tracker URL {0:?}")]
+    InvalidUrl(String),
+    #[error("tracker I/O failed: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("tracker did not answer")]
+    Timeout,
+    #[error("unexpected tracker response: {0}")]
+    UnexpectedResponse(String),
+    #[error("tracker {0} does not support scraping")]
+    ScrapeUnsupported(String),
+    #[error("tracker request was cancelled")]
+    Cancelled,
+    #[error(transparent)]
+    Proxy(#[from] ProxyError),
+    #[error(transparent)]
+    Torrent(#[from] TorrentFileError),
+}
+
+impl TrackerError {
+    /// Whether trying again later may succeed: network trouble and server errors,
+    /// but not a refusal or a reply we cannot read.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            TrackerError::Http(e) => !e.is_decode() && !e.is_builder(),
+            TrackerError::Status(status) => status.is_server_error(),
+            TrackerError::Io(_) | TrackerError::Timeout => true,
+            TrackerError::Proxy(ProxyError::Io(_)) => true,
+            _ => false,
+        }
+    }
+}
+
+/// `announce` and every `announce-list` URL a client here can announce to, without
+/// duplicates.
+pub fn supported_trackers(torrent_file: &TorrentFile) -> Vec<String> {
+    let mut trackers: Vec<String> = Vec::new();
+    let tiers = torrent_file.announce_list.iter().flatten().flatten();
+    for url in std::iter::once(&torrent_file.announce).chain(tiers) {
+        if TorrentTrackerClient::supports(url) && !trackers.contains(url) {
+            trackers.push(url.clone());
+        }
+    }
+    trackers
+}
+
+pub struct TorrentTrackerClient {
+    tracker_client: reqwest::blocking::Client,
+    /// UDP clients by tracker URL, so connection IDs are reused.
+    /// One client per URL, so a slow tracker only blocks announces to itself.
+    udp_clients: Mutex<HashMap<String, Arc<Mutex<UdpTrackerClient>>>>,
+    peer_ids: PeerIdGenerator,
+    proxy: Option<ProxyConfig>,
+    timeout: Option<Duration>,
+}
+
+impl TorrentTrackerClient {
+    pub fn new() -> Self {
+        Self {
+            tracker_client: reqwest::blocking::Client::new(),
+            udp_clients: Mutex::new(HashMap::new()),
+            peer_ids: PeerIdGenerator::default(),
+            proxy: None,
+            timeout: None,
+        }
+    }
+
+    /// Sends HTTP announces and, for SOCKS5, UDP ones through `proxy`.
+    pub fn proxy(mut self, proxy: ProxyConfig) -> Result<Self, TrackerError> {
+        self.tracker_client = reqwest::blocking::Client::builder()
+            .proxy(proxy.to_reqwest()?)
+            .build()?;
+        self.udp_clients = Mutex::new(HashMap::new());
+        self.proxy = Some(proxy);
+        Ok(self)
+    }
+
+    /// Bounds every request, UDP retransmissions included. Without it a silent UDP
+    /// tracker is retried for hours.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    pub fn peer_id_policy(mut self, policy: PeerIdPolicy) -> Self {
+        self.peer_ids = PeerIdGenerator::new(policy);
+        self
+    }
+
+    /// Peer ID announced for the torrent with `info_hashes`, to be used in the handshake
+    /// with its peers too.
+    pub fn peer_id_for(&self, info_hashes: &[InfoHash]) -> [u8; 20] {
+        self.peer_ids.peer_id_for(info_hashes)
+    }
+
+    /// Whether announces to `url` are implemented.
+    pub fn supports(url: &str) -> bool {
+        url.starts_with("http://") || url.starts_with("https://") || url.starts_with("udp://")
+    }
+
+    pub fn get_peers(&self, torrent_file: &TorrentFile) -> Result<Vec<SocketAddr>, TrackerError> {
+        self.get_peers_for(torrent_file, &torrent_file.primary_info_hash()?)
+    }
+
+    /// Announces to the swarm of `info_hash`, hybrid torrents have one for each version.
+    pub fn get_peers_for(
+        &self,
+        torrent_file: &TorrentFile,
+        info_hash: &InfoHash,
+    ) -> Result<Vec<SocketAddr>, TrackerError> {
+        let request = AnnounceRequest::new(*info_hash, torrent_file.info().total_length() as u64)
+            .peer_id(self.peer_id_for(&torrent_file.info_hashes()?))
+            .event(AnnounceEvent::Started);
+        let mut response = self.announce(&torrent_file.announce, &request)?;
+        response.resolve_peer_hosts(RESOLVE_TIMEOUT);
+        Ok(response.peers)
+    }
+
+    /// Announces a magnet link to its first supported tracker from `tr`.
+    pub fn get_peers_for_magnet(
+        &self,
+        magnet: &MagnetLink,
+    ) -> Result<Vec<SocketAddr>, TrackerError> {
+        let Some(info_hash) = magnet.info_hashes.first() else {
+            return Ok(Vec::new());
+        };
+        let Some(tracker) = magnet
+            .trackers
+            .iter()
+            .find(|tracker| Self::supports(tracker))
+        else {
+            return Err(TrackerError::NoTracker);
+        };
+        let request = AnnounceRequest::new(*info_hash, UNKNOWN_LEFT)
+            .peer_id(self.peer_id_for(&magnet.info_hashes));
+        let mut response = self.announce(tracker, &request)?;
+        response.resolve_peer_hosts(RESOLVE_TIMEOUT);
+        Ok(response.peers)
+    }
+
+    /// Announces over HTTP or, for `udp://` URLs, BEP 15.
+    pub fn announce(
+        &self,
+        announce_url: &str,
+        request: &AnnounceRequest,
+    ) -> Result<TrackerResponse, TrackerError> {
+        if announce_url.starts_with("udp://") {
+            return self.with_udp_client(announce_url, |client| client.announce(request));
+        }
+
+        let url = url::with_query(announce_url, &request.query());
+        TrackerResponse::from_bytes(&self.get(&url)?)
+    }
+
+    /// Swarm statistics of `info_hashes` from the tracker behind `announce_url`, without
+    /// announcing. Torrents the tracker does not know are missing from the result.
+    pub fn scrape(
+        &self,
+        announce_url: &str,
+        info_hashes: &[InfoHash],
+    ) -> Result<HashMap<InfoHash, ScrapeStats>, TrackerError> {
+        let truncated: Vec<[u8; 20]> = info_hashes.iter().map(InfoHash::truncated).collect();
+
+        if announce_url.starts_with("udp://") {
+            let stats = self.with_udp_client(announce_url, |client| client.scrape(&truncated))?;
+            return Ok(info_hashes.iter().copied().zip(stats).collect());
+        }
+
+        let scrape_url = url::scrape_url(announce_url)
+            .ok_or_else(|| TrackerError::ScrapeUnsupported(announce_url.to_string()))?;
+        let url = url::with_query(&scrape_url, &scrape::scrape_query(&truncated));
+        let mut stats = scrape::parse_scrape_response(&self.get(&url)?)?;
+        Ok(info_hashes
+            .iter()
+            .zip(&truncated)
+            .filter_map(|(info_hash, hash)| Some((*info_hash, stats.remove(hash)?)))
+            .collect())
+    }
+
+    fn get(&self, url: &str) -> Result<Vec<u8>, TrackerError> {
+        let mut request = self.tracker_client.get(url);
+        if let Some(timeout) = self.timeout {
+            request = request.timeout(timeout);
+        }
+        let response = request.send()?;
+        if !response.status().is_success() {
+            return Err(TrackerError::Status(response.status()));
+        }
+        Ok(response.bytes()?.to_vec())
+    }
+
+    /// Runs `f` with the client of a UDP tracker, created on first use.
+    fn with_udp_client<T>(
+        &self,
+        url: &str,
+        f: impl FnOnce(&mut UdpTrackerClient) -> Result<T, TrackerError>,
+    ) -> Result<T, TrackerError> {
+        let deadline = self.timeout.map(|timeout| Instant::now() + timeout);
+        let existing = self
+            .udp_clients
+            .lock()
+            .expect("UDP clients lock poisoned")
+            .get(url)
+            .cloned();
+        let client = match existing {
+            Some(client) => client,
+            None => {
+                // Connecting resolves and may talk to a proxy, so it happens unlocked.
+                // The proxy handshake gets no more than what is left of the deadline.
+                let proxy = self.proxy.clone().map(|proxy| match deadline {
+                    Some(deadline) => {
+                        let timeout = proxy
+                            .timeout
+                            .min(deadline.saturating_duration_since(Instant::now()));
+                        proxy.timeout(timeout)
+                    }
+                    None => proxy,
+                });
+                let client = UdpTrackerClient::connect_via(url, proxy.as_ref())?;
+                let mut udp_clients = self.udp_clients.lock().expect("UDP clients lock poisoned");
+                Arc::clone(
+                    udp_clients
+                        .entry(url.to_string())
+                        .or_insert_with(|| Arc::new(Mutex::new(client))),
+                )
+            }
+        };
+        let mut client = client.lock().expect("UDP client lock poisoned");
+        client.set_interrupt(deadline.map(|deadline| Interrupt::new().deadline(deadline)));
+        f(&mut client)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AnnounceEvent {
+    Started,
+    Completed,
+    Stopped,
+}
+
+impl AnnounceEvent {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AnnounceEvent::Started => "started",
+            AnnounceEvent::Completed => "completed",
+            AnnounceEvent::Stopped => "stopped",
+        }
+    }
+}
+
+/// Limit for resolving the hostnames of one announce reply.
+pub const RESOLVE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// `left` while the size of a torrent is unknown, e.g. for a magnet link before the
+/// metadata arrives. Trackers count `left=0` as a seeder.
+pub const UNKNOWN_LEFT: u64 = 16 * 1024;
+
+/// Parameters of one announce. Regular re-announces have no `event`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AnnounceRequest {
+    pub info_hash: InfoHash,
+    pub peer_id: [u8; 20],
+    pub port: u16,
+    pub uploaded: u64,
+    pub downloaded: u64,
+    pub left: u64,
+    pub event: Option<AnnounceEvent>,
+    pub numwant: Option<u32>,
+    /// Random value that lets the tracker recognize us when our IP changes.
+    pub key: Option<u32>,
+    pub ip: Option<IpAddr>,
+    /// BEP 7: our IPv6 address, so trackers reached over IPv4 can hand it out too.
+    pub ipv6: Option<Ipv6Addr>,
+    /// `tracker id` from a previous response, sent back as `trackerid`.
+    pub tracker_id: Option<String>,
+    pub no_peer_id: bool,
+}
+
+impl AnnounceRequest {
+    /// Announces with the session peer ID unless [`Self::peer_id`] sets another.
+    pub fn new(info_hash: InfoHash, left: u64) -> Self {
+        Self {
+            info_hash,
+            peer_id: peer_id::session_peer_id(),
+            port: 6881,
+            uploaded: 0,
+            downloaded: 0,
+            left,
+            event: None,
+            numwant: None,
+            key: None,
+            ip: None,
+            ipv6: None,
+            tracker_id: None,
+            no_peer_id: false,
+        }
+    }
+
+    pub fn peer_id(mut self, peer_id: [u8; 20]) -> Self {
+        self.peer_id = peer_id;
+        self
+    }
+
+    pub fn port(mut self, port: u16) -> Self {
+        self.port = port;
+        self
+    }
+
+    pub fn uploaded(mut self, uploaded: u64) -> Self {
+        self.uploaded = uploaded;
+        self
+    }
+
+    pub fn downloaded(mut self, downloaded: u64) -> Self {
+        self.downloaded = downloaded;
+        self
+    }
+
+    pub fn left(mut self, left: u64) -> Self {
+        self.left = left;
+        self
+    }
+
+    pub fn event(mut self, event: AnnounceEvent) -> Self {
+        self.event = Some(event);
+        self
+    }
+
+    pub fn numwant(mut self, numwant: u32) -> Self {
+        self.numwant = Some(numwant);
+        self
+    }
+
+    pub fn key(mut self, key: u32) -> Self {
+        self.key = Some(key);
+        self
+    }
+
+    pub fn ip(mut self, ip: IpAddr) -> Self {
+        self.ip = Some(ip);
+        self
+    }
+
+    pub fn ipv6(mut self, ipv6: Ipv6Addr) -> Self {
+        self.ipv6 = Some(ipv6);
+        self
+    }
+
+    pub fn tracker_id(mut self, tracker_id: Option<String>) -> Self {
+        self.tracker_id = tracker_id;
+        self
+    }
+
+    pub fn no_peer_id(mut self) -> Self {
+        self.no_peer_id = true;
+        self
+    }
+
+    /// Query string of an HTTP announce, without the leading `?`.
+    pub fn query(&self) -> String {
+        let hash = self.info_hash.truncated();
+        let mut query = format!(
+            "info_hash={}&peer_id={}&port={}&uploaded={}&downloaded={}&left={}&compact=1",
+            url::encode(&hash),
+            url::encode(&self.peer_id),
+            self.port,
+            self.uploaded,
+            self.downloaded,
+            self.left
+        );
+        if self.no_peer_id {
+            query.push_str("&no_peer_id=1");
+        }
+        if let Some(event) = self.event {
+            query.push_str(&format!("&event={}", event.as_str()));
+        }
+        if let Some(numwant) = self.numwant {
+            query.push_str(&format!("&numwant={}", numwant));
+        }
+        if let Some(key) = self.key {
+            query.push_str(&format!("&key={:08x}", key));
+        }
+        if let Some(ip) = self.ip {
+            query.push_str(&format!("&ip={}", url::encode(ip.to_string().as_bytes())));
+        }
+        if let Some(ipv6) = self.ipv6 {
+            query.push_str(&format!(
+                "&ipv6={}",
+                url::encode(ipv6.to_string().as_bytes())
+            ));
+        }
+        if let Some(tracker_id) = &self.tracker_id {
+            query.push_str(&format!(
+                "&trackerid={}",
+                url::encode(tracker_id.as_bytes())
+            ));
+        }
+        query
+    }
+}
+
+/// Announce reply as sent by the tracker. A failure reply may only have `failure reason`.
+#[derive(Debug, Default, PartialEq, serde::Deserialize, serde::Serialize)]
+pub struct RawTrackerResponse {
+    #[serde(
+        rename = "failure reason",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    failure_reason: Option<String>,
+    #[serde(
+        rename = "warning message",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    warning_message: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    interval: Option<u64>,
+    #[serde(
+        rename = "min interval",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    min_interval: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    peers: Option<PeerList>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    peers6: Option<ByteBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    complete: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    incomplete: Option<u64>,
+    #[serde(
+        rename = "tracker id",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    tracker_id: Option<String>,
+    /// BEP 24: our address as the tracker sees it, 4 or 16 bytes.
+    #[serde(
+        rename = "external ip",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    external_ip: Option<ByteBuf>,
+}
+
+/// BEP 23 compact peers, or the original list of dictionaries when a tracker
+/// ignores `compact=1`.
+#[derive(Debug, PartialEq, serde::Deserialize, serde::Serialize)]
+#[serde(untagged)]
+pub enum PeerList {
+    Compact(ByteBuf),
+    Dictionary(Vec<DictionaryPeer>),
+}
+
+/// A dictionary peer whose `ip` is a hostname.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PeerHost {
+    pub host: String,
+    pub port: u16,
+    pub peer_id: Option<[u8; 20]>,
+}
+
+/// Every key is optional here, so a broken entry is reported with its index.
+#[derive(Debug, PartialEq, serde::Deserialize, serde::Serialize)]
+pub struct DictionaryPeer {
+    /// IPv4 or IPv6 address, or a hostname.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    ip: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    port: Option<i64>,
+    #[serde(rename = "peer id", default, skip_serializing_if = "Option::is_none")]
+    peer_id: Option<ByteBuf>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TrackerResponse {
+    pub interval: Duration,
+    /// Regular announces must not be sent more often than this.
+    pub min_interval: Option<Duration>,
+    /// Peers of `peers` followed by those of `peers6`.
+    pub peers: Vec<SocketAddr>,
+    /// Peer IDs of a dictionary peer list, compact lists have none.
+    pub peer_ids: HashMap<SocketAddr, [u8; 20]>,
+    /// Dictionary peers given by hostname. Parsing does no I/O, see
+    /// [`TrackerResponse::resolve_peer_hosts`].
+    pub peer_hosts: Vec<PeerHost>,
+    /// Dictionary peers that were skipped.
+    pub malformed_peers: Vec<MalformedPeer>,
+    pub complete: Option<u64>,
+    pub incomplete: Option<u64>,
+    /// The announce succeeded, but the tracker wants the user to know something.
+    pub warning_message: Option<String>,
+    /// To be sent back as `trackerid` on the next announces.
+    pub tracker_id: Option<String>,
+    /// Our public address according to the tracker, see [`Session::report_external_ip`].
+    ///
+    /// [`Session::report_external_ip`]: crate::torrent::session::Session::report_external_ip
+    pub external_ip: Option<IpAddr>,
+}
+
+impl TrackerResponse {
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, TrackerError> {
+        let mut deserializer = BencodeDeserializer::new(bytes);
+        RawTrackerResponse::deserialize(&mut deserializer)?.try_into()
+    }
+
+    /// Moves `peer_hosts` into `peers`, resolving them on a helper thread. Hosts not
+    /// resolved within `timeout` are dropped.
+    pub fn resolve_peer_hosts(&mut self, timeout: Duration) {
+        let hosts = std::mem::take(&mut self.peer_hosts);
+        if hosts.is_empty() {
+            return;
+        }
+        let deadline = Instant::now() + timeout;
+        let (sender, receiver) = mpsc::channel();
+        std::thread::spawn(move || {
+            for host in hosts {
+                let address = (host.host.as_str(), host.port)
+                    .to_socket_addrs()
+                    .ok()
+                    .and_then(|mut addresses| addresses.next());
+                if sender.send(address.map(|a| (a, host.peer_id))).is_err() {
+                    return;
+                }
+            }
+        });
+        while let Ok(resolved) =
+            receiver.recv_timeout(deadline.saturating_duration_since(Instant::now()))
+        {
+            if let Some((address, peer_id)) = resolved {
+                self.add_peer(address, peer_id);
+            }
+        }
+    }
+
+    fn add_peer(&mut self, address: SocketAddr, peer_id: Option<[u8; 20]>) {
+        if let Some(peer_id) = peer_id {
+            self.peer_ids.insert(address, peer_id);
+        }
+        if !self.peers.contains(&address) {
+            self.peers.push(address);
+        }
+    }
+}
+
+impl TryFrom<RawTrackerResponse> for TrackerResponse {
+    type Error = TrackerError;
+
+    fn try_from(tracker_response: RawTrackerResponse) -> Result<Self, Self::Error> {
+        if let Some(reason) = tracker_response.failure_reason {
+            return Err(TrackerError::Failure(reason));
+        }
+        let interval = tracker_response
+            .interval
+            .ok_or(TrackerError::MissingField("interval"))?;
+        // IPv6-only trackers may send nothing but `peers6`.
+        if tracker_response.peers.is_none() && tracker_response.peers6.is_none() {
+            return Err(TrackerError::MissingField("peers"));
+        }
+        let mut peer_ids = HashMap::new();
+        let mut peer_hosts = Vec::new();
+        let mut malformed_peers = Vec::new();
+        let mut peers = match &tracker_response.peers {
+            Some(PeerList::Compact(peers)) => parse_compact_peers(peers)?,
+            Some(PeerList::Dictionary(entries)) => parse_dictionary_peers(
+                entries,
+                &mut peer_ids,
+                &mut peer_hosts,
+                &mut malformed_peers,
+            ),
+            None => Vec::new(),
+        };
+        if let Some(peers6) = &tracker_response.peers6 {
+            peers.extend(parse_compact_peers6(peers6)?);
+        }
+
+        Ok(Self {
+            interval: Duration::from_secs(interval),
+            min_interval: tracker_response.min_interval.map(Duration::from_secs),
+            peers,
+            peer_ids,
+            peer_hosts,
+            malformed_peers,
+            complete: tracker_response.complete,
+            incomplete: tracker_response.incomplete,
+            warning_message: tracker_response.warning_message,
+            tracker_id: tracker_response.tracker_id,
+            // Only a hint, a malformed one is not worth failing the announce.
+            external_ip: tracker_response
+                .external_ip
+                .as_deref()
+                .and_then(|ip| parse_external_ip(ip)),
+        })
+    }
+}
+
+/// BEP 24: the raw bytes of an IPv4 or IPv6 address.
+fn parse_external_ip(bytes: &[u8]) -> Option<IpAddr> {
+    match bytes.len() {
+        4 => <[u8; 4]>::try_from(bytes).ok().map(IpAddr::from),
+        16 => <[u8; 16]>::try_from(bytes).ok().map(IpAddr::from),
+        _ => None,
+    }
+}
+
+/// BEP 23: 4 bytes of IPv4 address and 2 bytes of port per peer.
+fn parse_compact_peers(peers: &[u8]) -> Result<Vec<SocketAddr>, TrackerError> {
+    if !peers.len().is_multiple_of(6) {
+        return Err(TrackerError::InvalidPeers(peers.len()));
+    }
+    Ok(peers
+        .chunks_exact(6)
+        .map(|chunk| {
+            let ip = Ipv4Addr::new(chunk[0], chunk[1], chunk[2], chunk[3]);
+            SocketAddr::new(ip.into(), u16::from_be_bytes([chunk[4], chunk[5]]))
+        })
+        .collect())
+}
+
+/// An entry of a dictionary peer list that was left out.
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+#[error("peer {index} of the peer list is malformed: {reason}")]
+pub struct MalformedPeer {
+    pub index: usize,
+    pub reason: &'static str,
+}
+
+/// BEP 3 peer dictionaries. Hostnames go to `peer_hosts` unresolved, malformed
+/// entries are skipped and reported in `malformed_peers`.
+fn parse_dictionary_peers(
+    entries: &[DictionaryPeer],
+    peer_ids: &mut HashMap<SocketAddr, [u8; 20]>,
+    peer_hosts: &mut Vec<PeerHost>,
+    malformed_peers: &mut Vec<MalformedPeer>,
+) -> Vec<SocketAddr> {
+    let mut peers = Vec::with_capacity(entries.len());
+    for (index, entry) in entries.iter().enumerate() {
+        let (host, port, peer_id) = match parse_dictionary_peer(entry) {
+            Ok(peer) => peer,
+            Err(reason) => {
+                malformed_peers.push(MalformedPeer { index, reason });
+                continue;
+            }
+        };
+        match host.parse::<IpAddr>() {
+            Ok(ip) => {
+                let address = SocketAddr::new(ip, port);
+                if let Some(peer_id) = peer_id {
+                    peer_ids.insert(address, peer_id);
+                }
+                peers.push(address);
+            }
+            Err(_) => peer_hosts.push(PeerHost {
+                host: host.to_string(),
+                port,
+                peer_id,
+            }),
+        }
+    }
+    peers
+}
+
+fn parse_dictionary_peer(
+    entry: &DictionaryPeer,
+) -> Result<(&str, u16, Option<[u8; 20]>), &'static str> {
+    let host = match entry.ip.as_deref() {
+        None => return Err("no ip"),
+        Some("") => return Err("empty ip"),
+        Some(host) => host,
+    };
+    let port = entry.port.ok_or("no port")?;
+    let port = u16::try_from(port).map_err(|_| "port out of range")?;
+    let peer_id = match &entry.peer_id {
+        None => None,
+        Some(peer_id) => {
+            Some(<[u8; 20]>::try_from(peer_id.as_slice()).map_err(|_| "peer id is not 20 bytes")?)
+        }
+    };
+    Ok((host, port, peer_id))
+}
+
+/// BEP 7: 16 bytes of IPv6 address and 2 bytes of port per peer.
+fn parse_compact_peers6(peers: &[u8]) -> Result<Vec<SocketAddr>, TrackerError> {
+    if !peers.len().is_multiple_of(18) {
+        return Err(TrackerError::InvalidPeers6(peers.len()));
+    }
+    Ok(peers
+        .chunks_exact(18)
+        .map(|chunk| {
+            let octets: [u8; 16] = chunk[..16].try_into().expect("chunk is 18 bytes");
+            let ip = Ipv6Addr::from(octets);
+            SocketAddr::new(ip.into(), u16::from_be_bytes([chunk[16], chunk[17]]))
+        })
+        .collect())
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::thread::JoinHandle;
+
+    /// Answers HTTP requests with `responses` in order, returning the request lines.
+    pub(super) fn serve(
+        responses: Vec<(&'static str, Vec<u8>)>,
+    ) -> (String, JoinHandle<Vec<String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/announce", listener.local_addr().unwrap());
+
+        let handle = std::thread::spawn(move || {
+            let mut request_lines = Vec::new();
+            for (status, body) in responses {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut request = Vec::new();
+                let mut buffer = [0; 1024];
+                while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+                    let read = stream.read(&mut buffer).unwrap();
+                    request.extend_from_slice(&buffer[..read]);
+                }
+                let mut response = format!(
+                    "HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                    status,
+                    body.len()
+                )
+                .into_bytes();
+                response.extend_from_slice(&body);
+                stream.write_all(&response).unwrap();
+                let request = String::from_utf8_lossy(&request).to_string();
+                request_lines.push(request.lines().next().unwrap_or_default().to_string());
+            }
+            request_lines
+        });
+        (url, handle)
+    }
+
+    fn serve_once(status: &'static str, body: &[u8]) -> (String, JoinHandle<Vec<String>>) {
+        serve(vec![(status, body.to_vec())])
+    }
+
+    #[test]
+    fn parse_response() {
+        let body = b"d8:completei3e10:incompletei1e8:intervali1800e12:min intervali60e5:peers6:\x7f\x00\x00\x01\x1a\xe115:warning message9:slow downe";
+        let response = TrackerResponse::from_bytes(body).unwrap();
+        assert_eq!(response.interval, Duration::from_secs(1800));
+        assert_eq!(response.min_interval, Some(Duration::from_secs(60)));
+        assert_eq!(
+            response.peers,
+            vec![SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 6881)]
+        );
+        assert_eq!(response.complete, Some(3));
+        assert_eq!(response.warning_message.as_deref(), Some("slow down"));
+    }
+
+    #[test]
+    fn parse_optional_fields() {
+        // Only `interval` and the peers are required.
+        let response = TrackerResponse::from_bytes(b"d8:intervali900e5:peers0:e").unwrap();
+        assert_eq!(response.complete, None);
+        assert_eq!(response.min_interval, None);
+        assert_eq!(response.tracker_id, None);
+        assert_eq!(response.external_ip, None);
+
+        let body = b"d11:external ip4:\xcb\x00\x71\x078:intervali900e5:peers0:10:tracker id3:abce";
+        let response = TrackerResponse::from_bytes(body).unwrap();
+        assert_eq!(
+            response.external_ip,
+            Some(Ipv4Addr::new(203, 0, 113, 7).into())
+        );
+        assert_eq!(response.tracker_id.as_deref(), Some("abc"));
+
+        let mut body = b"d11:external ip16:".to_vec();
+        body.extend_from_slice(&Ipv6Addr::LOCALHOST.octets());
+        body.extend_from_slice(b"8:intervali900e5:peers0:e");
+        let response = TrackerResponse::from_bytes(&body).unwrap();
+        assert_eq!(response.external_ip, Some(Ipv6Addr::LOCALHOST.into()));
+
+        let body = b"d11:external ip3:abc8:intervali900e5:peers0:e";
+        assert_eq!(TrackerResponse::from_bytes(body).unwrap().external_ip, None);
+    }
+
+    #[test]
+    fn parse_dictionary_peers() {
+        let body = b"d8:intervali60e5:peersl\
+            d2:ip8:10.0.0.17:peer id20:-BR0100-abcdefghijkl4:porti6881ee\
+            d2:ip3:::14:porti6882ee\
+            d2:ip9:localhost4:porti6883ee\
+            ee";
+        let mut response = TrackerResponse::from_bytes(body).unwrap();
+        let first = SocketAddr::new(Ipv4Addr::new(10, 0, 0, 1).into(), 6881);
+        assert_eq!(response.peers.len(), 2);
+        assert_eq!(response.peers[0], first);
+        assert_eq!(
+            response.peers[1],
+            SocketAddr::new(Ipv6Addr::LOCALHOST.into(), 6882)
+        );
+        assert_eq!(
+            response.peer_ids,
+            HashMap::from([(first, *b"-BR0100-abcdefghijkl")])
+        );
+        // Hostnames are left to the caller.
+        assert_eq!(
+            response.peer_hosts,
+            vec![PeerHost {
+                host: "localhost".to_string(),
+                port: 6883,
+                peer_id: None
+            }]
+        );
+        response.resolve_peer_hosts(RESOLVE_TIMEOUT);
+        assert!(response.peer_hosts.is_empty());
+        assert_eq!(response.peers.len(), 3);
+        assert!(response.peers[2].ip().is_loopback());
+        assert_eq!(response.peers[2].port(), 6883);
+
+        let malformed: [(&[u8], &str); 4] = [
+            (b"d4:porti1ee", "no ip"),
+            (b"d2:ip8:10.0.0.1e", "no port"),
+            (b"d2:ip8:10.0.0.14:porti70000ee", "port out of range"),
+            (
+                b"d2:ip8:10.0.0.17:peer id3:abc4:porti1ee",
+                "peer id is not 20 bytes",
+            ),
+        ];
+        for (entry, expected) in malformed {
+            let mut body = b"d8:intervali60e5:peersld2:ip8:10.0.0.14:porti1ee".to_vec();
+            body.extend_from_slice(entry);
+            body.extend_from_slice(b"d2:ip8:10.0.0.24:porti2eeee");
+            // Valid peers around a malformed one are kept.
+            let response = TrackerResponse::from_bytes(&body).unwrap();
+            assert_eq!(
+                response.peers,
+                vec![
+                    SocketAddr::new(Ipv4Addr::new(10, 0, 0, 1).into(), 1),
+                    SocketAddr::new(Ipv4Addr::new(10, 0, 0, 2).into(), 2),
+                ]
+            );
+            assert!(
+                matches!(
+                    &response.malformed_peers[..],
+                    [MalformedPeer { index: 1, reason }] if *reason == expected
+                ),
+                "for {}",
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn parse_ipv6_peers() {
+        let mut body = b"d8:intervali60e5:peers6:\x7f\x00\x00\x01\x1a\xe16:peers618:".to_vec();
+        body.extend_from_slice(&Ipv6Addr::LOCALHOST.octets());
+        body.extend_from_slice(b"\x1a\xe2e");
+        let response = TrackerResponse::from_bytes(&body).unwrap();
+        assert_eq!(
+            response.peers,
+            vec![
+                SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 6881),
+                SocketAddr::new(Ipv6Addr::LOCALHOST.into(), 6882),
+            ]
+        );
+
+        // An IPv6-only tracker.
+        let mut body = b"d8:intervali60e6:peers618:".to_vec();
+        body.extend_from_slice(&Ipv6Addr::LOCALHOST.octets());
+        body.extend_from_slice(b"\x1a\xe2e");
+        assert_eq!(TrackerResponse::from_bytes(&body).unwrap().peers.len(), 1);
+
+        assert!(matches!(
+            TrackerResponse::from_bytes(b"d8:intervali60e6:peers66:abcdefe"),
+            Err(TrackerError::InvalidPeers6(6))
+        ));
+    }
+
+    #[test]
+    fn failure_replies_and_malformed_responses() {
+        let failure = b"d14:failure reason17:torrent not founde";
+        assert!(matches!(
+            TrackerResponse::from_bytes(failure),
+            Err(TrackerError::Failure(reason)) if reason == "torrent not found"
+        ));
+        assert!(matches!(
+            TrackerResponse::from_bytes(b"d5:peers0:e"),
+            Err(TrackerError::MissingField("interval"))
+        ));
+        assert!(matches!(
+            TrackerResponse::from_bytes(b"d8:intervali60e5:peers5:abcdee"),
+            Err(TrackerError::InvalidPeers(5))
+        ));
+        assert!(matches!(
+            TrackerResponse::from_bytes(b"<html>"),
+            Err(TrackerError::Decode(_))
+        ));
+    }
+
+    #[test]
+    fn http_errors_are_returned() {
+        let client = TorrentTrackerClient::new();
+        let info_hash = InfoHash::V1([0xAB; 20]);
+
+        let request = AnnounceRequest::new(info_hash, 10);
+
+        let (url, server) = serve_once("500 Internal Server Error", b"oops");
+        assert!(matches!(
+            client.announce(&url, &request),
+            Err(TrackerError::Status(status)) if status.as_u16() == 500
+        ));
+        server.join().unwrap();
+
+        let (url, server) = serve_once("200 OK", b"d8:intervali60e5:peers0:e");
+        let response = client.announce(&url, &request).unwrap();
+        assert_eq!(response.peers, vec![]);
+        let request_lines = server.join().unwrap();
+        assert!(request_lines[0].starts_with("GET /announce?info_hash=%AB%AB"));
+
+        // Nothing listens on the port any more.
+        assert!(matches!(
+            client.announce(&url, &request),
+            Err(TrackerError::Http(_))
+        ));
+    }
+
+    #[test]
+    fn scrapes_http_and_udp_trackers() {
+        let client = TorrentTrackerClient::new();
+        let known = InfoHash::V1([0xAA; 20]);
+        let unknown = InfoHash::V1([0xBB; 20]);
+
+        let mut body = b"d5:filesd20:".to_vec();
+        body.extend_from_slice(&[0xAA; 20]);
+        body.extend_from_slice(b"d8:completei5e10:downloadedi50e10:incompletei10eeee");
+        let (url, server) = serve_once("200 OK", &body);
+        let url = url.replace("/announce", "/announce.php?passkey=x");
+        let stats = client.scrape(&url, &[known, unknown]).unwrap();
+        assert_eq!(
+            stats,
+            HashMap::from([(
+                known,
+                ScrapeStats {
+                    complete: 5,
+                    incomplete: 10,
+                    downloaded: 50
+                }
+            )])
+        );
+        let request_lines = server.join().unwrap();
+        assert!(request_lines[0].starts_with(&format!(
+            "GET /scrape.php?passkey=x&info_hash={}&info_hash=%BB",
+            "%AA".repeat(20)
+        )));
+
+        assert!(matches!(
+            client.scrape("http://t.example/a", &[known]),
+            Err(TrackerError::ScrapeUnsupported(_))
+        ));
+
+        let (address, server) = udp::tests::udp_tracker(2);
+        let stats = client
+            .scrape(&format!("udp://{}", address), &[known, unknown])
+            .unwrap();
+        assert_eq!(stats[&known].complete, 10);
+        assert_eq!(stats[&unknown].complete, 11);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn silent_udp_tracker_does_not_block_others() {
+        let client = std::sync::Arc::new(TorrentTrackerClient::new());
+        let silent = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let silent_url = format!("udp://{}", silent.local_addr().unwrap());
+        // Gives up after 100 + 200 + 400 + 800 ms, well after the other scrape.
+        let silent_client = UdpTrackerClient::connect(&silent_url)
+            .unwrap()
+            .base_timeout(Duration::from_millis(100))
+            .max_retransmissions(3);
+        client
+            .udp_clients
+            .lock()
+            .unwrap()
+            .insert(silent_url.clone(), Arc::new(Mutex::new(silent_client)));
+        let waiting = std::sync::Arc::clone(&client);
+        let waiting =
+            std::thread::spawn(move || waiting.scrape(&silent_url, &[InfoHash::V1([0xAA; 20])]));
+        std::thread::sleep(Duration::from_millis(200));
+
+        let (address, server) = udp::tests::udp_tracker(2);
+        let stats = client
+            .scrape(&format!("udp://{}", address), &[InfoHash::V1([0xAA; 20])])
+            .unwrap();
+        assert_eq!(stats[&InfoHash::V1([0xAA; 20])].complete, 10);
+        server.join().unwrap();
+        assert!(!waiting.is_finished());
+        assert!(matches!(
+            waiting.join().unwrap(),
+            Err(TrackerError::Timeout)
+        ));
+        drop(silent);
+    }
+
+    #[test]
+    fn announces_and_scrapes_through_socks5_proxy() {
+        let (proxy, commands) = crate::torrent::proxy::tests::socks5_proxy(Some(("u", "p")));
+        let client = TorrentTrackerClient::new()
+            .proxy(ProxyConfig::socks5(proxy.to_string()).credentials("u", "p"))
+            .unwrap();
+
+        let (url, server) = serve_once("200 OK", b"d8:intervali60e5:peers0:e");
+        let request = AnnounceRequest::new(InfoHash::V1([0xAB; 20]), 10);
+        assert!(client.announce(&url, &request).unwrap().peers.is_empty());
+        server.join().unwrap();
+        assert_eq!(commands.recv().unwrap(), 0x01);
+
+        let (address, server) = udp::tests::udp_tracker(2);
+        let stats = client
+            .scrape(&format!("udp://{}", address), &[InfoHash::V1([0xAA; 20])])
+            .unwrap();
+        assert_eq!(stats[&InfoHash::V1([0xAA; 20])].complete, 10);
+        assert_eq!(server.join().unwrap(), vec![0, 2]);
+        assert_eq!(commands.recv().unwrap(), 0x03);
+    }
+
+    #[test]
+    fn announce_request_query() {
+        let request = AnnounceRequest::new(InfoHash::V1([0x01; 20]), 500)
+            .peer_id(*b"-BR0100-abcdefghijkl")
+            .port(51413)
+            .uploaded(1024)
+            .downloaded(2048)
+            .event(AnnounceEvent::Completed)
+            .numwant(50)
+            .key(0xBEEF)
+            .ip(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)))
+            .ipv6("2001:db8::1".parse().unwrap())
+            .tracker_id(Some("id 1".to_string()))
+            .no_peer_id();
+        assert_eq!(
+            request.query(),
+            "info_hash=%01%01%01%01%01%01%01%01%01%01%01%01%01%01%01%01%01%01%01%01\
+             &peer_id=-BR0100-abcdefghijkl&port=51413&uploaded=1024&downloaded=2048\
+             &left=500&compact=1&no_peer_id=1&event=completed&numwant=50&key=0000beef\
+             &ip=10.0.0.1&ipv6=2001%3Adb8%3A%3A1&trackerid=id%201"
+        );
+
+        let response =
+            TrackerResponse::from_bytes(b"d8:intervali60e5:peers0:10:tracker id3:abce").unwrap();
+        assert_eq!(response.tracker_id.as_deref(), Some("abc"));
+    }
+}
@@ -0,0 +1,121 @@
+//! Swarm statistics without joining the swarm.
+
+use crate::bencode::BencodeDeserializer;
+use crate::torrent::tracker::{url, TrackerError};
+use serde::Deserialize;
+use std::collections::HashMap;
+
+/// Swarm statistics of one info hash from a scrape.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+pub struct ScrapeStats {
+    /// Seeders.
+    #[serde(default)]
+    pub complete: u64,
+    /// Leechers.
+    #[serde(default)]
+    pub incomplete: u64,
+    /// Times the torrent was downloaded completely.
+    #[serde(default)]
+    pub downloaded: u64,
+}
+
+/// Query string of an HTTP scrape, one `info_hash` per torrent.
+pub fn scrape_query(info_hashes: &[[u8; 20]]) -> String {
+    info_hashes
+        .iter()
+        .map(|hash| format!("info_hash={}", url::encode(hash)))
+        .collect::<Vec<_>>()
+        .join("&")
+}
+
+/// Parses the `files` dictionary of an HTTP scrape reply. Torrents the tracker does
+/// not know are missing from the result.
+pub fn parse_scrape_response(bytes: &[u8]) -> Result<HashMap<[u8; 20], ScrapeStats>, TrackerError> {
+    let mut files = None;
+    for (key, value) in BencodeDeserializer::new(bytes).parse_raw_dict()? {
+        match key {
+            b"failure reason" => {
+                let mut deserializer = BencodeDeserializer::new(value);
+                return Err(TrackerError::Failure(String::deserialize(
+                    &mut deserializer,
+                )?));
+            }
+            b"files" => files = Some(value),
+            _ => {}
+        }
+    }
+    let files = files.ok_or(TrackerError::MissingField("files"))?;
+
+    let mut stats = HashMap::new();
+    for (info_hash, value) in BencodeDeserializer::new(files).parse_raw_dict()? {
+        let info_hash: [u8; 20] = info_hash.try_into().map_err(|_| {
+            TrackerError::UnexpectedResponse(format!(
+                "scraped info hash of {} bytes",
+                info_hash.len()
+            ))
+        })?;
+        let mut deserializer = BencodeDeserializer::new(value);
+        stats.insert(info_hash, ScrapeStats::deserialize(&mut deserializer)?);
+    }
+    Ok(stats)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_files_of_scrape_reply() {
+        let mut body = b"d5:filesd20:".to_vec();
+        body.extend_from_slice(&[0xAA; 20]);
+        body.extend_from_slice(b"d8:completei5e10:downloadedi50e10:incompletei10e4:name3:fooe20:");
+        body.extend_from_slice(&[0xBB; 20]);
+        body.extend_from_slice(b"d8:completei1eee5:flagsd20:min_request_intervali60eee");
+
+        let stats = parse_scrape_response(&body).unwrap();
+        assert_eq!(stats.len(), 2);
+        assert_eq!(
+            stats[&[0xAA; 20]],
+            ScrapeStats {
+                complete: 5,
+                incomplete: 10,
+                downloaded: 50
+            }
+        );
+        assert_eq!(
+            stats[&[0xBB; 20]],
+            ScrapeStats {
+                complete: 1,
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    fn scrape_failures() {
+        assert!(matches!(
+            parse_scrape_response(b"d14:failure reason13:not supportede"),
+            Err(TrackerError::Failure(reason)) if reason == "not supported"
+        ));
+        assert!(matches!(
+            parse_scrape_response(b"de"),
+            Err(TrackerError::MissingField("files"))
+        ));
+        assert!(matches!(
+            parse_scrape_response(b"d5:filesd3:abcdeee"),
+            Err(TrackerError::UnexpectedResponse(_))
+        ));
+    }
+
+    #[test]
+    fn query_repeats_info_hash() {
+        assert_eq!(
+            scrape_query(&[[0x01; 20], [0xFF; 20]]),
+            format!(
+                "info_hash={}&info_hash={}",
+                "%01".repeat(20),
+                "%FF".repeat(20)
+            )
+        );
+    }
+}
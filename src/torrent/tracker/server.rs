@@ -0,0 +1,717 @@
+//! A small HTTP tracker serving `/announce` and `/scrape` from memory.
+
+use crate::bencode::to_bencode;
+use crate::torrent::tracker::scrape::ScrapeStats;
+use crate::torrent::tracker::{DictionaryPeer, PeerList, RawTrackerResponse};
+use percent_encoding::percent_decode;
+use serde_bytes::ByteBuf;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+pub const DEFAULT_INTERVAL: Duration = Duration::from_secs(30 * 60);
+/// Peers that did not announce for this long are dropped from their swarm.
+pub const DEFAULT_PEER_TIMEOUT: Duration = Duration::from_secs(2 * 30 * 60);
+pub const DEFAULT_NUMWANT: usize = 50;
+pub const MAX_NUMWANT: usize = 200;
+/// Time a client has to send its whole request, and again to read the reply.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+const MAX_REQUEST_BYTES: usize = 8 * 1024;
+
+#[derive(Debug, Clone)]
+struct SwarmPeer {
+    /// The announcing address, plus the one from `ipv6=` if it was accepted.
+    addresses: Vec<SocketAddr>,
+    left: u64,
+    last_seen: Instant,
+}
+
+#[derive(Debug, Default)]
+struct Swarm {
+    /// Keyed by the announcing address too, so knowing a peer ID is not enough to
+    /// replace or remove that peer.
+    peers: HashMap<([u8; 20], IpAddr), SwarmPeer>,
+    downloaded: u64,
+}
+
+impl Swarm {
+    fn stats(&self) -> ScrapeStats {
+        let complete = self.peers.values().filter(|peer| peer.left == 0).count() as u64;
+        ScrapeStats {
+            complete,
+            incomplete: self.peers.len() as u64 - complete,
+            downloaded: self.downloaded,
+        }
+    }
+}
+
+/// Parameters of an announce as far as the tracker cares.
+#[derive(Debug)]
+struct Announce {
+    info_hash: [u8; 20],
+    peer_id: [u8; 20],
+    port: u16,
+    left: u64,
+    event: Option<String>,
+    numwant: usize,
+    compact: bool,
+    no_peer_id: bool,
+    ip: Option<IpAddr>,
+    ipv6: Option<Ipv6Addr>,
+}
+
+impl Announce {
+    fn parse(query: &str) -> Result<Self, String> {
+        let parameters = parse_query(query);
+        // A repeated parameter overrides the earlier ones.
+        let get = |name: &str| {
+            parameters
+                .iter()
+                .rev()
+                .find(|(key, _)| key == name)
+                .map(|(_, value)| value.as_slice())
+        };
+        let get_str = |name: &str| get(name).and_then(|value| std::str::from_utf8(value).ok());
+        let hash = |name: &str| {
+            get(name)
+                .ok_or_else(|| format!("missing {}", name))?
+                .try_into()
+                .map_err(|_| format!("invalid {}", name))
+        };
+        fn number<T: FromStr>(name: &str, value: Option<&str>) -> Result<Option<T>, String> {
+            value
+                .map(|value| value.parse().map_err(|_| format!("invalid {}", name)))
+                .transpose()
+        }
+
+        Ok(Self {
+            info_hash: hash("info_hash")?,
+            peer_id: hash("peer_id")?,
+            port: number("port", get_str("port"))?.ok_or("missing port")?,
+            left: number("left", get_str("left"))?.unwrap_or(0),
+            event: get_str("event")
+                .filter(|event| !event.is_empty())
+                .map(str::to_string),
+            numwant: number("numwant", get_str("numwant"))?
+                .unwrap_or(DEFAULT_NUMWANT)
+                .min(MAX_NUMWANT),
+            compact: get_str("compact") != Some("0"),
+            no_peer_id: get_str("no_peer_id") == Some("1"),
+            ip: get_str("ip").and_then(|ip| ip.parse().ok()),
+            ipv6: get_str("ipv6").and_then(|ip| ip.parse().ok()),
+        })
+    }
+}
+
+/// In-memory swarms of all torrents a tracker serves.
+#[derive(Debug)]
+pub struct Swarms {
+    interval: Duration,
+    peer_timeout: Duration,
+    allowed: Option<HashSet<[u8; 20]>>,
+    trust_client_ip: bool,
+    torrents: HashMap<[u8; 20], Swarm>,
+}
+
+impl Default for Swarms {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Swarms {
+    pub fn new() -> Self {
+        Self {
+            interval: DEFAULT_INTERVAL,
+            peer_timeout: DEFAULT_PEER_TIMEOUT,
+            allowed: None,
+            trust_client_ip: false,
+            torrents: HashMap::new(),
+        }
+    }
+
+    pub fn interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    pub fn peer_timeout(mut self, peer_timeout: Duration) -> Self {
+        self.peer_timeout = peer_timeout;
+        self
+    }
+
+    /// Serves only the listed info hashes, announces of others fail.
+    pub fn allow(mut self, info_hashes: impl IntoIterator<Item = [u8; 20]>) -> Self {
+        self.allowed
+            .get_or_insert_with(HashSet::new)
+            .extend(info_hashes);
+        self
+    }
+
+    /// Registers the address from `ip=` instead of the announcing one, and `ipv6=` from
+    /// clients announcing over IPv4. Any client can then add third-party addresses, so
+    /// only enable it behind a trusted proxy.
+    pub fn trust_client_ip(mut self, trust_client_ip: bool) -> Self {
+        self.trust_client_ip = trust_client_ip;
+        self
+    }
+
+    pub fn is_allowed(&self, info_hash: &[u8; 20]) -> bool {
+        self.allowed
+            .as_ref()
+            .is_none_or(|allowed| allowed.contains(info_hash))
+    }
+
+    /// Bencoded reply to the query string of an announce from `remote`.
+    pub fn announce(&mut self, query: &str, remote: IpAddr, now: Instant) -> Vec<u8> {
+        let announce = match Announce::parse(query) {
+            Ok(announce) => announce,
+            Err(reason) => return failure(reason),
+        };
+        if !self.is_allowed(&announce.info_hash) {
+            return failure("torrent not allowed".to_string());
+        }
+        self.expire(now);
+
+        let ip = match announce.ip {
+            Some(ip) if self.trust_client_ip => ip,
+            _ => remote,
+        };
+        let key = (announce.peer_id, remote);
+        let swarm = self.torrents.entry(announce.info_hash).or_default();
+        if announce.event.as_deref() == Some("stopped") {
+            swarm.peers.remove(&key);
+        } else {
+            if announce.event.as_deref() == Some("completed") {
+                swarm.downloaded += 1;
+            }
+            let mut addresses = vec![SocketAddr::new(ip, announce.port)];
+            // An IPv6 client already proves it has IPv6, others must be trusted.
+            let ipv6 = announce
+                .ipv6
+                .filter(|_| self.trust_client_ip || remote.is_ipv6())
+                .map(|ipv6| SocketAddr::new(ipv6.into(), announce.port));
+            if let Some(ipv6) = ipv6.filter(|ipv6| !addresses.contains(ipv6)) {
+                addresses.push(ipv6);
+            }
+            swarm.peers.insert(
+                key,
+                SwarmPeer {
+                    addresses,
+                    left: announce.left,
+                    last_seen: now,
+                },
+            );
+        }
+
+        let others = swarm
+            .peers
+            .iter()
+            .filter(|(peer_key, _)| **peer_key != key)
+            .take(announce.numwant);
+        let (peers, peers6) = if announce.compact {
+            let mut peers = Vec::new();
+            let mut peers6 = Vec::new();
+            for address in others.flat_map(|(_, peer)| &peer.addresses) {
+                let (list, octets) = match address.ip() {
+                    IpAddr::V4(ip) => (&mut peers, ip.octets().to_vec()),
+                    IpAddr::V6(ip) => (&mut peers6, ip.octets().to_vec()),
+                };
+                list.extend_from_slice(&octets);
+                list.extend_from_slice(&address.port().to_be_bytes());
+            }
+            let peers6 = (!peers6.is_empty()).then(|| ByteBuf::from(peers6));
+            (PeerList::Compact(ByteBuf::from(peers)), peers6)
+        } else {
+            let peers = others
+                .flat_map(|((peer_id, _), peer)| {
+                    peer.addresses.iter().map(move |address| DictionaryPeer {
+                        ip: Some(address.ip().to_string()),
+                        port: Some(address.port() as i64),
+                        peer_id: (!announce.no_peer_id).then(|| ByteBuf::from(peer_id.to_vec())),
+                    })
+                })
+                .collect();
+            (PeerList::Dictionary(peers), None)
+        };
+
+        let stats = swarm.stats();
+        let response = RawTrackerResponse {
+            interval: Some(self.interval.as_secs()),
+            min_interval: Some(self.interval.as_secs() / 2),
+            peers: Some(peers),
+            peers6,
+            complete: Some(stats.complete),
+            incomplete: Some(stats.incomplete),
+            external_ip: Some(ByteBuf::from(match remote {
+                IpAddr::V4(ip) => ip.octets().to_vec(),
+                IpAddr::V6(ip) => ip.octets().to_vec(),
+            })),
+            ..Default::default()
+        };
+        to_bencode(&response).expect("tracker response serializes")
+    }
+
+    /// Bencoded reply to the query string of a scrape. Without any `info_hash`, every
+    /// served torrent is listed.
+    pub fn scrape(&mut self, query: &str, now: Instant) -> Vec<u8> {
+        self.expire(now);
+        let requested: Vec<[u8; 20]> = parse_query(query)
+            .into_iter()
+            .filter(|(key, _)| key == "info_hash")
+            .filter_map(|(_, value)| value.try_into().ok())
+            .collect();
+
+        let mut files = BTreeMap::new();
+        if requested.is_empty() {
+            for (info_hash, swarm) in &self.torrents {
+                files.insert(ByteBuf::from(info_hash.to_vec()), swarm.stats());
+            }
+        } else {
+            for info_hash in requested.iter().filter(|hash| self.is_allowed(hash)) {
+                let stats = self
+                    .torrents
+                    .get(info_hash)
+                    .map(Swarm::stats)
+                    .unwrap_or_default();
+                files.insert(ByteBuf::from(info_hash.to_vec()), stats);
+            }
+        }
+        to_bencode(&ScrapeReply { files }).expect("scrape response serializes")
+    }
+
+    /// Drops peers that stopped announcing and the swarms left empty.
+    fn expire(&mut self, now: Instant) {
+        let timeout = self.peer_timeout;
+        for swarm in self.torrents.values_mut() {
+            swarm
+                .peers
+                .retain(|_, peer| now.saturating_duration_since(peer.last_seen) < timeout);
+        }
+        self.torrents
+            .retain(|_, swarm| !swarm.peers.is_empty() || swarm.downloaded > 0);
+    }
+}
+
+#[derive(serde::Serialize)]
+struct ScrapeReply {
+    files: BTreeMap<ByteBuf, ScrapeStats>,
+}
+
+fn failure(reason: String) -> Vec<u8> {
+    let response = RawTrackerResponse {
+        failure_reason: Some(reason),
+        ..Default::default()
+    };
+    to_bencode(&response).expect("tracker response serializes")
+}
+
+/// Decoded query parameters in order. Values stay bytes, `info_hash` is binary.
+fn parse_query(query: &str) -> Vec<(String, Vec<u8>)> {
+    query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            (
+                percent_decode(key.as_bytes())
+                    .decode_utf8_lossy()
+                    .to_string(),
+                percent_decode(value.as_bytes()).collect(),
+            )
+        })
+        .collect()
+}
+
+/// An HTTP tracker on a std [`TcpListener`], with a thread per connection.
+pub struct TrackerServer {
+    listener: TcpListener,
+    swarms: Swarms,
+}
+
+impl TrackerServer {
+    pub fn bind(address: impl ToSocketAddrs) -> io::Result<Self> {
+        Ok(Self {
+            listener: TcpListener::bind(address)?,
+            swarms: Swarms::new(),
+        })
+    }
+
+    /// Replaces the default swarm settings.
+    pub fn swarms(mut self, swarms: Swarms) -> Self {
+        self.swarms = swarms;
+        self
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Serves on its own thread until [`TrackerServerHandle::stop`].
+    pub fn spawn(mut self) -> io::Result<TrackerServerHandle> {
+        let address = self.local_addr()?;
+        let stopped = Arc::new(AtomicBool::new(false));
+        let thread = {
+            let stopped = Arc::clone(&stopped);
+            let swarms = Arc::new(Mutex::new(std::mem::take(&mut self.swarms)));
+            std::thread::spawn(move || {
+                for stream in self.listener.incoming() {
+                    if stopped.load(Ordering::SeqCst) {
+                        break;
+                    }
+                    if let Ok(stream) = stream {
+                        let swarms = Arc::clone(&swarms);
+                        // A slow or broken connection only affects its own client.
+                        std::thread::spawn(move || handle_connection(stream, &swarms));
+                    }
+                }
+            })
+        };
+        Ok(TrackerServerHandle {
+            address,
+            stopped,
+            thread: Some(thread),
+        })
+    }
+}
+
+fn handle_connection(mut stream: TcpStream, swarms: &Mutex<Swarms>) -> io::Result<()> {
+    stream.set_write_timeout(Some(REQUEST_TIMEOUT))?;
+    let remote = stream.peer_addr()?.ip();
+
+    // The timeout is for the whole request, not for each read of it.
+    let deadline = Instant::now() + REQUEST_TIMEOUT;
+    let mut request = Vec::new();
+    let mut buffer = [0; 1024];
+    while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Ok(());
+        }
+        stream.set_read_timeout(Some(remaining))?;
+        let read = stream.read(&mut buffer)?;
+        if read == 0 || request.len() + read > MAX_REQUEST_BYTES {
+            return Ok(());
+        }
+        request.extend_from_slice(&buffer[..read]);
+    }
+
+    let request = String::from_utf8_lossy(&request);
+    let target = request
+        .lines()
+        .next()
+        .and_then(|line| line.strip_prefix("GET "))
+        .and_then(|rest| rest.split(' ').next())
+        .unwrap_or_default();
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    let now = Instant::now();
+    let mut swarms = swarms.lock().expect("swarms lock poisoned");
+    let (status, body) = match path {
+        "/announce" => ("200 OK", swarms.announce(query, canonical(remote), now)),
+        "/scrape" => ("200 OK", swarms.scrape(query, now)),
+        _ => ("404 Not Found", b"not found".to_vec()),
+    };
+    drop(swarms);
+
+    let mut response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        status,
+        body.len()
+    )
+    .into_bytes();
+    response.extend_from_slice(&body);
+    stream.write_all(&response)
+}
+
+/// IPv4 clients of a dual-stack socket show up as `::ffff:a.b.c.d`.
+fn canonical(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(ip) => ip.to_ipv4_mapped().map_or(IpAddr::V6(ip), IpAddr::V4),
+        ip => ip,
+    }
+}
+
+/// Controls a tracker running on its own thread.
+pub struct TrackerServerHandle {
+    address: SocketAddr,
+    stopped: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl TrackerServerHandle {
+    pub fn address(&self) -> SocketAddr {
+        self.address
+    }
+
+    /// Announce URL of the tracker, using loopback when bound to every interface.
+    pub fn announce_url(&self) -> String {
+        format!("http://{}/announce", self.reachable_address())
+    }
+
+    pub fn stop(mut self) {
+        self.shutdown();
+    }
+
+    fn reachable_address(&self) -> SocketAddr {
+        let mut address = self.address;
+        if address.ip().is_unspecified() {
+            address.set_ip(match address {
+                SocketAddr::V4(_) => Ipv4Addr::LOCALHOST.into(),
+                SocketAddr::V6(_) => Ipv6Addr::LOCALHOST.into(),
+            });
+        }
+        address
+    }
+
+    fn shutdown(&mut self) {
+        self.stopped.store(true, Ordering::SeqCst);
+        // Wakes the blocking accept.
+        let _ = TcpStream::connect(self.reachable_address());
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Drop for TrackerServerHandle {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::torrent::meta::InfoHash;
+    use crate::torrent::tracker::{
+        AnnounceEvent, AnnounceRequest, TorrentTrackerClient, TrackerError, TrackerResponse,
+    };
+
+    /// Query of a seeder, `extra` parameters are appended.
+    fn announce_query(info_hash: [u8; 20], peer: u8, extra: &str) -> String {
+        let request = AnnounceRequest::new(InfoHash::V1(info_hash), 0)
+            .peer_id([peer; 20])
+            .port(6880 + peer as u16);
+        format!("{}{}", request.query(), extra)
+    }
+
+    #[test]
+    fn swarms_track_peers_and_expire_them() {
+        let start = Instant::now();
+        let mut swarms = Swarms::new().peer_timeout(Duration::from_secs(60));
+        let local = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+        let remote = IpAddr::V6(Ipv6Addr::LOCALHOST);
+        swarms.announce(&announce_query([0xAA; 20], 1, ""), local, start);
+        swarms.announce(&announce_query([0xAA; 20], 2, "&left=5"), remote, start);
+
+        let reply = swarms.announce(&announce_query([0xAA; 20], 3, ""), local, start);
+        let response = TrackerResponse::from_bytes(&reply).unwrap();
+        let mut peers = response.peers.clone();
+        peers.sort();
+        assert_eq!(
+            peers,
+            vec![SocketAddr::new(local, 6881), SocketAddr::new(remote, 6882),]
+        );
+        assert_eq!(response.complete, Some(2));
+        assert_eq!(response.incomplete, Some(1));
+        assert_eq!(response.interval, DEFAULT_INTERVAL);
+
+        // Dictionary replies carry peer IDs unless `no_peer_id` is set.
+        let reply = swarms.announce(&announce_query([0xAA; 20], 3, "&compact=0"), local, start);
+        let response = TrackerResponse::from_bytes(&reply).unwrap();
+        assert_eq!(response.peer_ids[&SocketAddr::new(local, 6881)], [1; 20]);
+        let reply = swarms.announce(
+            &announce_query([0xAA; 20], 3, "&compact=0&no_peer_id=1"),
+            local,
+            start,
+        );
+        let response = TrackerResponse::from_bytes(&reply).unwrap();
+        assert_eq!(response.peers.len(), 2);
+        assert!(response.peer_ids.is_empty());
+
+        // Peer 1 stops, the others time out.
+        swarms.announce(
+            &announce_query([0xAA; 20], 1, "&event=stopped"),
+            local,
+            start,
+        );
+        let later = start + Duration::from_secs(61);
+        let reply = swarms.announce(&announce_query([0xAA; 20], 4, ""), local, later);
+        let response = TrackerResponse::from_bytes(&reply).unwrap();
+        assert!(response.peers.is_empty());
+        assert_eq!(response.complete, Some(1));
+    }
+
+    #[test]
+    fn client_addresses_are_not_trusted() {
+        let now = Instant::now();
+        let local = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+        let other = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2));
+        let peers_seen_by = |swarms: &mut Swarms| {
+            let reply = swarms.announce(&announce_query([0xAA; 20], 9, ""), other, now);
+            TrackerResponse::from_bytes(&reply).unwrap().peers
+        };
+
+        let mut swarms = Swarms::new();
+        swarms.announce(
+            &announce_query([0xAA; 20], 1, "&ip=192.0.2.1&ipv6=2001%3Adb8%3A%3A1"),
+            local,
+            now,
+        );
+        assert_eq!(
+            peers_seen_by(&mut swarms),
+            vec![SocketAddr::new(local, 6881)]
+        );
+
+        // Another host knowing the peer ID can neither evict nor replace the peer.
+        swarms.announce(&announce_query([0xAA; 20], 1, "&event=stopped"), other, now);
+        assert_eq!(
+            peers_seen_by(&mut swarms),
+            vec![SocketAddr::new(local, 6881)]
+        );
+        swarms.announce(&announce_query([0xAA; 20], 1, "&event=stopped"), local, now);
+        assert!(peers_seen_by(&mut swarms).is_empty());
+
+        // A client announcing over IPv6 may add its IPv6 address.
+        let local6: IpAddr = "2001:db8::2".parse().unwrap();
+        swarms.announce(
+            &announce_query([0xAA; 20], 2, "&ipv6=2001%3Adb8%3A%3A1"),
+            local6,
+            now,
+        );
+        let mut peers = peers_seen_by(&mut swarms);
+        peers.sort();
+        assert_eq!(
+            peers,
+            vec![
+                SocketAddr::new("2001:db8::1".parse().unwrap(), 6882),
+                SocketAddr::new(local6, 6882),
+            ]
+        );
+
+        let mut swarms = Swarms::new().trust_client_ip(true);
+        swarms.announce(
+            &announce_query([0xAA; 20], 1, "&ip=192.0.2.1&ipv6=2001%3Adb8%3A%3A1"),
+            local,
+            now,
+        );
+        assert_eq!(
+            peers_seen_by(&mut swarms),
+            vec![
+                SocketAddr::new(Ipv4Addr::new(192, 0, 2, 1).into(), 6881),
+                SocketAddr::new("2001:db8::1".parse().unwrap(), 6881),
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_and_unlisted_announces() {
+        let now = Instant::now();
+        let local = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        let mut swarms = Swarms::new().allow([[0xAA; 20]]);
+        let failure_of = |reply: Vec<u8>| match TrackerResponse::from_bytes(&reply) {
+            Err(TrackerError::Failure(reason)) => reason,
+            other => panic!("expected a failure, got {:?}", other),
+        };
+
+        assert_eq!(
+            failure_of(swarms.announce(&announce_query([0xBB; 20], 1, ""), local, now)),
+            "torrent not allowed"
+        );
+        assert_eq!(
+            failure_of(swarms.announce("info_hash=abc", local, now)),
+            "invalid info_hash"
+        );
+        assert_eq!(
+            failure_of(swarms.announce(&format!("info_hash={}", "%AA".repeat(20)), local, now)),
+            "missing peer_id"
+        );
+        assert!(TrackerResponse::from_bytes(&swarms.announce(
+            &announce_query([0xAA; 20], 1, ""),
+            local,
+            now
+        ))
+        .is_ok());
+    }
+
+    #[test]
+    fn serves_announce_and_scrape_over_http() {
+        let info_hash = InfoHash::V1([0xAA; 20]);
+        let server = TrackerServer::bind("127.0.0.1:0")
+            .unwrap()
+            // Lets the IPv4 client below register an IPv6 address.
+            .swarms(
+                Swarms::new()
+                    .allow([info_hash.truncated()])
+                    .trust_client_ip(true),
+            )
+            .spawn()
+            .unwrap();
+        let url = server.announce_url();
+        let client = TorrentTrackerClient::new();
+
+        let seeder = AnnounceRequest::new(info_hash, 0)
+            .peer_id([1; 20])
+            .port(7001)
+            .event(AnnounceEvent::Started);
+        assert!(client.announce(&url, &seeder).unwrap().peers.is_empty());
+        let leecher = AnnounceRequest::new(info_hash, 100)
+            .peer_id([2; 20])
+            .port(7002)
+            .ipv6(Ipv6Addr::LOCALHOST);
+        let response = client.announce(&url, &leecher).unwrap();
+        assert_eq!(
+            response.peers,
+            vec![SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 7001)]
+        );
+
+        // The leecher's IPv6 address is handed out in `peers6`.
+        let response = client.announce(&url, &seeder).unwrap();
+        let mut peers = response.peers;
+        peers.sort();
+        assert_eq!(
+            peers,
+            vec![
+                SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 7002),
+                SocketAddr::new(Ipv6Addr::LOCALHOST.into(), 7002),
+            ]
+        );
+
+        let unknown = InfoHash::V1([0xBB; 20]);
+        let stats = client.scrape(&url, &[info_hash, unknown]).unwrap();
+        assert_eq!(
+            stats,
+            HashMap::from([(
+                info_hash,
+                ScrapeStats {
+                    complete: 1,
+                    incomplete: 1,
+                    downloaded: 0
+                }
+            )])
+        );
+        assert!(matches!(
+            client.announce(&url, &AnnounceRequest::new(unknown, 0)),
+            Err(TrackerError::Failure(_))
+        ));
+        server.stop();
+    }
+
+    #[test]
+    fn slow_client_does_not_block_others() {
+        let server = TrackerServer::bind("127.0.0.1:0").unwrap().spawn().unwrap();
+        let mut slow = TcpStream::connect(server.address()).unwrap();
+        slow.write_all(b"G").unwrap();
+
+        let started = Instant::now();
+        let request = AnnounceRequest::new(InfoHash::V1([0xAA; 20]), 0);
+        let client = TorrentTrackerClient::new();
+        assert!(client.announce(&server.announce_url(), &request).is_ok());
+        assert!(started.elapsed() < REQUEST_TIMEOUT);
+    }
+}
@@ -0,0 +1,617 @@
+//! BEP 15: UDP tracker protocol.
+
+use crate::torrent::proxy::{ProxyConfig, Socks5UdpRelay};
+use crate::torrent::random::random_u32;
+use crate::torrent::tracker::scrape::ScrapeStats;
+use crate::torrent::tracker::{
+    parse_compact_peers, parse_compact_peers6, AnnounceEvent, AnnounceRequest, TrackerError,
+    TrackerResponse,
+};
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use std::collections::HashMap;
+use std::io::Cursor;
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs, UdpSocket};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Magic constant of the connect request.
+pub const PROTOCOL_ID: u64 = 0x0417_2710_1980;
+/// Timeout of the first attempt, doubled on every retransmission.
+pub const BASE_TIMEOUT: Duration = Duration::from_secs(15);
+pub const MAX_RETRANSMISSIONS: u32 = 8;
+/// A connection ID may be used for one minute after it was received.
+pub const CONNECTION_ID_LIFETIME: Duration = Duration::from_secs(60);
+/// Info hashes per scrape request, more do not fit into a reply of a typical MTU.
+pub const MAX_SCRAPE_HASHES: usize = 74;
+
+/// How often a waiting exchange checks its [`Interrupt`].
+const INTERRUPT_POLL: Duration = Duration::from_millis(50);
+
+const ACTION_CONNECT: u32 = 0;
+const ACTION_ANNOUNCE: u32 = 1;
+const ACTION_SCRAPE: u32 = 2;
+const ACTION_ERROR: u32 = 3;
+
+/// A plain socket to the resolved tracker, or a SOCKS5 relay when trackers go through
+/// a proxy. The relay gets the host as named, so the proxy resolves it.
+#[derive(Debug)]
+enum UdpTransport {
+    Direct {
+        socket: UdpSocket,
+        tracker: SocketAddr,
+    },
+    Socks5 {
+        relay: Socks5UdpRelay,
+        host: String,
+        port: u16,
+    },
+}
+
+impl UdpTransport {
+    fn send(&self, data: &[u8]) -> Result<(), TrackerError> {
+        match self {
+            UdpTransport::Direct { socket, tracker } => {
+                socket.send_to(data, tracker)?;
+            }
+            UdpTransport::Socks5 { relay, host, port } => {
+                relay.send_to(data, host, *port)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// A datagram and the address it came from, `None` if it is not from the tracker.
+    /// The relay only reports where a reply came from, which the transaction ID has
+    /// to vouch for.
+    fn recv(&self, buffer: &mut [u8]) -> std::io::Result<Option<(usize, SocketAddr)>> {
+        match self {
+            UdpTransport::Direct { socket, tracker } => {
+                let (length, from) = socket.recv_from(buffer)?;
+                Ok((from == *tracker).then_some((length, from)))
+            }
+            UdpTransport::Socks5 { relay, .. } => relay.recv_from(buffer).map(Some),
+        }
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> std::io::Result<()> {
+        match self {
+            UdpTransport::Direct { socket, .. } => socket.set_read_timeout(timeout),
+            UdpTransport::Socks5 { relay, .. } => relay.set_read_timeout(timeout),
+        }
+    }
+}
+
+/// Stops requests of a [`UdpTrackerClient`] at a deadline, or from another thread.
+/// Clones share the cancellation.
+#[derive(Debug, Clone, Default)]
+pub struct Interrupt {
+    deadline: Option<Instant>,
+    cancelled: Arc<AtomicBool>,
+}
+
+impl Interrupt {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn deadline(mut self, deadline: Instant) -> Self {
+        self.deadline = Some(deadline);
+        self
+    }
+
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    /// [`TrackerError::Cancelled`] or, past the deadline, [`TrackerError::Timeout`].
+    pub fn check(&self) -> Result<(), TrackerError> {
+        if self.cancelled.load(Ordering::SeqCst) {
+            return Err(TrackerError::Cancelled);
+        }
+        match self.deadline {
+            Some(deadline) if Instant::now() >= deadline => Err(TrackerError::Timeout),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Client of one UDP tracker, keeping its connection ID between requests.
+#[derive(Debug)]
+pub struct UdpTrackerClient {
+    socket: UdpTransport,
+    /// Whether the tracker was last reached over IPv6, so it answers with IPv6 peers.
+    ipv6: bool,
+    connection: Option<(u64, Instant)>,
+    base_timeout: Duration,
+    max_retransmissions: u32,
+    connection_lifetime: Duration,
+    interrupt: Option<Interrupt>,
+}
+
+impl UdpTrackerClient {
+    /// Resolves the host of a `udp://host:port/...` URL.
+    pub fn connect(url: &str) -> Result<Self, TrackerError> {
+        Self::connect_via(url, None)
+    }
+
+    /// Like [`Self::connect`], sending through a SOCKS5 UDP relay when `proxy` is set.
+    /// The proxy then resolves the host, no DNS query leaves this machine.
+    pub fn connect_via(url: &str, proxy: Option<&ProxyConfig>) -> Result<Self, TrackerError> {
+        let invalid = || TrackerError::InvalidUrl(url.to_string());
+        let address = url
+            .strip_prefix("udp://")
+            .and_then(|rest| rest.split(['/', '?', '#']).next())
+            .filter(|address| !address.is_empty())
+            .ok_or_else(invalid)?;
+        match proxy {
+            Some(proxy) => {
+                let (host, port) = address.rsplit_once(':').ok_or_else(invalid)?;
+                let port = port.parse().map_err(|_| invalid())?;
+                let host = host.trim_start_matches('[').trim_end_matches(']');
+                let ipv6 = matches!(host.parse(), Ok(IpAddr::V6(_)));
+                let relay = proxy.udp_associate()?;
+                Ok(Self::with_transport(
+                    UdpTransport::Socks5 {
+                        relay,
+                        host: host.to_string(),
+                        port,
+                    },
+                    ipv6,
+                ))
+            }
+            None => {
+                let tracker = address.to_socket_addrs()?.next().ok_or_else(invalid)?;
+                Ok(Self::new(tracker)?)
+            }
+        }
+    }
+
+    pub fn new(tracker: SocketAddr) -> std::io::Result<Self> {
+        let bind: SocketAddr = match tracker {
+            SocketAddr::V4(_) => "0.0.0.0:0".parse().expect("valid address"),
+            SocketAddr::V6(_) => "[::]:0".parse().expect("valid address"),
+        };
+        Ok(Self::with_transport(
+            UdpTransport::Direct {
+                socket: UdpSocket::bind(bind)?,
+                tracker,
+            },
+            tracker.is_ipv6(),
+        ))
+    }
+
+    fn with_transport(socket: UdpTransport, ipv6: bool) -> Self {
+        Self {
+            socket,
+            ipv6,
+            connection: None,
+            base_timeout: BASE_TIMEOUT,
+            max_retransmissions: MAX_RETRANSMISSIONS,
+            connection_lifetime: CONNECTION_ID_LIFETIME,
+            interrupt: None,
+        }
+    }
+
+    pub fn base_timeout(mut self, base_timeout: Duration) -> Self {
+        self.base_timeout = base_timeout;
+        self
+    }
+
+    pub fn max_retransmissions(mut self, max_retransmissions: u32) -> Self {
+        self.max_retransmissions = max_retransmissions;
+        self
+    }
+
+    pub fn connection_lifetime(mut self, connection_lifetime: Duration) -> Self {
+        self.connection_lifetime = connection_lifetime;
+        self
+    }
+
+    /// Applies to the following requests until replaced.
+    pub fn set_interrupt(&mut self, interrupt: Option<Interrupt>) {
+        self.interrupt = interrupt;
+    }
+
+    pub fn announce(&mut self, request: &AnnounceRequest) -> Result<TrackerResponse, TrackerError> {
+        let response = self.transact(ACTION_ANNOUNCE, |packet| {
+            packet.extend_from_slice(&request.info_hash.truncated());
+            packet.extend_from_slice(&request.peer_id);
+            packet.write_u64::<BigEndian>(request.downloaded)?;
+            packet.write_u64::<BigEndian>(request.left)?;
+            packet.write_u64::<BigEndian>(request.uploaded)?;
+            packet.write_u32::<BigEndian>(event_id(request.event))?;
+            // Only an IPv4 address fits, trackers take the IPv6 one from the packet.
+            let ip = match request.ip {
+                Some(IpAddr::V4(ip)) => u32::from(ip),
+                _ => 0,
+            };
+            packet.write_u32::<BigEndian>(ip)?;
+            packet.write_u32::<BigEndian>(request.key.unwrap_or(0))?;
+            let numwant = request.numwant.map(|n| n as i32).unwrap_or(-1);
+            packet.write_i32::<BigEndian>(numwant)?;
+            packet.write_u16::<BigEndian>(request.port)
+        })?;
+
+        let mut reader = Cursor::new(&response[..]);
+        let interval = reader.read_u32::<BigEndian>().map_err(|_| truncated())?;
+        let leechers = reader.read_u32::<BigEndian>().map_err(|_| truncated())?;
+        let seeders = reader.read_u32::<BigEndian>().map_err(|_| truncated())?;
+        // Trackers reached over IPv6 answer with IPv6 peers.
+        let peers = if self.ipv6 {
+            parse_compact_peers6(&response[12..])?
+        } else {
+            parse_compact_peers(&response[12..])?
+        };
+        Ok(TrackerResponse {
+            interval: Duration::from_secs(interval as u64),
+            min_interval: None,
+            peers,
+            peer_ids: HashMap::new(),
+            peer_hosts: Vec::new(),
+            malformed_peers: Vec::new(),
+            complete: Some(seeders as u64),
+            incomplete: Some(leechers as u64),
+            warning_message: None,
+            tracker_id: None,
+            external_ip: None,
+        })
+    }
+
+    /// Statistics for each of `info_hashes`, in the same order. Long lists are split
+    /// into several requests.
+    pub fn scrape(&mut self, info_hashes: &[[u8; 20]]) -> Result<Vec<ScrapeStats>, TrackerError> {
+        let mut stats = Vec::with_capacity(info_hashes.len());
+        for chunk in info_hashes.chunks(MAX_SCRAPE_HASHES) {
+            stats.extend(self.scrape_chunk(chunk)?);
+        }
+        Ok(stats)
+    }
+
+    fn scrape_chunk(&mut self, info_hashes: &[[u8; 20]]) -> Result<Vec<ScrapeStats>, TrackerError> {
+        let response = self.transact(ACTION_SCRAPE, |packet| {
+            for info_hash in info_hashes {
+                packet.extend_from_slice(info_hash);
+            }
+            Ok(())
+        })?;
+        if response.len() < info_hashes.len() * 12 {
+            return Err(truncated());
+        }
+
+        let mut reader = Cursor::new(&response[..]);
+        let mut stats = Vec::with_capacity(info_hashes.len());
+        for _ in info_hashes {
+            let seeders = reader.read_u32::<BigEndian>()?;
+            let completed = reader.read_u32::<BigEndian>()?;
+            let leechers = reader.read_u32::<BigEndian>()?;
+            stats.push(ScrapeStats {
+                complete: seeders as u64,
+                incomplete: leechers as u64,
+                downloaded: completed as u64,
+            });
+        }
+        Ok(stats)
+    }
+
+    /// Sends a request with a valid connection ID and returns the payload after
+    /// action and transaction ID.
+    fn transact(
+        &mut self,
+        action: u32,
+        write_body: impl Fn(&mut Vec<u8>) -> std::io::Result<()>,
+    ) -> Result<Vec<u8>, TrackerError> {
+        for attempt in 0..=self.max_retransmissions {
+            let connection_id = match self.connection {
+                Some((id, received)) if received.elapsed() < self.connection_lifetime => id,
+                _ => {
+                    let id = self.request_connection_id(attempt)?;
+                    self.connection = Some((id, Instant::now()));
+                    id
+                }
+            };
+
+            let transaction_id = random_u32();
+            let mut packet = Vec::new();
+            packet.write_u64::<BigEndian>(connection_id)?;
+            packet.write_u32::<BigEndian>(action)?;
+            packet.write_u32::<BigEndian>(transaction_id)?;
+            write_body(&mut packet)?;
+
+            if let Some(payload) = self.exchange(&packet, action, transaction_id, attempt)? {
+                return Ok(payload);
+            }
+        }
+        Err(TrackerError::Timeout)
+    }
+
+    fn request_connection_id(&mut self, attempt: u32) -> Result<u64, TrackerError> {
+        for attempt in attempt..=self.max_retransmissions {
+            let transaction_id = random_u32();
+            let mut packet = Vec::new();
+            packet.write_u64::<BigEndian>(PROTOCOL_ID)?;
+            packet.write_u32::<BigEndian>(ACTION_CONNECT)?;
+            packet.write_u32::<BigEndian>(transaction_id)?;
+
+            if let Some(payload) =
+                self.exchange(&packet, ACTION_CONNECT, transaction_id, attempt)?
+            {
+                return Cursor::new(payload)
+                    .read_u64::<BigEndian>()
+                    .map_err(|_| truncated());
+            }
+        }
+        Err(TrackerError::Timeout)
+    }
+
+    /// One send and wait of `15 * 2^attempt` seconds, `None` on timeout.
+    fn exchange(
+        &mut self,
+        packet: &[u8],
+        action: u32,
+        transaction_id: u32,
+        attempt: u32,
+    ) -> Result<Option<Vec<u8>>, TrackerError> {
+        self.socket.send(packet)?;
+        let deadline = Instant::now() + self.base_timeout * 2u32.saturating_pow(attempt);
+
+        let mut buffer = [0; 65536];
+        loop {
+            if let Some(interrupt) = &self.interrupt {
+                interrupt.check()?;
+            }
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return Ok(None);
+            }
+            let wait = match self.interrupt {
+                Some(_) => remaining.min(INTERRUPT_POLL),
+                None => remaining,
+            };
+            self.socket.set_read_timeout(Some(wait))?;
+            let (length, from) = match self.socket.recv(&mut buffer) {
+                Ok(Some(received)) => received,
+                Ok(None) => continue,
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut
+                    ) =>
+                {
+                    continue
+                }
+                Err(e) => return Err(e.into()),
+            };
+            if length < 8 {
+                continue;
+            }
+
+            let mut reader = Cursor::new(&buffer[..length]);
+            let received_action = reader.read_u32::<BigEndian>()?;
+            let received_transaction = reader.read_u32::<BigEndian>()?;
+            // Late replies to earlier attempts are dropped.
+            if received_transaction != transaction_id {
+                continue;
+            }
+            let payload = buffer[8..length].to_vec();
+            self.ipv6 = from.is_ipv6();
+            return match received_action {
+                ACTION_ERROR => {
+                    // Often a stale connection ID, the next request asks for a new one.
+                    self.connection = None;
+                    Err(TrackerError::Failure(
+                        String::from_utf8_lossy(&payload).to_string(),
+                    ))
+                }
+                received if received == action => Ok(Some(payload)),
+                received => Err(TrackerError::UnexpectedResponse(format!(
+                    "action {} instead of {}",
+                    received, action
+                ))),
+            };
+        }
+    }
+}
+
+fn event_id(event: Option<AnnounceEvent>) -> u32 {
+    match event {
+        None => 0,
+        Some(AnnounceEvent::Completed) => 1,
+        Some(AnnounceEvent::Started) => 2,
+        Some(AnnounceEvent::Stopped) => 3,
+    }
+}
+
+fn truncated() -> TrackerError {
+    TrackerError::UnexpectedResponse("truncated UDP tracker response".to_string())
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::torrent::meta::InfoHash;
+    use std::net::{Ipv4Addr, Ipv6Addr};
+    use std::thread::JoinHandle;
+
+    /// Stand-in tracker answering `requests` packets. The first announce is dropped
+    /// to force a retransmission. Returns the actions it received.
+    pub(crate) fn udp_tracker(requests: usize) -> (SocketAddr, JoinHandle<Vec<u32>>) {
+        udp_tracker_on("127.0.0.1:0", requests)
+    }
+
+    /// Like [`udp_tracker`], answering IPv6 clients with the IPv6 peer `[::1]:6881`.
+    pub(crate) fn udp_tracker_on(
+        bind: &str,
+        requests: usize,
+    ) -> (SocketAddr, JoinHandle<Vec<u32>>) {
+        let socket = UdpSocket::bind(bind).unwrap();
+        let address = socket.local_addr().unwrap();
+        let handle = std::thread::spawn(move || {
+            let mut actions = Vec::new();
+            let mut dropped_announce = false;
+            let mut buffer = [0; 2048];
+            for _ in 0..requests {
+                let (length, from) = socket.recv_from(&mut buffer).unwrap();
+                let mut reader = Cursor::new(&buffer[..length]);
+                let connection_id = reader.read_u64::<BigEndian>().unwrap();
+                let action = reader.read_u32::<BigEndian>().unwrap();
+                let transaction_id = reader.read_u32::<BigEndian>().unwrap();
+                actions.push(action);
+
+                let mut reply = Vec::new();
+                if action != ACTION_CONNECT && connection_id != 0xC0FFEE {
+                    reply.write_u32::<BigEndian>(ACTION_ERROR).unwrap();
+                    reply.write_u32::<BigEndian>(transaction_id).unwrap();
+                    reply.extend_from_slice(b"bad connection id");
+                    socket.send_to(&reply, from).unwrap();
+                    continue;
+                }
+                reply.write_u32::<BigEndian>(action).unwrap();
+                reply.write_u32::<BigEndian>(transaction_id).unwrap();
+                match action {
+                    ACTION_CONNECT => {
+                        assert_eq!(connection_id, PROTOCOL_ID);
+                        reply.write_u64::<BigEndian>(0xC0FFEE).unwrap();
+                    }
+                    ACTION_ANNOUNCE => {
+                        if !dropped_announce {
+                            dropped_announce = true;
+                            continue;
+                        }
+                        assert_eq!(length, 98);
+                        assert_eq!(&buffer[16..36], &[0xAA; 20]);
+                        reply.write_u32::<BigEndian>(1800).unwrap();
+                        reply.write_u32::<BigEndian>(2).unwrap();
+                        reply.write_u32::<BigEndian>(5).unwrap();
+                        if from.is_ipv6() {
+                            reply.extend_from_slice(&Ipv6Addr::LOCALHOST.octets());
+                        } else {
+                            reply.extend_from_slice(&Ipv4Addr::LOCALHOST.octets());
+                        }
+                        reply.extend_from_slice(&[0x1A, 0xE1]);
+                    }
+                    ACTION_SCRAPE => {
+                        for (n, _) in buffer[16..length].chunks(20).enumerate() {
+                            reply.write_u32::<BigEndian>(n as u32 + 10).unwrap();
+                            reply.write_u32::<BigEndian>(100).unwrap();
+                            reply.write_u32::<BigEndian>(1).unwrap();
+                        }
+                    }
+                    _ => unreachable!(),
+                }
+                socket.send_to(&reply, from).unwrap();
+            }
+            actions
+        });
+        (address, handle)
+    }
+
+    #[test]
+    fn connect_announce_and_scrape() {
+        let (address, server) = udp_tracker(4);
+        let mut client = UdpTrackerClient::new(address)
+            .unwrap()
+            .base_timeout(Duration::from_millis(200));
+
+        let request =
+            AnnounceRequest::new(InfoHash::V1([0xAA; 20]), 100).event(AnnounceEvent::Started);
+        let response = client.announce(&request).unwrap();
+        assert_eq!(response.interval, Duration::from_secs(1800));
+        assert_eq!(response.complete, Some(5));
+        assert_eq!(response.incomplete, Some(2));
+        assert_eq!(
+            response.peers,
+            vec![SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 6881)]
+        );
+
+        let stats = client.scrape(&[[0xAA; 20], [0xBB; 20]]).unwrap();
+        assert_eq!(
+            stats,
+            vec![
+                ScrapeStats {
+                    complete: 10,
+                    incomplete: 1,
+                    downloaded: 100
+                },
+                ScrapeStats {
+                    complete: 11,
+                    incomplete: 1,
+                    downloaded: 100
+                },
+            ]
+        );
+
+        // Connection ID is reused: one connect, the dropped and the answered announce, a scrape.
+        assert_eq!(
+            server.join().unwrap(),
+            vec![
+                ACTION_CONNECT,
+                ACTION_ANNOUNCE,
+                ACTION_ANNOUNCE,
+                ACTION_SCRAPE
+            ]
+        );
+    }
+
+    #[test]
+    fn announces_over_ipv6() {
+        let (address, server) = udp_tracker_on("[::1]:0", 3);
+        let mut client = UdpTrackerClient::connect(&format!("udp://{}/announce", address))
+            .unwrap()
+            .base_timeout(Duration::from_millis(200));
+        let request = AnnounceRequest::new(InfoHash::V1([0xAA; 20]), 100);
+        let response = client.announce(&request).unwrap();
+        assert_eq!(
+            response.peers,
+            vec![SocketAddr::new(Ipv6Addr::LOCALHOST.into(), 6881)]
+        );
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn expired_connection_ids_are_renewed_and_errors_reported() {
+        let (address, server) = udp_tracker(4);
+        let mut client = UdpTrackerClient::new(address)
+            .unwrap()
+            .base_timeout(Duration::from_millis(200))
+            .connection_lifetime(Duration::ZERO);
+        client.scrape(&[[0x01; 20]]).unwrap();
+        client.scrape(&[[0x01; 20]]).unwrap();
+        assert_eq!(
+            server.join().unwrap(),
+            vec![ACTION_CONNECT, ACTION_SCRAPE, ACTION_CONNECT, ACTION_SCRAPE]
+        );
+
+        let (address, server) = udp_tracker(3);
+        let mut client = UdpTrackerClient::new(address)
+            .unwrap()
+            .base_timeout(Duration::from_millis(200));
+        client.connection = Some((0xBAD, Instant::now()));
+        assert!(matches!(
+            client.scrape(&[[0x01; 20]]),
+            Err(TrackerError::Failure(message)) if message == "bad connection id"
+        ));
+        // The rejected ID is not reused.
+        client.scrape(&[[0x01; 20]]).unwrap();
+        assert_eq!(
+            server.join().unwrap(),
+            vec![ACTION_SCRAPE, ACTION_CONNECT, ACTION_SCRAPE]
+        );
+    }
+
+    #[test]
+    fn gives_up_after_retransmissions() {
+        let silent = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let mut client = UdpTrackerClient::new(silent.local_addr().unwrap())
+            .unwrap()
+            .base_timeout(Duration::from_millis(10))
+            .max_retransmissions(2);
+        let started = Instant::now();
+        assert!(matches!(
+            client.scrape(&[[0x01; 20]]),
+            Err(TrackerError::Timeout)
+        ));
+        // 10 + 20 + 40 ms.
+        assert!(started.elapsed() >= Duration::from_millis(70));
+    }
+}
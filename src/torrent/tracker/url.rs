@@ -0,0 +1,110 @@
+//! Building announce and scrape URLs on top of whatever the torrent lists.
+
+use percent_encoding::{percent_encode, AsciiSet, NON_ALPHANUMERIC};
+
+/// BEP 3: every byte except `0-9a-zA-Z.-_~` is escaped.
+const TRACKER_ENCODE_SET: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'.')
+    .remove(b'-')
+    .remove(b'_')
+    .remove(b'~');
+
+/// Percent-encodes binary values such as `info_hash` and `peer_id`.
+pub fn encode(bytes: &[u8]) -> String {
+    percent_encode(bytes, TRACKER_ENCODE_SET).to_string()
+}
+
+/// Appends `query` to the query of `url`, keeping existing parameters such as
+/// passkeys and any fragment.
+pub fn with_query(url: &str, query: &str) -> String {
+    let (base, fragment) = match url.split_once('#') {
+        Some((base, fragment)) => (base, Some(fragment)),
+        None => (url, None),
+    };
+    let separator = match base.split_once('?') {
+        None => "?",
+        Some((_, "")) => "",
+        Some((_, existing)) if existing.ends_with('&') => "",
+        Some(_) => "&",
+    };
+
+    let mut result = format!("{}{}{}", base, separator, query);
+    if let Some(fragment) = fragment {
+        result.push('#');
+        result.push_str(fragment);
+    }
+    result
+}
+
+/// Scrape URL by the usual convention: the last path segment has to start with
+/// `announce`, which is replaced by `scrape`. Other trackers do not support scraping.
+pub fn scrape_url(announce_url: &str) -> Option<String> {
+    let path_end = announce_url.find(['?', '#']).unwrap_or(announce_url.len());
+    let (path, rest) = announce_url.split_at(path_end);
+    let segment_start = path.rfind('/')? + 1;
+    let segment = &path[segment_start..];
+    let suffix = segment.strip_prefix("announce")?;
+    Some(format!(
+        "{}scrape{}{}",
+        &path[..segment_start],
+        suffix,
+        rest
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn appends_to_existing_query() {
+        let cases = [
+            ("http://t.example/announce", "http://t.example/announce?a=1"),
+            (
+                "https://t.example/announce.php?passkey=abc",
+                "https://t.example/announce.php?passkey=abc&a=1",
+            ),
+            (
+                "http://t.example/announce?",
+                "http://t.example/announce?a=1",
+            ),
+            (
+                "http://t.example/announce?passkey=abc&",
+                "http://t.example/announce?passkey=abc&a=1",
+            ),
+            (
+                "http://t.example/announce?passkey=abc#frag",
+                "http://t.example/announce?passkey=abc&a=1#frag",
+            ),
+            ("http://t.example/a#frag", "http://t.example/a?a=1#frag"),
+        ];
+        for (url, expected) in cases {
+            assert_eq!(with_query(url, "a=1"), expected, "for {}", url);
+        }
+    }
+
+    #[test]
+    fn scrape_urls() {
+        let cases = [
+            ("http://t.example/announce", Some("http://t.example/scrape")),
+            (
+                "http://t.example/x/announce.php?passkey=abc",
+                Some("http://t.example/x/scrape.php?passkey=abc"),
+            ),
+            ("http://t.example/a", None),
+            ("http://t.example/announce/x", None),
+            ("http://t.example/x%064announce", None),
+        ];
+        for (url, expected) in cases {
+            assert_eq!(scrape_url(url).as_deref(), expected, "for {}", url);
+        }
+    }
+
+    #[test]
+    fn encodes_by_tracker_spec() {
+        assert_eq!(
+            encode(b"-BR0100-a.b_c~\x00\xff /"),
+            "-BR0100-a.b_c~%00%FF%20%2F"
+        );
+    }
+}
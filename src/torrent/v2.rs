@@ -0,0 +1,366 @@
+//! BitTorrent v2 (BEP 52) metainfo: the `file tree`, `piece layers` and merkle verification.
+
+use serde_bytes::ByteBuf;
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+
+/// Leaves of the v2 merkle trees always cover 16 KiB blocks.
+pub const MERKLE_BLOCK_SIZE: u64 = 16 * 1024;
+
+pub type Sha256Hash = [u8; 32];
+
+/// `file tree` dictionary: each key is a path component.
+#[derive(Debug, Default, Clone, PartialEq, serde::Deserialize, serde::Serialize)]
+#[serde(transparent)]
+pub struct FileTree(pub BTreeMap<String, FileTreeNode>);
+
+/// Node of the file tree. Files are marked by the empty key, everything else is a child.
+#[derive(Debug, Default, Clone, PartialEq, serde::Deserialize, serde::Serialize)]
+pub struct FileTreeNode {
+    #[serde(rename = "", default, skip_serializing_if = "Option::is_none")]
+    pub file: Option<V2File>,
+    #[serde(flatten)]
+    pub children: BTreeMap<String, FileTreeNode>,
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Deserialize, serde::Serialize)]
+pub struct V2File {
+    pub length: u64,
+    /// Root of the file merkle tree, absent for empty files.
+    #[serde(
+        rename = "pieces root",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub pieces_root: Option<ByteBuf>,
+}
+
+impl FileTree {
+    /// Files with their path components, in the order defined by the tree.
+    pub fn files(&self) -> Vec<(Vec<&str>, &V2File)> {
+        let mut files = Vec::new();
+        for (name, node) in &self.0 {
+            node.collect_files(vec![name.as_str()], &mut files);
+        }
+        files
+    }
+
+    pub fn total_length(&self) -> u64 {
+        self.files().iter().map(|(_, file)| file.length).sum()
+    }
+
+    /// Adds `file` at `path`, creating the intermediate directories.
+    pub fn insert(&mut self, path: &[String], file: V2File) {
+        let Some((first, rest)) = path.split_first() else {
+            return;
+        };
+        let mut node = self.0.entry(first.clone()).or_default();
+        for component in rest {
+            node = node.children.entry(component.clone()).or_default();
+        }
+        node.file = Some(file);
+    }
+}
+
+impl FileTreeNode {
+    fn collect_files<'a>(
+        &'a self,
+        path: Vec<&'a str>,
+        files: &mut Vec<(Vec<&'a str>, &'a V2File)>,
+    ) {
+        if let Some(file) = &self.file {
+            files.push((path.clone(), file));
+        }
+        for (name, child) in &self.children {
+            let mut child_path = path.clone();
+            child_path.push(name.as_str());
+            child.collect_files(child_path, files);
+        }
+    }
+}
+
+/// `piece layers`: pieces root of every file larger than a piece, mapped to its concatenated
+/// piece hashes.
+#[derive(Debug, Default, Clone, PartialEq, serde::Deserialize, serde::Serialize)]
+#[serde(transparent)]
+pub struct PieceLayers(pub BTreeMap<ByteBuf, ByteBuf>);
+
+impl PieceLayers {
+    pub fn layer(&self, pieces_root: &[u8]) -> Option<&[u8]> {
+        self.0
+            .get(&ByteBuf::from(pieces_root))
+            .map(|layer| layer.as_slice())
+    }
+}
+
+#[derive(Debug, Clone, thiserror::Error, PartialEq)]
+pub enum V2VerificationError {
+    #[error("piece length {0} is not a power of two of at least 16 KiB")]
+    InvalidPieceLength(u64),
+    #[error("file length mismatch: expected {expected}, got {actual}")]
+    LengthMismatch { expected: u64, actual: u64 },
+    #[error("file has no pieces root")]
+    MissingPiecesRoot,
+    #[error("piece layer is missing for pieces root {0}")]
+    MissingPieceLayer(String),
+    #[error("piece layer does not hash to the pieces root")]
+    PieceLayerMismatch,
+    #[error("pieces do not match the piece layer: {0:?}")]
+    BadPieces(Vec<usize>),
+    #[error("data does not hash to the pieces root")]
+    PiecesRootMismatch,
+}
+
+pub fn sha256(data: &[u8]) -> Sha256Hash {
+    Sha256::digest(data).into()
+}
+
+fn hash_pair(left: &Sha256Hash, right: &Sha256Hash) -> Sha256Hash {
+    let mut hasher = Sha256::new();
+    hasher.update(left);
+    hasher.update(right);
+    hasher.finalize().into()
+}
+
+/// Merkle root over `hashes`, padded to `width` (a power of two) with `padding`.
+pub fn merkle_root(hashes: &[Sha256Hash], width: usize, padding: Sha256Hash) -> Sha256Hash {
+    debug_assert!(width.is_power_of_two() && width >= hashes.len());
+    let mut layer = hashes.to_vec();
+    layer.resize(width, padding);
+    while layer.len() > 1 {
+        layer = layer
+            .chunks(2)
+            .map(|pair| hash_pair(&pair[0], &pair[1]))
+            .collect();
+    }
+    layer[0]
+}
+
+/// Root of a subtree where every one of `leaves` is zero.
+fn zero_subtree_root(leaves: usize) -> Sha256Hash {
+    merkle_root(&[], leaves, [0; 32])
+}
+
+/// SHA-256 of each 16 KiB block of `data`.
+pub fn block_hashes(data: &[u8]) -> Vec<Sha256Hash> {
+    data.chunks(MERKLE_BLOCK_SIZE as usize)
+        .map(sha256)
+        .collect()
+}
+
+/// Hashes of the `piece layers` entry for `data`, each covering `piece_length` bytes.
+pub fn piece_layer_hashes(
+    data: &[u8],
+    piece_length: u64,
+) -> Result<Vec<Sha256Hash>, V2VerificationError> {
+    piece_layer_from_leaves(&block_hashes(data), piece_length)
+}
+
+/// Same as [`piece_layer_hashes`], but from already computed block hashes.
+pub fn piece_layer_from_leaves(
+    leaves: &[Sha256Hash],
+    piece_length: u64,
+) -> Result<Vec<Sha256Hash>, V2VerificationError> {
+    let leaves_per_piece = leaves_per_piece(piece_length)?;
+    Ok(leaves
+        .chunks(leaves_per_piece)
+        .map(|leaves| merkle_root(leaves, leaves_per_piece, [0; 32]))
+        .collect())
+}
+
+/// Root of the merkle tree of `data`, as stored in `pieces root`.
+pub fn pieces_root(data: &[u8], piece_length: u64) -> Result<Sha256Hash, V2VerificationError> {
+    pieces_root_from_leaves(&block_hashes(data), piece_length)
+}
+
+/// Same as [`pieces_root`], but from already computed block hashes.
+pub fn pieces_root_from_leaves(
+    leaves: &[Sha256Hash],
+    piece_length: u64,
+) -> Result<Sha256Hash, V2VerificationError> {
+    if leaves.len() <= leaves_per_piece(piece_length)? {
+        let width = leaves.len().max(1).next_power_of_two();
+        return Ok(merkle_root(leaves, width, [0; 32]));
+    }
+    let layer = piece_layer_from_leaves(leaves, piece_length)?;
+    Ok(root_from_piece_layer(&layer, piece_length))
+}
+
+fn root_from_piece_layer(layer: &[Sha256Hash], piece_length: u64) -> Sha256Hash {
+    let leaves_per_piece = (piece_length / MERKLE_BLOCK_SIZE) as usize;
+    let width = layer.len().next_power_of_two();
+    merkle_root(layer, width, zero_subtree_root(leaves_per_piece))
+}
+
+/// v2 pieces are whole subtrees of 16 KiB blocks.
+fn is_valid_piece_length(piece_length: u64) -> bool {
+    piece_length >= MERKLE_BLOCK_SIZE && piece_length.is_power_of_two()
+}
+
+fn leaves_per_piece(piece_length: u64) -> Result<usize, V2VerificationError> {
+    if !is_valid_piece_length(piece_length) {
+        return Err(V2VerificationError::InvalidPieceLength(piece_length));
+    }
+    Ok((piece_length / MERKLE_BLOCK_SIZE) as usize)
+}
+
+/// Checks a single piece of a file against its `piece layers` hash.
+pub fn verify_piece(data: &[u8], piece_length: u64, expected: &[u8]) -> bool {
+    let Ok(leaves_per_piece) = leaves_per_piece(piece_length) else {
+        return false;
+    };
+    merkle_root(&block_hashes(data), leaves_per_piece, [0; 32])[..] == *expected
+}
+
+/// Verifies full file contents against its `pieces root` and, for multi-piece files, the
+/// piece layer.
+pub fn verify_file(
+    file: &V2File,
+    piece_length: u64,
+    piece_layers: &PieceLayers,
+    data: &[u8],
+) -> Result<(), V2VerificationError> {
+    leaves_per_piece(piece_length)?;
+    if data.len() as u64 != file.length {
+        return Err(V2VerificationError::LengthMismatch {
+            expected: file.length,
+            actual: data.len() as u64,
+        });
+    }
+    let Some(expected_root) = &file.pieces_root else {
+        if file.length == 0 {
+            return Ok(());
+        }
+        return Err(V2VerificationError::MissingPiecesRoot);
+    };
+
+    if file.length <= piece_length {
+        return match pieces_root(data, piece_length)?[..] == expected_root[..] {
+            true => Ok(()),
+            false => Err(V2VerificationError::PiecesRootMismatch),
+        };
+    }
+
+    let layer = piece_layers
+        .layer(expected_root)
+        .ok_or_else(|| V2VerificationError::MissingPieceLayer(hex::encode(expected_root)))?;
+    let expected_hashes: Vec<Sha256Hash> = layer
+        .chunks(32)
+        .map(|chunk| chunk.try_into().unwrap_or([0; 32]))
+        .collect();
+    if layer.len() % 32 != 0
+        || root_from_piece_layer(&expected_hashes, piece_length)[..] != expected_root[..]
+    {
+        return Err(V2VerificationError::PieceLayerMismatch);
+    }
+
+    let bad_pieces: Vec<usize> = data
+        .chunks(piece_length as usize)
+        .enumerate()
+        .filter(|(index, piece)| match expected_hashes.get(*index) {
+            Some(expected) => !verify_piece(piece, piece_length, expected),
+            None => true,
+        })
+        .map(|(index, _)| index)
+        .collect();
+    if !bad_pieces.is_empty() {
+        return Err(V2VerificationError::BadPieces(bad_pieces));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bencode::{to_bencode, BencodeDeserializer};
+    use serde::Deserialize;
+
+    #[test]
+    fn file_tree_round_trip() {
+        let root = [0xAB; 32];
+        let data = [
+            &b"d3:dird1:ad0:d6:lengthi5e11:pieces root32:"[..],
+            &root[..],
+            &b"eee5:emptyd0:d6:lengthi0eeee"[..],
+        ]
+        .concat();
+
+        let mut deserializer = BencodeDeserializer::new(&data);
+        let tree = FileTree::deserialize(&mut deserializer).unwrap();
+
+        let files = tree.files();
+        assert_eq!(files.len(), 2);
+        assert_eq!(files[0].0, vec!["dir", "a"]);
+        assert_eq!(files[0].1.length, 5);
+        assert_eq!(
+            files[0].1.pieces_root.as_ref().map(|root| root.as_slice()),
+            Some(&root[..])
+        );
+        assert_eq!(files[1].0, vec!["empty"]);
+        assert_eq!(files[1].1.pieces_root, None);
+        assert_eq!(tree.total_length(), 5);
+
+        assert_eq!(to_bencode(&tree).unwrap(), data);
+    }
+
+    #[test]
+    fn small_file_root_is_padded_to_power_of_two() {
+        let data = vec![7u8; (MERKLE_BLOCK_SIZE * 3) as usize];
+        let leaves = block_hashes(&data);
+        let expected = hash_pair(
+            &hash_pair(&leaves[0], &leaves[1]),
+            &hash_pair(&leaves[2], &[0; 32]),
+        );
+        assert_eq!(pieces_root(&data, MERKLE_BLOCK_SIZE * 4), Ok(expected));
+    }
+
+    #[test]
+    fn verify_multi_piece_file() {
+        let piece_length = MERKLE_BLOCK_SIZE * 2;
+        let data: Vec<u8> = (0..MERKLE_BLOCK_SIZE * 5).map(|i| i as u8).collect();
+        let root = pieces_root(&data, piece_length).unwrap();
+        let layer = piece_layer_hashes(&data, piece_length).unwrap();
+        assert_eq!(layer.len(), 3);
+
+        let file = V2File {
+            length: data.len() as u64,
+            pieces_root: Some(ByteBuf::from(root.to_vec())),
+        };
+        let layers = PieceLayers(BTreeMap::from([(
+            ByteBuf::from(root.to_vec()),
+            ByteBuf::from(layer.concat()),
+        )]));
+        assert_eq!(verify_file(&file, piece_length, &layers, &data), Ok(()));
+
+        let mut corrupted = data.clone();
+        corrupted[(piece_length + 1) as usize] ^= 0xFF;
+        assert_eq!(
+            verify_file(&file, piece_length, &layers, &corrupted),
+            Err(V2VerificationError::BadPieces(vec![1]))
+        );
+
+        assert_eq!(
+            verify_file(&file, piece_length, &PieceLayers::default(), &data),
+            Err(V2VerificationError::MissingPieceLayer(hex::encode(root)))
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_piece_lengths() {
+        let data = vec![7u8; (MERKLE_BLOCK_SIZE * 4) as usize];
+        let file = V2File {
+            length: data.len() as u64,
+            pieces_root: Some(ByteBuf::from(vec![0; 32])),
+        };
+        for piece_length in [0, MERKLE_BLOCK_SIZE / 2, MERKLE_BLOCK_SIZE * 3] {
+            assert_eq!(
+                verify_file(&file, piece_length, &PieceLayers::default(), &data),
+                Err(V2VerificationError::InvalidPieceLength(piece_length))
+            );
+            assert!(!verify_piece(&data, piece_length, &[0; 32]));
+            let error = V2VerificationError::InvalidPieceLength(piece_length);
+            assert_eq!(pieces_root(&data, piece_length), Err(error.clone()));
+            assert_eq!(piece_layer_hashes(&data, piece_length), Err(error));
+        }
+    }
+}
@@ -0,0 +1,363 @@
+//! Lint of metainfo files: collects every problem instead of stopping at the first one.
+
+use crate::torrent::meta::{HybridLayoutError, TorrentFile};
+use std::collections::HashSet;
+
+/// Names that cannot be used as file names on Windows, with or without an extension.
+const RESERVED_NAMES: &[&str] = &[
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+const TRACKER_SCHEMES: &[&str] = &["http", "https", "udp", "ws", "wss"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    /// The torrent cannot be downloaded correctly.
+    Error,
+    /// The torrent works, but breaks conventions other clients rely on.
+    Warning,
+}
+
+#[derive(Debug, Clone, PartialEq, thiserror::Error)]
+pub enum ValidationIssue {
+    #[error("`pieces` length {0} is not a multiple of 20")]
+    PiecesNotMultipleOf20(usize),
+    #[error("expected {expected} pieces for the total length, got {actual}")]
+    PieceCountMismatch { expected: usize, actual: usize },
+    #[error("`piece length` is zero")]
+    ZeroPieceLength,
+    #[error("`piece length` {0} is not a power of two")]
+    PieceLengthNotPowerOfTwo(usize),
+    /// BEP 52 requires a power of two for v2 and hybrid torrents.
+    #[error("v2 `piece length` {0} is not a power of two")]
+    PieceLengthNotPowerOfTwoForV2(usize),
+    #[error("v2 `piece length` {0} is smaller than 16 KiB")]
+    PieceLengthTooSmallForV2(usize),
+    #[error("torrent has neither `length`, `files` nor `file tree`")]
+    NoFiles,
+    #[error("`name` is empty")]
+    EmptyName,
+    #[error("path {path:?} has invalid component {component:?}: {reason}")]
+    InvalidPathComponent {
+        path: String,
+        component: String,
+        reason: &'static str,
+    },
+    #[error("file path {0:?} is listed more than once")]
+    DuplicatePath(String),
+    #[error("invalid announce URL {url:?}: {reason}")]
+    InvalidAnnounceUrl { url: String, reason: String },
+    #[error(transparent)]
+    HybridLayout(#[from] HybridLayoutError),
+}
+
+impl ValidationIssue {
+    pub fn severity(&self) -> Severity {
+        match self {
+            ValidationIssue::PieceLengthNotPowerOfTwo(_) => Severity::Warning,
+            _ => Severity::Error,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ValidationReport {
+    pub issues: Vec<ValidationIssue>,
+}
+
+impl ValidationReport {
+    /// No issue of [`Severity::Error`], warnings are allowed.
+    pub fn is_valid(&self) -> bool {
+        self.errors().next().is_none()
+    }
+
+    pub fn errors(&self) -> impl Iterator<Item = &ValidationIssue> {
+        self.issues
+            .iter()
+            .filter(|issue| issue.severity() == Severity::Error)
+    }
+
+    pub fn warnings(&self) -> impl Iterator<Item = &ValidationIssue> {
+        self.issues
+            .iter()
+            .filter(|issue| issue.severity() == Severity::Warning)
+    }
+}
+
+impl std::fmt::Display for ValidationReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.issues.is_empty() {
+            return write!(f, "no issues found");
+        }
+        for issue in &self.issues {
+            writeln!(f, "{:?}: {}", issue.severity(), issue)?;
+        }
+        Ok(())
+    }
+}
+
+impl TorrentFile {
+    /// Checks the metainfo for structural problems, reporting all of them.
+    pub fn validate(&self) -> ValidationReport {
+        let mut issues = Vec::new();
+        self.validate_pieces(&mut issues);
+        self.validate_paths(&mut issues);
+        self.validate_trackers(&mut issues);
+        if self.info().is_hybrid() {
+            if let Err(e) = self.info().check_hybrid_layout() {
+                issues.push(e.into());
+            }
+        }
+        ValidationReport { issues }
+    }
+
+    fn validate_pieces(&self, issues: &mut Vec<ValidationIssue>) {
+        let info = self.info();
+        if info.piece_length == 0 {
+            issues.push(ValidationIssue::ZeroPieceLength);
+        } else if !info.piece_length.is_power_of_two() {
+            issues.push(match info.is_v2() {
+                true => ValidationIssue::PieceLengthNotPowerOfTwoForV2(info.piece_length),
+                false => ValidationIssue::PieceLengthNotPowerOfTwo(info.piece_length),
+            });
+        }
+        if info.is_v2() && info.piece_length < 16 * 1024 {
+            issues.push(ValidationIssue::PieceLengthTooSmallForV2(info.piece_length));
+        }
+        if info.length.is_none() && info.files.is_none() && info.file_tree.is_none() {
+            issues.push(ValidationIssue::NoFiles);
+        }
+
+        // v2-only torrents have no `pieces` at all.
+        if info.is_v2() && !info.is_v1() {
+            return;
+        }
+        if !info.pieces.len().is_multiple_of(20) {
+            issues.push(ValidationIssue::PiecesNotMultipleOf20(info.pieces.len()));
+        }
+        if info.piece_length > 0 {
+            let expected = info.total_length().div_ceil(info.piece_length);
+            let actual = info.pieces.len() / 20;
+            if expected != actual {
+                issues.push(ValidationIssue::PieceCountMismatch { expected, actual });
+            }
+        }
+    }
+
+    fn validate_paths(&self, issues: &mut Vec<ValidationIssue>) {
+        let info = self.info();
+        if info.name.is_empty() {
+            issues.push(ValidationIssue::EmptyName);
+        } else {
+            check_components(std::slice::from_ref(&info.name), issues);
+        }
+
+        let mut seen = HashSet::new();
+        for file in info.files.iter().flatten() {
+            check_components(&file.path, issues);
+            if let Some(target) = &file.symlink_path {
+                check_components(target, issues);
+            }
+            let path = file.path.join("/");
+            if !file.is_padding() && !seen.insert(path.clone()) {
+                issues.push(ValidationIssue::DuplicatePath(path));
+            }
+        }
+
+        if let Some(file_tree) = &info.file_tree {
+            for (path, _) in file_tree.files() {
+                let components: Vec<String> = path.iter().map(|c| c.to_string()).collect();
+                check_components(&components, issues);
+            }
+        }
+    }
+
+    fn validate_trackers(&self, issues: &mut Vec<ValidationIssue>) {
+        let tiers = self.announce_list.iter().flatten().flatten();
+        for url in std::iter::once(&self.announce).chain(tiers) {
+            if let Err(reason) = check_announce_url(url) {
+                issues.push(ValidationIssue::InvalidAnnounceUrl {
+                    url: url.clone(),
+                    reason,
+                });
+            }
+        }
+    }
+}
+
+fn check_components(components: &[String], issues: &mut Vec<ValidationIssue>) {
+    for component in components {
+        if let Some(reason) = component_problem(component) {
+            issues.push(ValidationIssue::InvalidPathComponent {
+                path: components.join("/"),
+                component: component.clone(),
+                reason,
+            });
+        }
+    }
+}
+
+fn component_problem(component: &str) -> Option<&'static str> {
+    if component.is_empty() {
+        return Some("empty component");
+    }
+    if component == "." || component == ".." {
+        return Some("relative path component");
+    }
+    if component.contains(['/', '\\']) {
+        return Some("contains a path separator");
+    }
+    if component.chars().any(|c| c.is_control()) {
+        return Some("contains a control character");
+    }
+    let stem = component.split('.').next().unwrap_or(component);
+    if RESERVED_NAMES
+        .iter()
+        .any(|reserved| reserved.eq_ignore_ascii_case(stem))
+    {
+        return Some("reserved file name");
+    }
+    None
+}
+
+fn check_announce_url(url: &str) -> Result<(), String> {
+    let parsed = reqwest::Url::parse(url).map_err(|e| e.to_string())?;
+    if !TRACKER_SCHEMES.contains(&parsed.scheme()) {
+        return Err(format!("unsupported scheme {}", parsed.scheme()));
+    }
+    if parsed.host_str().is_none_or(str::is_empty) {
+        return Err("missing host".to_string());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::torrent::meta::{FileEntry, MetaInfo};
+    use crate::torrent::v2::FileTree;
+
+    fn info(files: Vec<FileEntry>, piece_length: usize, pieces: usize) -> MetaInfo {
+        MetaInfo {
+            length: None,
+            files: Some(files),
+            name: "dir".to_string(),
+            piece_length,
+            pieces: vec![0; pieces],
+            meta_version: None,
+            file_tree: None,
+            private: None,
+            source: None,
+        }
+    }
+
+    fn file(path: &[&str], length: u64) -> FileEntry {
+        FileEntry::new(path.iter().map(|c| c.to_string()).collect(), length)
+    }
+
+    #[test]
+    fn valid_torrent() {
+        let torrent = TorrentFile::new(
+            "http://t.example/announce".to_string(),
+            info(vec![file(&["a"], 10), file(&["b", "c"], 30)], 16, 60),
+        );
+        let report = torrent.validate();
+        assert_eq!(report.issues, vec![]);
+        assert!(report.is_valid());
+    }
+
+    #[test]
+    fn reports_every_problem() {
+        let mut torrent = TorrentFile::new(
+            "not a url".to_string(),
+            info(
+                vec![
+                    file(&["..", "etc"], 10),
+                    file(&["a/b"], 10),
+                    file(&["x"], 10),
+                    file(&["x"], 10),
+                    file(&["con.txt"], 10),
+                ],
+                24,
+                41,
+            ),
+        );
+        torrent.info_mut().name = String::new();
+        torrent.announce_list = Some(vec![vec!["ftp://t.example/".to_string()]]);
+
+        let report = torrent.validate();
+        assert!(!report.is_valid());
+        let issues = &report.issues;
+        assert!(issues.contains(&ValidationIssue::PieceLengthNotPowerOfTwo(24)));
+        assert!(issues.contains(&ValidationIssue::PiecesNotMultipleOf20(41)));
+        assert!(issues.contains(&ValidationIssue::PieceCountMismatch {
+            expected: 3,
+            actual: 2
+        }));
+        assert!(issues.contains(&ValidationIssue::EmptyName));
+        assert!(issues.contains(&ValidationIssue::DuplicatePath("x".to_string())));
+        let reasons: Vec<&str> = issues
+            .iter()
+            .filter_map(|issue| match issue {
+                ValidationIssue::InvalidPathComponent { reason, .. } => Some(*reason),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            reasons,
+            vec![
+                "relative path component",
+                "contains a path separator",
+                "reserved file name"
+            ]
+        );
+        let bad_urls: Vec<&str> = issues
+            .iter()
+            .filter_map(|issue| match issue {
+                ValidationIssue::InvalidAnnounceUrl { url, .. } => Some(url.as_str()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(bad_urls, vec!["not a url", "ftp://t.example/"]);
+        assert_eq!(report.warnings().count(), 1);
+    }
+
+    #[test]
+    fn piece_length_not_power_of_two_is_an_error_for_v2() {
+        let piece_length = 48 * 1024;
+        let mut torrent = TorrentFile::new(
+            "http://t.example/announce".to_string(),
+            info(vec![file(&["a"], 10)], piece_length, 20),
+        );
+        let report = torrent.validate();
+        assert_eq!(
+            report.issues,
+            vec![ValidationIssue::PieceLengthNotPowerOfTwo(piece_length)]
+        );
+        assert_eq!(report.issues[0].severity(), Severity::Warning);
+        assert!(report.is_valid());
+
+        torrent.info_mut().meta_version = Some(2);
+        torrent.info_mut().file_tree = Some(FileTree::default());
+        let report = torrent.validate();
+        let issue = ValidationIssue::PieceLengthNotPowerOfTwoForV2(piece_length);
+        assert!(report.issues.contains(&issue));
+        assert!(!report
+            .issues
+            .contains(&ValidationIssue::PieceLengthNotPowerOfTwo(piece_length)));
+        assert_eq!(issue.severity(), Severity::Error);
+        assert!(!report.is_valid());
+    }
+
+    #[test]
+    fn zero_piece_length() {
+        let torrent = TorrentFile::new(
+            "udp://t.example:6969".to_string(),
+            info(vec![file(&["a"], 10)], 0, 20),
+        );
+        assert_eq!(
+            torrent.validate().issues,
+            vec![ValidationIssue::ZeroPieceLength]
+        );
+    }
+}